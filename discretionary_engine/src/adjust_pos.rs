@@ -1,17 +1,19 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use color_eyre::eyre::{Context, Result, bail};
-use nautilus_bybit::{
-	common::enums::{BybitEnvironment, BybitProductType},
-	http::query::{BybitInstrumentsInfoParamsBuilder, BybitTickersParamsBuilder},
-};
+use discretionary_engine_risk::{collect_balances, get_total_balance, initialize_exchanges};
 use nautilus_model::identifiers::InstrumentId;
-use secrecy::ExposeSecret;
 use tracing::info;
 use v_exchanges::Ticker;
 use v_utils::{log, trades::Timeframe};
 
-use crate::{bybit_common::*, config::LiveSettings};
+use crate::{
+	bybit_common::*,
+	config::LiveSettings,
+	exchange_apis::{MarginType, order_types::PriceDistance},
+	risk::get_exchanges_auth,
+	ws_chase_limit::{format_price, format_qty},
+};
 
 #[derive(clap::Args, Debug)]
 #[command(group(
@@ -31,8 +33,10 @@ pub(crate) struct AdjustPosArgs {
 	#[arg(short = 'n', long)]
 	notional: Option<f64>,
 
-	/// Size with suffix inference: "$" for USD, asset name (e.g., "BTC") for that asset, or plain number for quote
-	#[arg(short = 's', long)]
+	/// Size with suffix inference: "$" for USD, asset name (e.g., "BTC") for that asset, or plain number for quote.
+	/// Other assets are converted at their current USDT price; stablecoins count as USD.
+	/// "%" is relative to the current position (e.g. "-50%" closes half of it), "%bal" to the total balance across exchanges.
+	#[arg(short = 's', long, allow_hyphen_values = true)]
 	size: Option<String>,
 
 	/// timeframe, in the format of "1m", "1h", "3M", etc.
@@ -40,13 +44,74 @@ pub(crate) struct AdjustPosArgs {
 	#[arg(short, long)]
 	tf: Option<Timeframe>,
 
-	/// Reduce-only mode: only reduce existing position, don't increase it
+	/// Reduce-only mode: only reduce existing position, don't increase it. Implied when shrinking the position by a percentage of it.
 	#[arg(long)]
 	reduce: bool,
 
 	/// Optional duration over which to execute the order (using chase-limit strategy)
 	#[arg(short, long)]
 	duration: Option<Timeframe>,
+
+	/// Stop chasing once price runs this far from where it was on start, either in price units or in percents (e.g. "50" or "0.5%"). What's left unfilled is abandoned instead of market-filled.
+	#[arg(long, requires = "duration")]
+	max_range: Option<PriceDistance>,
+
+	/// Leverage to set on the symbol before placing the order. Keeps the exchange's current setting if not provided.
+	#[arg(short, long)]
+	leverage: Option<u8>,
+
+	/// Margin type to set on the symbol before placing the order. Bybit requires leverage to be specified alongside it.
+	#[arg(short, long, requires = "leverage")]
+	margin_type: Option<MarginType>,
+
+	/// Take-profit to attach once the order fills: absolute price, or distance from the fill price with "%" (e.g. "5%").
+	#[arg(long, conflicts_with = "reduce")]
+	tp: Option<TpslLevel>,
+
+	/// Stop-loss to attach once the order fills: absolute price, or distance from the fill price with "%" (e.g. "2%").
+	#[arg(long, conflicts_with = "reduce")]
+	sl: Option<TpslLevel>,
+}
+
+/// Level of a take-profit or stop-loss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TpslLevel {
+	Price(f64),
+	/// From the fill price, in the direction appropriate for tp or sl.
+	Distance(PriceDistance),
+}
+impl std::str::FromStr for TpslLevel {
+	type Err = eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.ends_with('%') {
+			true => Ok(TpslLevel::Distance(s.parse()?)),
+			false => Ok(TpslLevel::Price(s.parse()?)),
+		}
+	}
+}
+impl TpslLevel {
+	fn price(&self, fill_price: f64, long: bool, take_profit: bool) -> f64 {
+		match self {
+			TpslLevel::Price(p) => *p,
+			TpslLevel::Distance(d) => {
+				let direction = if long == take_profit { 1.0 } else { -1.0 };
+				fill_price + direction * d.distance(fill_price)
+			}
+		}
+	}
+}
+
+/// Counted 1:1 with USD when used as a size unit.
+const STABLECOINS: [&str; 7] = ["USD", "USDT", "USDC", "BUSD", "FDUSD", "DAI", "TUSD"];
+
+/// Current price of `asset` in USDT, from its linear perpetual on Bybit.
+async fn usd_price(testnet: bool, asset: &str) -> Result<f64> {
+	let symbol = format!("{}USDT", asset.to_uppercase());
+	let ticker = fetch_book_ticker(testnet, BybitCategory::Linear, &symbol)
+		.await
+		.with_context(|| format!("No ticker found for {}, can't convert from {}", symbol, asset))?;
+	Ok(ticker.last_price)
 }
 
 /// Round quantity to the appropriate step size
@@ -59,9 +124,12 @@ pub(crate) async fn main(args: AdjustPosArgs, live_settings: Arc<LiveSettings>,
 
 	// Determine whether we have a quote amount (quantity) or notional amount (USD)
 	enum SizeType {
-		Quote(f64),    // Actual quantity of asset
-		Notional(f64), // USD value
-		MinOrder(f64), // Multiple of minimum order size
+		Quote(f64),           // Actual quantity of asset
+		Notional(f64),        // USD value
+		MinOrder(f64),        // Multiple of minimum order size
+		Asset(f64, String),   // Amount of some asset, converted at current prices
+		PositionPercent(f64), // Fraction of the current position
+		BalancePercent(f64),  // Fraction of the total balance
 	}
 
 	let size_type = if let Some(notional) = args.notional {
@@ -74,7 +142,13 @@ pub(crate) async fn main(args: AdjustPosArgs, live_settings: Arc<LiveSettings>,
 		// - unit defaults to quote if omitted
 		// Examples: "min" -> 1min, "BTC" -> 1BTC, "5$" -> 5 USD, "10" -> 10 quote
 
-		if size_str.ends_with('$') {
+		if let Some(magnitude) = size_str.strip_suffix("%bal") {
+			let percent = magnitude.parse::<f64>().context("Failed to parse balance percentage from --size")?;
+			SizeType::BalancePercent(percent / 100.0)
+		} else if let Some(magnitude) = size_str.strip_suffix('%') {
+			let percent = magnitude.parse::<f64>().context("Failed to parse position percentage from --size")?;
+			SizeType::PositionPercent(percent / 100.0)
+		} else if size_str.ends_with('$') {
 			// Strip $ and parse as USD
 			let magnitude = size_str.trim_end_matches('$').parse::<f64>().context("Failed to parse USD amount from --size")?;
 			SizeType::Notional(magnitude)
@@ -92,9 +166,10 @@ pub(crate) async fn main(args: AdjustPosArgs, live_settings: Arc<LiveSettings>,
 			// Handle different units
 			if unit_part == "min" {
 				SizeType::MinOrder(magnitude)
+			} else if STABLECOINS.contains(&unit_part.to_uppercase().as_str()) {
+				SizeType::Notional(magnitude)
 			} else {
-				// TODO: Handle conversion from other assets to USD
-				bail!("Asset conversion not yet implemented. Got {} {}, need to convert to USD", magnitude, unit_part);
+				SizeType::Asset(magnitude, unit_part.to_uppercase())
 			}
 		} else {
 			// Plain number - treat as quote currency (actual quantity)
@@ -107,107 +182,148 @@ pub(crate) async fn main(args: AdjustPosArgs, live_settings: Arc<LiveSettings>,
 
 	// Create Bybit HTTP clients
 	let exchange_name = args.ticker.exchange_name;
-	let (raw_client, client) = create_bybit_clients(live_settings.clone(), exchange_name.clone(), testnet)?;
+	let signed_client = BybitSignedClient::new(live_settings.clone(), exchange_name.clone(), testnet)?;
 
 	// Get current ticker price first
-	let symbol_raw = args.ticker.symbol.to_string();
-	let symbol = convert_symbol_to_bybit(&symbol_raw);
-	info!("Fetching current price for {} (converted from {})", symbol, symbol_raw);
-
-	let ticker_params = BybitTickersParamsBuilder::default()
-		.category(BybitProductType::Linear)
-		.symbol(symbol.clone())
-		.build()
-		.context("Failed to build ticker params")?;
-
-	let ticker_response: nautilus_bybit::http::models::BybitTickersLinearResponse = raw_client
-		.get_tickers::<nautilus_bybit::http::models::BybitTickersLinearResponse>(&ticker_params)
-		.await
-		.context("Failed to fetch ticker data")?;
-
-	let ticker = ticker_response.result.list.get(0).ok_or_else(|| color_eyre::eyre::eyre!("No ticker data found for {}", symbol))?;
+	let (symbol, category) = bybit_symbol(&args.ticker)?;
+	let base = base_coin(&args.ticker);
+	info!("Fetching current price for {} {} (converted from {})", category, symbol, args.ticker.symbol);
+	if !category.has_positions() && (args.leverage.is_some() || args.tp.is_some() || args.sl.is_some()) {
+		bail!("Leverage and TP/SL are position settings, and {} spot has no positions", symbol);
+	}
 
-	let current_price: f64 = ticker.last_price.parse().context("Failed to parse price as float")?;
+	let current_price = fetch_book_ticker(testnet, category, &symbol).await?.last_price;
 	info!("Current price: ${}", current_price);
 
 	// Fetch instrument info to get lot size filter
-	let instruments_params = BybitInstrumentsInfoParamsBuilder::default()
-		.category(BybitProductType::Linear)
-		.symbol(symbol.clone())
-		.build()
-		.context("Failed to build instruments info params")?;
-
-	let instruments_response: nautilus_bybit::http::models::BybitInstrumentLinearResponse = raw_client
-		.get_instruments::<nautilus_bybit::http::models::BybitInstrumentLinearResponse>(&instruments_params)
-		.await
-		.context("Failed to fetch instrument info")?;
-
-	let instrument = instruments_response
-		.result
-		.list
-		.get(0)
-		.ok_or_else(|| color_eyre::eyre::eyre!("No instrument info found for {}", symbol))?;
-
-	let lot_size_filter = &instrument.lot_size_filter;
-	let qty_step: f64 = lot_size_filter.qty_step.parse().context("Failed to parse qtyStep")?;
-	let min_order_qty: f64 = lot_size_filter.min_order_qty.parse().context("Failed to parse minOrderQty")?;
-	let max_order_qty: f64 = lot_size_filter.max_order_qty.parse().context("Failed to parse maxOrderQty")?;
-
-	let price_filter = &instrument.price_filter;
-	let tick_size: f64 = price_filter.tick_size.parse().context("Failed to parse tickSize")?;
+	let InstrumentRules {
+		qty_step,
+		min_order_qty,
+		max_order_qty,
+		tick_size,
+		max_leverage,
+	} = fetch_instrument_rules(testnet, category, &symbol).await?;
 
 	info!(
 		"Instrument info - qtyStep: {}, tickSize: {}, minOrderQty: {}, maxOrderQty: {}",
 		qty_step, tick_size, min_order_qty, max_order_qty
 	);
 
+	// Apply leverage and margin type before anything gets placed, as Bybit refuses some of the switches with open orders on the symbol
+	if let Some(leverage) = args.leverage {
+		if let Some(max_leverage) = max_leverage
+			&& leverage as f64 > max_leverage
+		{
+			bail!("Requested leverage {}x exceeds maximum of {}x for {}", leverage, max_leverage, symbol);
+		}
+
+		if let Some(margin_type) = args.margin_type {
+			signed_client.switch_margin_type(category, &symbol, margin_type, leverage).await?;
+			info!("Margin type set to {:?}", margin_type);
+		}
+		signed_client.set_leverage(category, &symbol, leverage).await?;
+		info!("Leverage set to {}x", leverage);
+	}
+
 	// Calculate quantity based on size type, extracting sign for order side
+	let mut reduce_only = args.reduce;
 	let (raw_quantity, side) = match size_type {
 		SizeType::Quote(qty) => (qty, if qty >= 0.0 { "Buy" } else { "Sell" }),
 		SizeType::Notional(usd) => {
-			let qty = usd / current_price;
+			let qty = category.order_qty(usd / current_price, current_price);
 			(qty, if usd >= 0.0 { "Buy" } else { "Sell" })
 		}
 		SizeType::MinOrder(multiplier) => {
 			let qty = multiplier * min_order_qty;
 			(qty, if multiplier >= 0.0 { "Buy" } else { "Sell" })
 		}
+		SizeType::Asset(amount, asset) => {
+			let base_qty = if asset == base {
+				amount
+			} else {
+				let asset_price = usd_price(testnet, &asset).await?;
+				info!("{} price: ${}", asset, asset_price);
+				amount * asset_price / current_price
+			};
+			let qty = category.order_qty(base_qty, current_price);
+			(qty, if amount >= 0.0 { "Buy" } else { "Sell" })
+		}
+		SizeType::PositionPercent(fraction) => {
+			let position_size = signed_client.held_qty(category, &symbol, &base).await?;
+			if position_size == 0.0 {
+				bail!("No open position on {} to take a percentage of", symbol);
+			}
+			info!("Current position: {} {}", position_size, symbol);
+
+			if fraction < 0.0 {
+				reduce_only = true;
+			}
+			let qty = position_size * fraction;
+			(qty, if qty >= 0.0 { "Buy" } else { "Sell" })
+		}
+		SizeType::BalancePercent(fraction) => {
+			let config = live_settings.config()?;
+			let exchanges = initialize_exchanges(&get_exchanges_auth(&config))?;
+			let balances = collect_balances(&exchanges).await?;
+			let total_balance = get_total_balance(&balances, config.risk.as_ref().and_then(|r| r.other_balances));
+			info!("Total balance: ${}", *total_balance);
+
+			let qty = category.order_qty(*total_balance * fraction / current_price, current_price);
+			(qty, if fraction >= 0.0 { "Buy" } else { "Sell" })
+		}
 	};
 
+	// TP/SL are set in the direction of what the order adds to the position, and a reduce-only one adds nothing
+	if reduce_only && (args.tp.is_some() || args.sl.is_some()) {
+		bail!("TP/SL can't be attached to a reduce-only order");
+	}
+
 	// Work with absolute value for rounding
 	let abs_raw_qty = raw_quantity.abs();
-	let quantity = round_to_step(abs_raw_qty, qty_step);
+	let mut quantity = round_to_step(abs_raw_qty, qty_step);
+	// Spot can't go short, so reduce-only there means selling out of what is held
+	if reduce_only && !category.has_positions() {
+		if side != "Sell" {
+			bail!("Reduce-only on spot can only sell");
+		}
+		let held = signed_client.held_qty(category, &symbol, &base).await?;
+		quantity = quantity.min((held / qty_step).floor() * qty_step);
+	}
 
-	let actual_notional = quantity * current_price;
+	let actual_notional = category.notional(quantity, current_price);
 	log!("{} order: {:.6} -> rounded to {:.6} (notional: ${:.2})", side, abs_raw_qty, quantity, actual_notional);
 
 	// Check if we should use chase-limit execution
-	if args.duration.is_some() {
+	let (filled_qty, fill_price) = if args.duration.is_some() {
 		log!("Using WebSocket chase-limit execution with duration: {:?}", args.duration);
 
-		// Get API credentials for WebSocket
-		let config = live_settings.config()?;
-		let exchange_config = config.get_exchange(exchange_name)?;
-		let api_key = exchange_config.api_pubkey.clone();
-		let api_secret = exchange_config.api_secret.expose_secret().to_string();
-
-		// Determine environment
-		let environment = if testnet { BybitEnvironment::Testnet } else { BybitEnvironment::Mainnet };
-
 		// Create InstrumentId for ticker subscription
 		// Format: "SYMBOL.VENUE" e.g., "BTCUSDT.BYBIT"
 		let instrument_id = InstrumentId::from(format!("{}.BYBIT", symbol).as_str());
 
-		let filled_qty = crate::ws_chase_limit::execute_ws_chase_limit(
-			&raw_client, api_key, api_secret, environment, &symbol, instrument_id, side, quantity, qty_step, tick_size, args.duration,
+		let outcome = crate::ws_chase_limit::execute_ws_chase_limit(
+			&signed_client,
+			category,
+			&symbol,
+			instrument_id,
+			side,
+			quantity,
+			qty_step,
+			tick_size,
+			args.duration.map(|tf| Duration::from_millis(tf.0)),
+			args.max_range,
+			None,
 		)
 		.await
 		.context("WebSocket chase-limit execution failed")?;
 
-		let filled_notional = filled_qty * current_price;
+		let filled_notional = category.notional(outcome.filled, current_price);
 		println!("✅ Chase-limit execution completed!");
-		println!("   Filled: {:.6} {} (notional: ${:.2})", filled_qty, symbol, filled_notional);
-		Ok(())
+		println!("   Filled: {:.6} {} (notional: ${:.2})", outcome.filled, symbol, filled_notional);
+		if outcome.abandoned > 0.0 {
+			println!("   Abandoned: {:.6} {} (price ran past max range)", outcome.abandoned, symbol);
+		}
+		(outcome.filled, outcome.avg_price.unwrap_or(current_price))
 	} else {
 		// Format quantity properly based on step size
 		let qty_str = if qty_step >= 1.0 {
@@ -221,32 +337,65 @@ pub(crate) async fn main(args: AdjustPosArgs, live_settings: Arc<LiveSettings>,
 		};
 
 		// Prepare order request
-		let order_request = serde_json::json!({
-			"category": "linear",
+		let order_link_id = format!("adjust-{}", uuid::Uuid::new_v4());
+		let mut order_request = serde_json::json!({
+			"category": category.as_str(),
 			"symbol": symbol,
 			"side": side,
 			"orderType": "Market",
 			"qty": qty_str,
 			"timeInForce": "IOC",
-			"orderLinkId": format!("adjust-{}", uuid::Uuid::new_v4()),
-			"reduceOnly": args.reduce,
+			"orderLinkId": &order_link_id,
 		});
+		match category.has_positions() {
+			true => order_request["reduceOnly"] = reduce_only.into(),
+			false => order_request["marketUnit"] = category.market_unit().into(),
+		}
 
 		info!("Submitting market {} order for {} {}", side, quantity, symbol);
 
 		// Submit order
-		let order_response = client.place_order(&order_request).await.context("Failed to place order")?;
-
-		if order_response.ret_code == 0 {
-			println!("✅ Order submitted successfully!");
-			info!("Order submitted successfully!");
-			if let Some(order_id) = order_response.result.order_id {
-				println!("   Order ID: {}", order_id);
-			}
-			println!("   Quantity: {} {} (notional: ${:.2})", qty_str, symbol, actual_notional);
-			Ok(())
-		} else {
-			bail!("Order failed: {} (code: {})", order_response.ret_msg, order_response.ret_code);
+		let placed = signed_client.place_order(&order_request).await?;
+		println!("✅ Order submitted successfully!");
+		info!("Order submitted successfully!");
+		println!("   Order ID: {}", placed.order_id);
+		println!("   Quantity: {} {} (notional: ${:.2})", qty_str, symbol, actual_notional);
+
+		if args.tp.is_none() && args.sl.is_none() {
+			return Ok(());
 		}
+		// IOC resolves immediately, but give the exchange a moment to publish the execution
+		tokio::time::sleep(Duration::from_millis(200)).await;
+		match signed_client.order_execution_by_link_id(category, &symbol, &order_link_id).await? {
+			Some(execution) if execution.cum_exec_qty > 0.0 => (execution.cum_exec_qty, execution.cum_exec_value / execution.cum_exec_qty),
+			_ => (0.0, current_price),
+		}
+	};
+
+	if args.tp.is_none() && args.sl.is_none() {
+		return Ok(());
+	}
+	if filled_qty <= 0.0 {
+		bail!("Nothing got filled, not attaching TP/SL");
+	}
+
+	let long = side == "Buy";
+	let take_profit = args.tp.map(|tp| format_price(round_to_step(tp.price(fill_price, long, true), tick_size), tick_size));
+	let stop_loss = args.sl.map(|sl| format_price(round_to_step(sl.price(fill_price, long, false), tick_size), tick_size));
+
+	// Only cover what we've just added, unless it is the entire position
+	let position = signed_client.side_position(category, &symbol, long).await?;
+	let partial_size = (position.size > filled_qty + qty_step / 2.0).then(|| format_qty(filled_qty, qty_step));
+
+	signed_client
+		.set_trading_stop(category, &symbol, position.idx, take_profit.as_deref(), stop_loss.as_deref(), partial_size.as_deref())
+		.await?;
+	println!("   Fill price: {}", fill_price);
+	if let Some(tp) = &take_profit {
+		println!("   Take-profit: {}", tp);
+	}
+	if let Some(sl) = &stop_loss {
+		println!("   Stop-loss: {}", sl);
 	}
+	Ok(())
 }
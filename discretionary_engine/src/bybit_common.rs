@@ -1,20 +1,191 @@
 use std::sync::Arc;
 
-use color_eyre::eyre::{Context, Result};
+use color_eyre::eyre::{Context, Result, bail};
 use hmac::{Hmac, Mac};
-use nautilus_bybit::http::client::{BybitHttpClient, BybitRawHttpClient};
+use nautilus_bybit::{
+	common::enums::{BybitEnvironment, BybitProductType},
+	http::client::{BybitHttpClient, BybitRawHttpClient},
+};
 use secrecy::ExposeSecret;
 use sha2::Sha256;
 use tracing::info;
-use v_exchanges::ExchangeName;
+use v_exchanges::{ExchangeName, Ticker, core::Instrument};
 
-use crate::config::LiveSettings;
+use crate::{
+	config::LiveSettings,
+	exchange_apis::{MarginType, Market},
+};
 
 pub fn convert_symbol_to_bybit(symbol: &str) -> String {
 	let without_suffix = symbol.split('.').next().unwrap_or(symbol);
 	without_suffix.replace('-', "").to_uppercase()
 }
 
+/// Bybit symbol and category of the ticker: `btc-usdt.P` -> (`BTCUSDT`, Linear), `btc-usd.PI` -> (`BTCUSD`, Inverse), `btc-usdt` -> (`BTCUSDT`, Spot)
+pub fn bybit_symbol(ticker: &Ticker) -> Result<(String, BybitCategory)> {
+	let category = BybitCategory::from_instrument(&ticker.symbol.instrument)?;
+	Ok((convert_symbol_to_bybit(&ticker.symbol.to_string()), category))
+}
+
+/// Base coin of the ticker, in Bybit's casing. What a spot "position" is held in.
+pub fn base_coin(ticker: &Ticker) -> String {
+	let symbol = ticker.symbol.to_string();
+	symbol.split(['-', '.']).next().unwrap_or(&symbol).to_uppercase()
+}
+
+/// `category` of Bybit v5 requests.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum BybitCategory {
+	#[default]
+	Linear,
+	Inverse,
+	Spot,
+}
+impl BybitCategory {
+	pub fn from_instrument(instrument: &Instrument) -> Result<Self> {
+		match instrument {
+			Instrument::Perp => Ok(Self::Linear),
+			Instrument::PerpInverse => Ok(Self::Inverse),
+			Instrument::Spot => Ok(Self::Spot),
+			other => bail!("No Bybit category trades {other:?}"),
+		}
+	}
+
+	/// Category of the engine's [Market]s, as traded by [crate::exchange_apis::bybit].
+	pub fn from_market(market: Market) -> Result<Self> {
+		match market {
+			Market::BybitFutures => Ok(Self::Linear),
+			other => bail!("{other:?} is not a Bybit market"),
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Linear => "linear",
+			Self::Inverse => "inverse",
+			Self::Spot => "spot",
+		}
+	}
+
+	pub fn product_type(&self) -> BybitProductType {
+		match self {
+			Self::Linear => BybitProductType::Linear,
+			Self::Inverse => BybitProductType::Inverse,
+			Self::Spot => BybitProductType::Spot,
+		}
+	}
+
+	/// Spot has no positions: no reduce-only, leverage or position TP/SL, and what we hold is the wallet balance of the base coin.
+	pub fn has_positions(&self) -> bool {
+		*self != Self::Spot
+	}
+
+	/// Order quantity for `base_qty` of the base coin. Inverse contracts are $1 each, so there it is the USD value instead.
+	pub fn order_qty(&self, base_qty: f64, price: f64) -> f64 {
+		match self {
+			Self::Inverse => base_qty * price,
+			Self::Linear | Self::Spot => base_qty,
+		}
+	}
+
+	/// USD value of an order quantity, counterpart of [Self::order_qty].
+	pub fn notional(&self, qty: f64, price: f64) -> f64 {
+		match self {
+			Self::Inverse => qty,
+			Self::Linear | Self::Spot => qty * price,
+		}
+	}
+
+	/// `marketUnit` for market orders. Spot market buys are otherwise sized in quote, while we always pass base quantities.
+	pub fn market_unit(&self) -> Option<String> {
+		(*self == Self::Spot).then(|| "baseCoin".to_owned())
+	}
+}
+impl std::fmt::Display for BybitCategory {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.as_str())
+	}
+}
+
+fn base_url(testnet: bool) -> &'static str {
+	match testnet {
+		true => "https://api-testnet.bybit.com",
+		false => "https://api.bybit.com",
+	}
+}
+
+/// GET a public v5 market endpoint. Used over the typed nautilus requests where response models differ by category.
+pub async fn get_public(testnet: bool, endpoint: &str, query: &str) -> Result<serde_json::Value> {
+	let url = format!("{}{}?{}", base_url(testnet), endpoint, query);
+	let response = reqwest::get(&url).await.with_context(|| format!("Failed to send request to {endpoint}"))?;
+	let response_text = response.text().await.context("Failed to read response")?;
+	let response_json: serde_json::Value = serde_json::from_str(&response_text).context("Failed to parse response JSON")?;
+	ensure_ret_code(&response_json, &[]).with_context(|| format!("{endpoint} failed"))?;
+	Ok(response_json)
+}
+
+fn parse_str_f64(value: &serde_json::Value, field: &str) -> Result<f64> {
+	let s = value[field].as_str().ok_or_else(|| color_eyre::eyre::eyre!("No {field} in {value}"))?;
+	s.parse().with_context(|| format!("Failed to parse {field}: {s}"))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BookTicker {
+	pub last_price: f64,
+	pub bid: f64,
+	pub ask: f64,
+}
+
+pub async fn fetch_book_ticker(testnet: bool, category: BybitCategory, symbol: &str) -> Result<BookTicker> {
+	let response = get_public(testnet, "/v5/market/tickers", &format!("category={category}&symbol={symbol}")).await?;
+	let ticker = response["result"]["list"]
+		.as_array()
+		.and_then(|l| l.first())
+		.ok_or_else(|| color_eyre::eyre::eyre!("No ticker data found for {} ({})", symbol, category))?;
+	Ok(BookTicker {
+		last_price: parse_str_f64(ticker, "lastPrice")?,
+		bid: parse_str_f64(ticker, "bid1Price")?,
+		ask: parse_str_f64(ticker, "ask1Price")?,
+	})
+}
+
+/// Trading rules of a symbol, normalized over categories.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InstrumentRules {
+	/// Spot has no `qtyStep`, its `basePrecision` is used instead.
+	pub qty_step: f64,
+	pub min_order_qty: f64,
+	pub max_order_qty: f64,
+	pub tick_size: f64,
+	/// `None` on spot.
+	pub max_leverage: Option<f64>,
+}
+
+pub async fn fetch_instrument_rules(testnet: bool, category: BybitCategory, symbol: &str) -> Result<InstrumentRules> {
+	let response = get_public(testnet, "/v5/market/instruments-info", &format!("category={category}&symbol={symbol}")).await?;
+	let instrument = response["result"]["list"]
+		.as_array()
+		.and_then(|l| l.first())
+		.ok_or_else(|| color_eyre::eyre::eyre!("No instrument info found for {} ({})", symbol, category))?;
+
+	let lot_size_filter = &instrument["lotSizeFilter"];
+	let qty_step = match category {
+		BybitCategory::Spot => parse_str_f64(lot_size_filter, "basePrecision")?,
+		_ => parse_str_f64(lot_size_filter, "qtyStep")?,
+	};
+	let max_leverage = match category {
+		BybitCategory::Spot => None,
+		_ => Some(parse_str_f64(&instrument["leverageFilter"], "maxLeverage")?),
+	};
+	Ok(InstrumentRules {
+		qty_step,
+		min_order_qty: parse_str_f64(lot_size_filter, "minOrderQty")?,
+		max_order_qty: parse_str_f64(lot_size_filter, "maxOrderQty")?,
+		tick_size: parse_str_f64(&instrument["priceFilter"], "tickSize")?,
+		max_leverage,
+	})
+}
+
 pub fn create_bybit_clients(live_settings: Arc<LiveSettings>, exchange_name: ExchangeName, testnet: bool) -> Result<(BybitRawHttpClient, BybitHttpClient)> {
 	let config = live_settings.config()?;
 	let exchange_config = config.get_exchange(exchange_name)?;
@@ -54,56 +225,61 @@ pub fn create_bybit_clients(live_settings: Arc<LiveSettings>, exchange_name: Exc
 	Ok((raw_client, client))
 }
 
-/// Creates a new Bybit HTTP client wrapper that can call the amend endpoint
-/// We need to store credentials to make authenticated requests
-pub struct BybitAmendClient {
+/// Signed Bybit v5 REST client. Everything private we do over REST goes through here; responses with non-zero `retCode` surface as [BybitApiError].
+pub struct BybitSignedClient {
 	api_key: String,
 	api_secret: String,
-	base_url: String,
+	testnet: bool,
 	http_client: reqwest::Client,
 }
 
-impl BybitAmendClient {
+impl BybitSignedClient {
 	pub fn new(live_settings: Arc<LiveSettings>, exchange_name: ExchangeName, testnet: bool) -> Result<Self> {
 		let config = live_settings.config()?;
 		let exchange_config = config.get_exchange(exchange_name)?;
 
-		let base_url = if testnet {
-			"https://api-testnet.bybit.com".to_string()
-		} else {
-			"https://api.bybit.com".to_string()
-		};
-
 		Ok(Self {
 			api_key: exchange_config.api_pubkey.clone(),
 			api_secret: exchange_config.api_secret.expose_secret().to_string(),
-			base_url,
+			testnet,
 			http_client: reqwest::Client::new(),
 		})
 	}
 
-	/// Amend an order's price using orderLinkId
-	pub async fn amend_order_by_link_id(&self, symbol: &str, order_link_id: &str, new_price: f64) -> Result<serde_json::Value> {
-		let timestamp = chrono::Utc::now().timestamp_millis();
-		let recv_window = 5000;
+	pub fn environment(&self) -> BybitEnvironment {
+		match self.testnet {
+			true => BybitEnvironment::Testnet,
+			false => BybitEnvironment::Mainnet,
+		}
+	}
 
-		let params = serde_json::json!({
-			"category": "linear",
-			"symbol": symbol,
-			"orderLinkId": order_link_id,
-			"price": format!("{}", new_price),
-		});
+	pub fn testnet(&self) -> bool {
+		self.testnet
+	}
 
-		let param_str = serde_json::to_string(&params)?;
+	/// `(api_key, api_secret)`, for authenticating websocket connections with the same account.
+	pub fn credentials(&self) -> (String, String) {
+		(self.api_key.clone(), self.api_secret.clone())
+	}
 
-		// Bybit signature: timestamp + api_key + recv_window + param_str
-		let sign_str = format!("{}{}{}{}", timestamp, self.api_key, recv_window, param_str);
+	/// Bybit signature: timestamp + api_key + recv_window + payload, where payload is the JSON body for POST and the query string for GET.
+	fn sign(&self, timestamp: i64, recv_window: u32, payload: &str) -> Result<String> {
+		let sign_str = format!("{}{}{}{}", timestamp, self.api_key, recv_window, payload);
 
 		let mut mac = Hmac::<Sha256>::new_from_slice(self.api_secret.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("Invalid secret key: {}", e))?;
 		mac.update(sign_str.as_bytes());
-		let signature = hex::encode(mac.finalize().into_bytes());
+		Ok(hex::encode(mac.finalize().into_bytes()))
+	}
+
+	/// Sign and POST `params` to a private v5 endpoint, returning the raw response JSON.
+	pub async fn post_signed(&self, endpoint: &str, params: &serde_json::Value) -> Result<serde_json::Value> {
+		let timestamp = chrono::Utc::now().timestamp_millis();
+		let recv_window = 5000;
+
+		let param_str = serde_json::to_string(params)?;
+		let signature = self.sign(timestamp, recv_window, &param_str)?;
 
-		let url = format!("{}/v5/order/amend", self.base_url);
+		let url = format!("{}{}", base_url(self.testnet), endpoint);
 
 		let response = self
 			.http_client
@@ -113,10 +289,10 @@ impl BybitAmendClient {
 			.header("X-BAPI-SIGN", signature)
 			.header("X-BAPI-RECV-WINDOW", recv_window.to_string())
 			.header("Content-Type", "application/json")
-			.json(&params)
+			.body(param_str)
 			.send()
 			.await
-			.context("Failed to send amend request")?;
+			.with_context(|| format!("Failed to send request to {endpoint}"))?;
 
 		let response_text = response.text().await.context("Failed to read response")?;
 		let response_json: serde_json::Value = serde_json::from_str(&response_text).context("Failed to parse response JSON")?;
@@ -124,45 +300,469 @@ impl BybitAmendClient {
 		Ok(response_json)
 	}
 
-	/// Amend an order's price using orderId
-	pub async fn amend_order_by_id(&self, symbol: &str, order_id: &str, new_price: f64) -> Result<serde_json::Value> {
+	/// Sign and GET a private v5 endpoint with `query` (already url-encoded, without the leading `?`), returning the raw response JSON.
+	pub async fn get_signed(&self, endpoint: &str, query: &str) -> Result<serde_json::Value> {
 		let timestamp = chrono::Utc::now().timestamp_millis();
 		let recv_window = 5000;
+		let signature = self.sign(timestamp, recv_window, query)?;
 
-		let params = serde_json::json!({
-			"category": "linear",
-			"symbol": symbol,
-			"orderId": order_id,
-			"price": format!("{}", new_price),
-		});
-
-		let param_str = serde_json::to_string(&params)?;
-
-		// Bybit signature: timestamp + api_key + recv_window + param_str
-		let sign_str = format!("{}{}{}{}", timestamp, self.api_key, recv_window, param_str);
-
-		let mut mac = Hmac::<Sha256>::new_from_slice(self.api_secret.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("Invalid secret key: {}", e))?;
-		mac.update(sign_str.as_bytes());
-		let signature = hex::encode(mac.finalize().into_bytes());
-
-		let url = format!("{}/v5/order/amend", self.base_url);
+		let url = format!("{}{}?{}", base_url(self.testnet), endpoint, query);
 
 		let response = self
 			.http_client
-			.post(&url)
+			.get(&url)
 			.header("X-BAPI-API-KEY", &self.api_key)
 			.header("X-BAPI-TIMESTAMP", timestamp.to_string())
 			.header("X-BAPI-SIGN", signature)
 			.header("X-BAPI-RECV-WINDOW", recv_window.to_string())
-			.header("Content-Type", "application/json")
-			.json(&params)
 			.send()
 			.await
-			.context("Failed to send amend request")?;
+			.with_context(|| format!("Failed to send request to {endpoint}"))?;
 
 		let response_text = response.text().await.context("Failed to read response")?;
 		let response_json: serde_json::Value = serde_json::from_str(&response_text).context("Failed to parse response JSON")?;
 
 		Ok(response_json)
 	}
+
+	/// [Self::post_signed], erroring with [BybitApiError] on non-zero `retCode`.
+	pub async fn post(&self, endpoint: &str, params: &serde_json::Value) -> Result<serde_json::Value> {
+		let response = self.post_signed(endpoint, params).await?;
+		ensure_ret_code(&response, &[])?;
+		Ok(response)
+	}
+
+	/// [Self::get_signed], erroring with [BybitApiError] on non-zero `retCode`.
+	pub async fn get(&self, endpoint: &str, query: &str) -> Result<serde_json::Value> {
+		let response = self.get_signed(endpoint, query).await?;
+		ensure_ret_code(&response, &[])?;
+		Ok(response)
+	}
+
+	/// Place a single order. `params` are those of `/v5/order/create`, `category` included.
+	pub async fn place_order(&self, params: &serde_json::Value) -> Result<PlacedOrder> {
+		let response = self.post("/v5/order/create", params).await.context("Failed to place order")?;
+		Ok(PlacedOrder::from_json(&response["result"]))
+	}
+
+	/// Change an open order. Only the fields set on `amend` are sent.
+	pub async fn amend_order(&self, category: BybitCategory, symbol: &str, order: &OrderRef, amend: &Amend) -> Result<PlacedOrder> {
+		let response = self.post("/v5/order/amend", &amend.to_params(category, symbol, order)).await.context("Failed to amend order")?;
+		Ok(PlacedOrder::from_json(&response["result"]))
+	}
+
+	/// Cancel an order. Orders that are already filled or cancelled are not an error.
+	pub async fn cancel_order(&self, category: BybitCategory, symbol: &str, order: &OrderRef) -> Result<()> {
+		let mut params = serde_json::json!({
+			"category": category.as_str(),
+			"symbol": symbol,
+		});
+		order.insert_into(&mut params);
+		let response = self.post_signed("/v5/order/cancel", &params).await?;
+		ensure_ret_code(&response, &[BybitRetCode::OrderNotExists]).context("Failed to cancel order")
+	}
+
+	/// Cancel an order by its orderLinkId. Orders that are already filled or cancelled are not an error.
+	pub async fn cancel_order_by_link_id(&self, category: BybitCategory, symbol: &str, order_link_id: &str) -> Result<()> {
+		self.cancel_order(category, symbol, &OrderRef::LinkId(order_link_id.to_owned())).await
+	}
+
+	/// Place up to [BATCH_SIZE] orders per request, each `params` being those of `/v5/order/create` without `category`. Results are per order, in the same order as given.
+	pub async fn place_batch(&self, category: BybitCategory, orders: &[serde_json::Value]) -> Result<Vec<Result<PlacedOrder, BybitApiError>>> {
+		self.batch("/v5/order/create-batch", category, orders.to_vec()).await
+	}
+
+	/// Batched [Self::amend_order].
+	pub async fn amend_batch(&self, category: BybitCategory, amends: &[(&str, OrderRef, Amend)]) -> Result<Vec<Result<PlacedOrder, BybitApiError>>> {
+		let requests = amends
+			.iter()
+			.map(|(symbol, order, amend)| {
+				let mut params = amend.to_params(category, symbol, order);
+				params.as_object_mut().unwrap().remove("category");
+				params
+			})
+			.collect();
+		self.batch("/v5/order/amend-batch", category, requests).await
+	}
+
+	/// Batched [Self::cancel_order]. Unlike there, orders that are already gone come back as [BybitRetCode::OrderNotExists].
+	pub async fn cancel_batch(&self, category: BybitCategory, orders: &[(&str, OrderRef)]) -> Result<Vec<Result<PlacedOrder, BybitApiError>>> {
+		let requests = orders
+			.iter()
+			.map(|(symbol, order)| {
+				let mut params = serde_json::json!({ "symbol": symbol });
+				order.insert_into(&mut params);
+				params
+			})
+			.collect();
+		self.batch("/v5/order/cancel-batch", category, requests).await
+	}
+
+	async fn batch(&self, endpoint: &str, category: BybitCategory, requests: Vec<serde_json::Value>) -> Result<Vec<Result<PlacedOrder, BybitApiError>>> {
+		let mut results = Vec::with_capacity(requests.len());
+		for chunk in requests.chunks(BATCH_SIZE) {
+			let params = serde_json::json!({
+				"category": category.as_str(),
+				"request": chunk,
+			});
+			let response = self.post(endpoint, &params).await.with_context(|| format!("{endpoint} failed"))?;
+			let list = response["result"]["list"].as_array().cloned().unwrap_or_default();
+			let ext_info = response["retExtInfo"]["list"].as_array().cloned().unwrap_or_default();
+			for i in 0..chunk.len() {
+				let code = ext_info.get(i).and_then(|e| e["code"].as_i64()).unwrap_or(0);
+				let result = match code {
+					0 => Ok(list.get(i).map(PlacedOrder::from_json).unwrap_or_default()),
+					_ => Err(BybitApiError {
+						code: code.into(),
+						msg: ext_info[i]["msg"].as_str().unwrap_or_default().to_owned(),
+					}),
+				};
+				results.push(result);
+			}
+		}
+		Ok(results)
+	}
+
+	/// Cumulative execution of an order by its orderLinkId, whether it is still open or not. `None` if Bybit doesn't know of it (never placed, or closed long ago).
+	pub async fn order_execution_by_link_id(&self, category: BybitCategory, symbol: &str, order_link_id: &str) -> Result<Option<OrderExecution>> {
+		let query = format!("category={category}&symbol={symbol}&orderLinkId={order_link_id}");
+		let response = self.get_signed("/v5/order/realtime", &query).await?;
+		ensure_ret_code(&response, &[]).context("Failed to query order")?;
+
+		let Some(order) = response["result"]["list"].as_array().and_then(|l| l.first()) else {
+			return Ok(None);
+		};
+		let parse = |field: &str| -> Result<f64> {
+			let s = order[field].as_str().unwrap_or_default();
+			// Bybit sends "" instead of "0" on fresh orders
+			if s.is_empty() {
+				Ok(0.0)
+			} else {
+				s.parse().with_context(|| format!("Failed to parse {field}: {s}"))
+			}
+		};
+		Ok(Some(OrderExecution {
+			cum_exec_qty: parse("cumExecQty")?,
+			cum_exec_value: parse("cumExecValue")?,
+			is_open: matches!(order["orderStatus"].as_str(), Some("New" | "PartiallyFilled" | "Untriggered")),
+		}))
+	}
+
+	/// Signed size of what we hold on the symbol: the one-way position on derivatives, the wallet balance of `base_coin` on spot.
+	pub async fn held_qty(&self, category: BybitCategory, symbol: &str, base_coin: &str) -> Result<f64> {
+		if !category.has_positions() {
+			let response = self.get_signed("/v5/account/wallet-balance", &format!("accountType=UNIFIED&coin={base_coin}")).await?;
+			ensure_ret_code(&response, &[]).context("Failed to query wallet balance")?;
+			let coins = response["result"]["list"].as_array().and_then(|l| l.first()).and_then(|a| a["coin"].as_array());
+			return match coins.and_then(|c| c.iter().find(|c| c["coin"].as_str() == Some(base_coin))) {
+				Some(coin) => parse_str_f64(coin, "walletBalance"),
+				None => Ok(0.0),
+			};
+		}
+
+		let response = self.get_signed("/v5/position/list", &format!("category={category}&symbol={symbol}")).await?;
+		ensure_ret_code(&response, &[]).context("Failed to fetch positions")?;
+		let Some(position) = response["result"]["list"].as_array().and_then(|l| l.first()) else {
+			return Ok(0.0);
+		};
+		let size = parse_str_f64(position, "size")?;
+		Ok(match position["side"].as_str() {
+			Some("Sell") => -size,
+			_ => size,
+		})
+	}
+
+	/// The position on the symbol that longs or shorts go to: the only one in one-way mode, the one of that side in hedge mode.
+	pub async fn side_position(&self, category: BybitCategory, symbol: &str, long: bool) -> Result<SidePosition> {
+		let response = self.get_signed("/v5/position/list", &format!("category={category}&symbol={symbol}")).await?;
+		ensure_ret_code(&response, &[]).context("Failed to fetch positions")?;
+		let positions = response["result"]["list"].as_array().map(Vec::as_slice).unwrap_or_default();
+		// hedge mode lists both sides, even when flat
+		let hedge_mode = positions.iter().any(|p| p["positionIdx"].as_u64().is_some_and(|idx| idx != 0));
+		let idx = match (hedge_mode, long) {
+			(false, _) => 0,
+			(true, true) => 1,
+			(true, false) => 2,
+		};
+		let size = match positions.iter().find(|p| p["positionIdx"].as_u64() == Some(idx as u64)) {
+			Some(position) => parse_str_f64(position, "size")?,
+			None => 0.0,
+		};
+		Ok(SidePosition { idx, size })
+	}
+
+	/// Cancel every open order on the symbol, conditional ones included. Returns how many were cancelled.
+	pub async fn cancel_all_orders(&self, category: BybitCategory, symbol: &str) -> Result<usize> {
+		let mut cancelled = 0;
+		// classic accounts only cancel the kind given in `orderFilter`, defaulting to plain orders
+		for order_filter in ["Order", "StopOrder"] {
+			let params = serde_json::json!({
+				"category": category.as_str(),
+				"symbol": symbol,
+				"orderFilter": order_filter,
+			});
+			let response = self.post_signed("/v5/order/cancel-all", &params).await?;
+			ensure_ret_code(&response, &[]).with_context(|| format!("Failed to cancel {order_filter}s"))?;
+			cancelled += response["result"]["list"].as_array().map_or(0, |l| l.len());
+		}
+		Ok(cancelled)
+	}
+
+	/// Set the same leverage for both sides of the symbol.
+	pub async fn set_leverage(&self, category: BybitCategory, symbol: &str, leverage: u8) -> Result<()> {
+		let params = serde_json::json!({
+			"category": category.as_str(),
+			"symbol": symbol,
+			"buyLeverage": leverage.to_string(),
+			"sellLeverage": leverage.to_string(),
+		});
+		let response = self.post_signed("/v5/position/set-leverage", &params).await?;
+		ensure_ret_code(&response, &[BybitRetCode::LeverageNotModified]).context("Failed to set leverage")
+	}
+
+	/// Switch the symbol between cross and isolated margin. Bybit requires leverage to be passed alongside.
+	/// Only works on classic accounts; unified accounts have margin mode set account-wide.
+	pub async fn switch_margin_type(&self, category: BybitCategory, symbol: &str, margin_type: MarginType, leverage: u8) -> Result<()> {
+		let trade_mode = match margin_type {
+			MarginType::Cross => 0,
+			MarginType::Isolated => 1,
+		};
+		let params = serde_json::json!({
+			"category": category.as_str(),
+			"symbol": symbol,
+			"tradeMode": trade_mode,
+			"buyLeverage": leverage.to_string(),
+			"sellLeverage": leverage.to_string(),
+		});
+		let response = self.post_signed("/v5/position/switch-isolated", &params).await?;
+		ensure_ret_code(&response, &[BybitRetCode::MarginModeNotModified]).context("Failed to switch margin type")
+	}
+
+	/// Exchange-side take-profit and stop-loss on the position, executed as reduce-only market orders. With `partial_size` only that much of the position is covered (`tpslMode` Partial), otherwise all of it (Full).
+	/// `position_idx` is [SidePosition::idx] of the position.
+	pub async fn set_trading_stop(
+		&self,
+		category: BybitCategory,
+		symbol: &str,
+		position_idx: u8,
+		take_profit: Option<&str>,
+		stop_loss: Option<&str>,
+		partial_size: Option<&str>,
+	) -> Result<()> {
+		let mut params = serde_json::json!({
+			"category": category.as_str(),
+			"symbol": symbol,
+			"positionIdx": position_idx,
+			"tpslMode": if partial_size.is_some() { "Partial" } else { "Full" },
+		});
+		if let Some(tp) = take_profit {
+			params["takeProfit"] = tp.into();
+			params["tpTriggerBy"] = "LastPrice".into();
+			params["tpOrderType"] = "Market".into();
+		}
+		if let Some(sl) = stop_loss {
+			params["stopLoss"] = sl.into();
+			params["slTriggerBy"] = "LastPrice".into();
+			params["slOrderType"] = "Market".into();
+		}
+		if let Some(size) = partial_size {
+			if take_profit.is_some() {
+				params["tpSize"] = size.into();
+			}
+			if stop_loss.is_some() {
+				params["slSize"] = size.into();
+			}
+		}
+		let response = self.post_signed("/v5/position/trading-stop", &params).await?;
+		ensure_ret_code(&response, &[]).context("Failed to set position TP/SL")
+	}
+
+	/// Bybit's dead-man switch: cancels all derivatives orders if the private websocket stays disconnected for `time_window_s` (10..=300).
+	/// Unlike Binance's countdown this is account-wide and is kept alive by the websocket connection itself, not by explicit heartbeats.
+	pub async fn set_disconnect_cancel_all(&self, time_window_s: u32) -> Result<()> {
+		let params = serde_json::json!({
+			"product": "DERIVATIVES",
+			"timeWindow": time_window_s.clamp(10, 300),
+		});
+		let response = self.post_signed("/v5/order/disconnected-cancel-all", &params).await?;
+		ensure_ret_code(&response, &[]).context("Failed to set disconnect-cancel-all window")
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OrderExecution {
+	pub cum_exec_qty: f64,
+	/// Sum of qty * price over all executions, in quote.
+	pub cum_exec_value: f64,
+	pub is_open: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SidePosition {
+	/// `positionIdx` to address the position by: 0 in one-way mode, 1 for long and 2 for short in hedge mode.
+	pub idx: u8,
+	/// Absolute.
+	pub size: f64,
+}
+
+/// Max orders per batch request. Derivatives take up to 20, but spot only 10.
+pub const BATCH_SIZE: usize = 10;
+
+/// Which of its two ids an order is referred to by.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OrderRef {
+	Id(String),
+	LinkId(String),
+}
+impl OrderRef {
+	fn insert_into(&self, params: &mut serde_json::Value) {
+		match self {
+			Self::Id(id) => params["orderId"] = id.as_str().into(),
+			Self::LinkId(link_id) => params["orderLinkId"] = link_id.as_str().into(),
+		}
+	}
+}
+
+/// Fields of `/v5/order/amend`. Those left `None` are kept as they are on the order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Amend {
+	pub price: Option<String>,
+	pub qty: Option<String>,
+	pub trigger_price: Option<String>,
+	pub take_profit: Option<String>,
+	pub stop_loss: Option<String>,
+}
+impl Amend {
+	fn to_params(&self, category: BybitCategory, symbol: &str, order: &OrderRef) -> serde_json::Value {
+		let mut params = serde_json::json!({
+			"category": category.as_str(),
+			"symbol": symbol,
+		});
+		order.insert_into(&mut params);
+		let fields = [
+			("price", &self.price),
+			("qty", &self.qty),
+			("triggerPrice", &self.trigger_price),
+			("takeProfit", &self.take_profit),
+			("stopLoss", &self.stop_loss),
+		];
+		for (key, value) in fields {
+			if let Some(value) = value {
+				params[key] = value.as_str().into();
+			}
+		}
+		params
+	}
+}
+
+/// Ids Bybit acknowledges a placed, amended or cancelled order with.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PlacedOrder {
+	pub order_id: String,
+	pub order_link_id: String,
+}
+impl PlacedOrder {
+	fn from_json(result: &serde_json::Value) -> Self {
+		Self {
+			order_id: result["orderId"].as_str().unwrap_or_default().to_owned(),
+			order_link_id: result["orderLinkId"].as_str().unwrap_or_default().to_owned(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BybitRetCode {
+	/// 10001. Among others, what a PostOnly that would cross gets rejected with.
+	ParamsError,
+	/// 10006
+	RateLimited,
+	/// 110001: order doesn't exist, or is too late to cancel
+	OrderNotExists,
+	/// 110007
+	InsufficientBalance,
+	/// 110017: reduce-only order would increase the position, or there is none
+	ReduceOnlyRejected,
+	/// 110026: cross/isolated margin mode is not modified
+	MarginModeNotModified,
+	/// 110043: leverage not modified
+	LeverageNotModified,
+	/// 170213: spot equivalent of [Self::OrderNotExists]
+	SpotOrderNotExists,
+	Other(i64),
+}
+impl From<i64> for BybitRetCode {
+	fn from(code: i64) -> Self {
+		match code {
+			10001 => Self::ParamsError,
+			10006 => Self::RateLimited,
+			110001 => Self::OrderNotExists,
+			110007 => Self::InsufficientBalance,
+			110017 => Self::ReduceOnlyRejected,
+			110026 => Self::MarginModeNotModified,
+			110043 => Self::LeverageNotModified,
+			170213 => Self::SpotOrderNotExists,
+			other => Self::Other(other),
+		}
+	}
+}
+impl From<BybitRetCode> for i64 {
+	fn from(code: BybitRetCode) -> Self {
+		match code {
+			BybitRetCode::ParamsError => 10001,
+			BybitRetCode::RateLimited => 10006,
+			BybitRetCode::OrderNotExists => 110001,
+			BybitRetCode::InsufficientBalance => 110007,
+			BybitRetCode::ReduceOnlyRejected => 110017,
+			BybitRetCode::MarginModeNotModified => 110026,
+			BybitRetCode::LeverageNotModified => 110043,
+			BybitRetCode::SpotOrderNotExists => 170213,
+			BybitRetCode::Other(code) => code,
+		}
+	}
+}
+
+/// Non-zero `retCode` of a v5 response. Is at the root of errors returned by [BybitSignedClient], so can be recovered with `downcast_ref`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BybitApiError {
+	pub code: BybitRetCode,
+	pub msg: String,
+}
+impl std::fmt::Display for BybitApiError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} (code: {})", self.msg, i64::from(self.code))
+	}
+}
+impl std::error::Error for BybitApiError {}
+
+/// Errors with [BybitApiError] unless `retCode` of the response is 0 or one of `tolerated`. Cancels tolerating [BybitRetCode::OrderNotExists] tolerate its spot equivalent too.
+fn ensure_ret_code(response: &serde_json::Value, tolerated: &[BybitRetCode]) -> Result<()> {
+	let ret_code = response
+		.get("retCode")
+		.and_then(|c| c.as_i64())
+		.ok_or_else(|| color_eyre::eyre::eyre!("No retCode in response: {response}"))?;
+	if ret_code == 0 {
+		return Ok(());
+	}
+	let code = BybitRetCode::from(ret_code);
+	if tolerated.contains(&code) || (code == BybitRetCode::SpotOrderNotExists && tolerated.contains(&BybitRetCode::OrderNotExists)) {
+		return Ok(());
+	}
+	let msg = response.get("retMsg").and_then(|m| m.as_str()).unwrap_or_default().to_owned();
+	Err(BybitApiError { code, msg }.into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ret_code_errors() {
+		let response = serde_json::json!({ "retCode": 170213, "retMsg": "Order does not exist." });
+		assert!(ensure_ret_code(&response, &[BybitRetCode::OrderNotExists]).is_ok());
+
+		let e = ensure_ret_code(&response, &[]).unwrap_err();
+		let api_error = e.downcast_ref::<BybitApiError>().unwrap();
+		assert_eq!(api_error.code, BybitRetCode::SpotOrderNotExists);
+		insta::assert_snapshot!(api_error, @"Order does not exist. (code: 170213)");
+	}
 }
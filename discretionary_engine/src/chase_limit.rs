@@ -1,41 +1,81 @@
+use std::collections::HashMap;
+
 use color_eyre::eyre::{Context, Result, bail};
-use nautilus_bybit::{
-	common::enums::BybitProductType,
-	http::{
-		client::{BybitHttpClient, BybitRawHttpClient},
-		query::BybitTickersParamsBuilder,
-	},
-};
 use tokio::time::{Duration, sleep};
-use tracing::info;
+use tracing::{info, warn};
 use v_utils::{log, trades::Timeframe};
 
+use crate::{
+	bybit_common::{BybitApiError, BybitCategory, BybitRetCode, BybitSignedClient, OrderExecution, fetch_book_ticker},
+	ws_chase_limit::{format_price, format_qty},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChaseExecution {
+	pub filled: f64,
+	/// Volume-weighted over all the orders the chase went through. `None` if nothing got filled.
+	pub avg_price: Option<f64>,
+}
+
+/// Executions of every order the chase has placed, by orderLinkId
+#[derive(Clone, Debug, Default)]
+struct ChaseFills(HashMap<String, OrderExecution>);
+impl ChaseFills {
+	fn filled(&self) -> f64 {
+		self.0.values().map(|e| e.cum_exec_qty).sum()
+	}
+
+	fn execution(&self) -> ChaseExecution {
+		let filled = self.filled();
+		let value: f64 = self.0.values().map(|e| e.cum_exec_value).sum();
+		ChaseExecution {
+			filled,
+			avg_price: (filled > 0.0).then(|| value / filled),
+		}
+	}
+
+	/// Pulls the latest cumulative execution of the order from the exchange. Returns whether it's still open.
+	async fn refresh(&mut self, signed_client: &BybitSignedClient, category: BybitCategory, symbol: &str, order_link_id: &str) -> Result<bool> {
+		match signed_client.order_execution_by_link_id(category, symbol, order_link_id).await? {
+			Some(execution) => {
+				self.0.insert(order_link_id.to_owned(), execution);
+				Ok(execution.is_open)
+			}
+			None => {
+				warn!("Exchange doesn't know of order {order_link_id}, assuming it never got filled");
+				Ok(false)
+			}
+		}
+	}
+}
+
 /// Executes an order using a chase-limit strategy
 ///
 /// This algorithm places a single limit order for the full quantity and continuously
 /// cancels and replaces it at a better price as the market moves (one tick better than
-/// the current best bid/ask, inside the spread if possible).
+/// the current best bid/ask, inside the spread if possible). Each replacement is only for
+/// what the previous ones haven't filled.
 /// When duration expires, any unfilled quantity is executed with a market order.
 ///
 /// # Arguments
-/// * `raw_client` - Raw Bybit HTTP client for market data
-/// * `client` - Authenticated Bybit HTTP client for order placement
+/// * `signed_client` - Authenticated client for order placement, cancels and status queries
+/// * `category` - Bybit category the symbol trades in
 /// * `symbol` - Trading symbol (Bybit format, e.g., "BTCUSDT")
 /// * `side` - Order side ("Buy" or "Sell")
-/// * `target_qty` - Total quantity to execute
+/// * `target_qty` - Total quantity to execute, in base (spot included)
 /// * `qty_step` - Minimum quantity increment for the instrument
 /// * `price_tick` - Minimum price increment for the instrument
 /// * `duration` - Optional duration to spread the execution over
 pub async fn execute_chase_limit(
-	raw_client: &BybitRawHttpClient,
-	client: &BybitHttpClient,
+	signed_client: &BybitSignedClient,
+	category: BybitCategory,
 	symbol: &str,
 	side: &str,
 	target_qty: f64,
 	qty_step: f64,
 	price_tick: f64,
 	duration: Option<Timeframe>,
-) -> Result<f64> {
+) -> Result<ChaseExecution> {
 	log!("Starting chase-limit execution for {} {} {}", side, target_qty, symbol);
 
 	// Calculate execution parameters based on duration
@@ -51,14 +91,29 @@ pub async fn execute_chase_limit(
 		(Duration::from_millis(500), None)
 	};
 
-	let base_order_link_id = format!("chase-{}", uuid::Uuid::new_v4());
+	// orderLinkId must be <= 45 chars, so the hyphenated uuid wouldn't leave room for the suffixes
+	let base_order_link_id = format!("c{}", uuid::Uuid::new_v4().simple());
 	let mut current_order_link_id: Option<String> = None;
 	let mut last_order_price: Option<f64> = None;
+	let mut fills = ChaseFills::default();
 	let mut iteration = 0;
 
 	loop {
 		iteration += 1;
 
+		// See if the resting order got filled since the last iteration
+		if let Some(ref order_link_id) = current_order_link_id
+			&& !fills.refresh(signed_client, category, symbol, order_link_id).await?
+		{
+			current_order_link_id = None;
+			last_order_price = None;
+		}
+		let remaining_qty = target_qty - fills.filled();
+		if remaining_qty < qty_step / 2.0 {
+			log!("Order fully filled");
+			break;
+		}
+
 		// Check if duration has expired
 		if let Some(end) = end_time {
 			if std::time::Instant::now() >= end {
@@ -66,34 +121,39 @@ pub async fn execute_chase_limit(
 
 				// Cancel any existing limit order first using orderLinkId
 				if let Some(ref order_link_id) = current_order_link_id {
-					let cancel_request = serde_json::json!({
-						"category": "linear",
+					signed_client.cancel_order_by_link_id(category, symbol, order_link_id).await?;
+					// could have filled some more before the cancel went through
+					fills.refresh(signed_client, category, symbol, order_link_id).await?;
+				}
+
+				let remaining_qty = target_qty - fills.filled();
+				if remaining_qty >= qty_step / 2.0 {
+					let final_order_link_id = format!("{}-final", base_order_link_id);
+					let mut market_request = serde_json::json!({
+						"category": category.as_str(),
 						"symbol": symbol,
-						"orderLinkId": order_link_id,
+						"side": side,
+						"orderType": "Market",
+						"qty": format_qty(remaining_qty, qty_step),
+						"timeInForce": "IOC",
+						"orderLinkId": &final_order_link_id,
 					});
+					if let Some(market_unit) = category.market_unit() {
+						market_request["marketUnit"] = market_unit.into();
+					}
 
-					// Best effort cancel
-					let _ = client.place_order(&cancel_request).await;
-				}
-
-				// Place market order for the full amount
-				// The exchange will only fill what's remaining unfilled
-				let market_request = serde_json::json!({
-					"category": "linear",
-					"symbol": symbol,
-					"side": side,
-					"orderType": "Market",
-					"qty": format!("{}", target_qty),
-					"timeInForce": "IOC",
-					"orderLinkId": format!("{}-final", base_order_link_id),
-				});
-
-				let market_response = client.place_order(&market_request).await.context("Failed to place final market order")?;
-
-				if market_response.ret_code == 0 {
-					log!("Final market order placed successfully");
-				} else {
-					log!("Final market order result: {} (code: {})", market_response.ret_msg, market_response.ret_code);
+					match signed_client.place_order(&market_request).await {
+						Ok(_) => {
+							log!("Final market order placed successfully");
+							// IOC resolves immediately, but give the exchange a moment to publish the execution
+							sleep(Duration::from_millis(200)).await;
+							fills.refresh(signed_client, category, symbol, &final_order_link_id).await?;
+						}
+						Err(e) => match e.downcast_ref::<BybitApiError>() {
+							Some(api_error) => log!("Final market order result: {}", api_error),
+							None => return Err(e.wrap_err("Failed to place final market order")),
+						},
+					}
 				}
 
 				break;
@@ -101,21 +161,8 @@ pub async fn execute_chase_limit(
 		}
 
 		// Get current best bid/ask
-		let ticker_params = BybitTickersParamsBuilder::default()
-			.category(BybitProductType::Linear)
-			.symbol(symbol.to_string())
-			.build()
-			.context("Failed to build ticker params")?;
-
-		let ticker_response: nautilus_bybit::http::models::BybitTickersLinearResponse = raw_client
-			.get_tickers::<nautilus_bybit::http::models::BybitTickersLinearResponse>(&ticker_params)
-			.await
-			.context("Failed to fetch ticker data")?;
-
-		let ticker = ticker_response.result.list.get(0).ok_or_else(|| color_eyre::eyre::eyre!("No ticker data found for {}", symbol))?;
-
-		let bid_price: f64 = ticker.bid1_price.parse().context("Failed to parse bid price")?;
-		let ask_price: f64 = ticker.ask1_price.parse().context("Failed to parse ask price")?;
+		let ticker = fetch_book_ticker(signed_client.testnet(), category, symbol).await.context("Failed to fetch ticker data")?;
+		let (bid_price, ask_price) = (ticker.bid, ticker.ask);
 
 		// Determine our limit price
 		// - For buys: try to place one tick above current bid, but not crossing the spread
@@ -148,47 +195,50 @@ pub async fn execute_chase_limit(
 			if let Some(ref old_order_link_id) = current_order_link_id {
 				log!("[{}] Cancelling previous order to update price", iteration);
 
-				let cancel_request = serde_json::json!({
-					"category": "linear",
-					"symbol": symbol,
-					"orderLinkId": old_order_link_id,
-				});
-
-				// Best effort cancel - don't fail if order is already filled
-				let _ = client.place_order(&cancel_request).await;
+				// Already filled orders are not an error
+				signed_client.cancel_order_by_link_id(category, symbol, old_order_link_id).await?;
+				// fills between our last check and the cancel must not be placed again
+				fills.refresh(signed_client, category, symbol, old_order_link_id).await?;
+				current_order_link_id = None;
+			}
 
-				// Wait a moment for cancel to process
-				sleep(Duration::from_millis(100)).await;
+			let remaining_qty = target_qty - fills.filled();
+			if remaining_qty < qty_step / 2.0 {
+				log!("Order fully filled");
+				break;
 			}
 
 			// Create new orderLinkId for this order
 			let new_order_link_id = format!("{}-{}", base_order_link_id, iteration);
 
 			// Place new order at updated price
-			log!("[{}] Placing {} limit order: {} @ {}", iteration, side, target_qty, limit_price);
+			log!("[{}] Placing {} limit order: {} @ {}", iteration, side, remaining_qty, limit_price);
 
 			let order_request = serde_json::json!({
-				"category": "linear",
+				"category": category.as_str(),
 				"symbol": symbol,
 				"side": side,
 				"orderType": "Limit",
-				"qty": format!("{}", target_qty),
-				"price": format!("{}", limit_price),
+				"qty": format_qty(remaining_qty, qty_step),
+				"price": format_price(limit_price, price_tick),
 				"timeInForce": "PostOnly",
 				"orderLinkId": &new_order_link_id,
 			});
 
-			let order_response = client.place_order(&order_request).await.context("Failed to place chase-limit order")?;
-
-			if order_response.ret_code == 0 {
-				current_order_link_id = Some(new_order_link_id);
-				last_order_price = Some(limit_price);
-				info!("[{}] Order placed successfully", iteration);
-			} else if order_response.ret_code == 10001 || order_response.ret_msg.contains("post only") || order_response.ret_msg.contains("would cross") {
-				info!("[{}] PostOnly rejected (would cross spread): {}, will retry", iteration, order_response.ret_msg);
-				// Don't update last_order_price or current_order_link_id, will retry next iteration
-			} else {
-				log!("Order placement warning: {} (code: {})", order_response.ret_msg, order_response.ret_code);
+			match signed_client.place_order(&order_request).await {
+				Ok(_) => {
+					current_order_link_id = Some(new_order_link_id);
+					last_order_price = Some(limit_price);
+					info!("[{}] Order placed successfully", iteration);
+				}
+				Err(e) => match e.downcast_ref::<BybitApiError>() {
+					Some(api_error) if api_error.code == BybitRetCode::ParamsError || api_error.msg.contains("post only") || api_error.msg.contains("would cross") => {
+						info!("[{}] PostOnly rejected (would cross spread): {}, will retry", iteration, api_error.msg);
+						// Don't update last_order_price or current_order_link_id, will retry next iteration
+					}
+					Some(api_error) => log!("Order placement warning: {}", api_error),
+					None => return Err(e.wrap_err("Failed to place chase-limit order")),
+				},
 			}
 		} else {
 			info!("[{}] Price unchanged, keeping order at {}", iteration, last_order_price.unwrap_or(0.0));
@@ -200,10 +250,21 @@ pub async fn execute_chase_limit(
 		// Safety check: don't run forever
 		if iteration > 10000 {
 			log!("Max iterations reached, stopping");
+			if let Some(ref order_link_id) = current_order_link_id {
+				signed_client.cancel_order_by_link_id(category, symbol, order_link_id).await?;
+				fills.refresh(signed_client, category, symbol, order_link_id).await?;
+			}
 			break;
 		}
 	}
 
-	log!("Chase-limit execution completed for {} {}", target_qty, symbol);
-	Ok(target_qty)
+	let execution = fills.execution();
+	log!(
+		"Chase-limit execution completed: filled {} out of {} {}, avg price {:?}",
+		execution.filled,
+		target_qty,
+		symbol,
+		execution.avg_price
+	);
+	Ok(execution)
 }
@@ -21,6 +21,8 @@ pub struct AppConfig {
 	pub comparison_offset_h: u32,
 	#[settings(flatten)]
 	pub risk: Option<RiskConfig>,
+	#[settings(flatten)]
+	pub reconciliation: Option<ReconciliationConfig>,
 }
 
 #[derive(Clone, Debug, v_macros::MyConfigPrimitives)]
@@ -31,6 +33,27 @@ pub struct ExchangeConfig {
 	pub passphrase: Option<SecretString>,
 }
 
+/// How the engine treats discrepancies between its own knowledge and the exchange. See [crate::reconcile].
+#[derive(Clone, Debug, v_macros::MyConfigPrimitives, v_macros::SettingsNested)]
+pub struct ReconciliationConfig {
+	#[settings(default = "60")]
+	pub interval_s: u64,
+	/// Cancel open orders the engine has placed for positions no running engine serves anymore.
+	#[settings(default = "false")]
+	pub cancel_orphaned: bool,
+	/// Followup protocols to attach to positions opened outside of the engine, separated by whitespace (eg "ts:p0.5 sar:t5m:s0.07:i0.02:m0.15"). Adopted only once seen unchanged over two consecutive polls. If not set, such positions are only reported.
+	pub adopt_with: Option<String>,
+}
+impl Default for ReconciliationConfig {
+	fn default() -> Self {
+		Self {
+			interval_s: 60,
+			cancel_orphaned: false,
+			adopt_with: None,
+		}
+	}
+}
+
 #[derive(Clone, Debug, Default, v_macros::MyConfigPrimitives, v_macros::SettingsNested)]
 pub struct RiskConfig {
 	#[settings(flatten)]
@@ -0,0 +1,200 @@
+//! Talking to a running position from the outside.
+//!
+//! Each running position listens on a unix socket in `positions_dir`, named `<asset>_<position id>.sock`. Requests and responses are single lines of JSON.
+use std::{
+	collections::HashSet,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Arc,
+};
+
+use color_eyre::eyre::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	net::{UnixListener, UnixStream},
+};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{config::LiveSettings, positions::PositionSpec, protocols::Protocol};
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct UpdateProtocolArgs {
+	/// Running position to update, either by its coin (e.g. "BTC") or by (a prefix of) its id.
+	position: String,
+	/// New spec of the protocol, e.g. "ts:p0.7". Must be of a protocol the position is already running. Its weight is kept as it was started with.
+	spec: String,
+	/// Spec the protocol was started with. Only needed if the position runs several protocols of the same kind.
+	#[arg(long)]
+	id: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct UpdateRequest {
+	spec: String,
+	id: Option<String>,
+}
+
+pub(crate) async fn main(args: UpdateProtocolArgs, live_settings: Arc<LiveSettings>) -> Result<()> {
+	let positions_dir = live_settings.config()?.positions_dir;
+	let socket_path = find_socket(&positions_dir, &args.position)?;
+
+	let stream = match UnixStream::connect(&socket_path).await {
+		Ok(stream) => stream,
+		Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+			// left over from a run that didn't get to clean up
+			let _ = std::fs::remove_file(&socket_path);
+			bail!("Position {} is no longer running", args.position);
+		}
+		Err(e) => return Err(e).wrap_err_with(|| format!("Failed to connect to {socket_path:?}")),
+	};
+	let (read, mut write) = stream.into_split();
+	let request = UpdateRequest { spec: args.spec, id: args.id };
+	write.write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes()).await?;
+
+	let mut response = String::new();
+	BufReader::new(read).read_line(&mut response).await?;
+	match serde_json::from_str::<Result<String, String>>(&response)? {
+		Ok(updated) => println!("Updated {updated}"),
+		Err(e) => bail!("Position refused the update: {e}"),
+	}
+	Ok(())
+}
+
+fn find_socket(positions_dir: &Path, selector: &str) -> Result<PathBuf> {
+	let mut matching = Vec::new();
+	for entry in std::fs::read_dir(positions_dir)? {
+		let path = entry?.path();
+		if path.extension().is_none_or(|ext| ext != "sock") {
+			continue;
+		}
+		let Some((asset, id)) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.split_once('_')) else {
+			continue;
+		};
+		if asset.eq_ignore_ascii_case(selector) || id.starts_with(selector) {
+			matching.push(path);
+		}
+	}
+	match matching.len() {
+		0 => bail!("No running position matches {selector}"),
+		1 => Ok(matching.remove(0)),
+		_ => bail!("Several running positions match {selector}, pick one by its id: {matching:?}"),
+	}
+}
+
+/// Positions some running engine (this one included) is currently serving, as `(asset, position id)`. Sockets nobody listens on are left over from runs that didn't get to clean up, and are removed.
+pub(crate) async fn live_positions(positions_dir: &Path) -> Result<HashSet<(String, Uuid)>> {
+	let mut live = HashSet::new();
+	for entry in std::fs::read_dir(positions_dir)? {
+		let path = entry?.path();
+		if path.extension().is_none_or(|ext| ext != "sock") {
+			continue;
+		}
+		let Some((asset, id)) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.split_once('_')) else {
+			continue;
+		};
+		let Ok(id) = Uuid::parse_str(id) else { continue };
+		match UnixStream::connect(&path).await {
+			Ok(_) => {
+				live.insert((asset.to_owned(), id));
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+				let _ = std::fs::remove_file(&path);
+			}
+			// can't tell, so better assume it's someone's
+			Err(e) => {
+				warn!("Failed to probe {path:?}: {e}");
+				live.insert((asset.to_owned(), id));
+			}
+		}
+	}
+	Ok(live)
+}
+
+/// Removes the socket once the position stops serving it.
+#[derive(Debug)]
+struct SocketFile(PathBuf);
+impl Drop for SocketFile {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.0);
+	}
+}
+
+/// Accepts updates to the position's protocols for as long as it's polled. `protocols` share params with the ones the position runs, so nothing else needs to be notified.
+#[instrument(skip_all, fields(position_id = %spec.id))]
+pub(crate) async fn serve(positions_dir: PathBuf, spec: &PositionSpec, protocols: Vec<Protocol>) -> Result<()> {
+	let socket = SocketFile(positions_dir.join(format!("{}_{}.sock", spec.asset, spec.id)));
+	let listener = UnixListener::bind(&socket.0).wrap_err_with(|| format!("Failed to listen on {:?}", socket.0))?;
+	info!("Accepting protocol updates on {:?}", socket.0);
+
+	loop {
+		let (stream, _) = listener.accept().await?;
+		let (read, mut write) = stream.into_split();
+		let mut line = String::new();
+		match BufReader::new(read).read_line(&mut line).await {
+			// just being checked for liveness, see [live_positions]
+			Ok(0) => continue,
+			Ok(_) => {}
+			Err(e) => {
+				warn!("Failed to read protocol update request: {e}");
+				continue;
+			}
+		}
+		let response = match serde_json::from_str::<UpdateRequest>(&line) {
+			Ok(request) => apply_update(&protocols, &request).map_err(|e| e.to_string()),
+			Err(e) => Err(format!("Malformed request: {e}")),
+		};
+		match &response {
+			Ok(updated) => info!("Updated {updated}"),
+			Err(e) => warn!("Refused protocol update: {e}"),
+		}
+		let _ = write.write_all(format!("{}\n", serde_json::to_string(&response)?).as_bytes()).await;
+	}
+}
+
+fn apply_update(protocols: &[Protocol], request: &UpdateRequest) -> Result<String> {
+	let new = Protocol::from_str(&request.spec)?;
+	let targets: Vec<&Protocol> = protocols.iter().filter(|p| p.same_kind(&new) && request.id.as_ref().is_none_or(|id| p.id() == *id)).collect();
+	// same spec can be used both for acquisition and followup, in which case both are updated
+	let ids: HashSet<String> = targets.iter().map(|p| p.id()).collect();
+	let id = match ids.len() {
+		0 => bail!("Position runs no such protocol"),
+		1 => ids.into_iter().next().unwrap(),
+		_ => bail!("Position runs several protocols of this kind, pick one with `--id`: {ids:?}"),
+	};
+
+	let old = targets[0].signature();
+	for protocol in targets {
+		protocol.update_params(new.params())?;
+	}
+	Ok(format!("{id}: {old} -> {}", new.signature()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::protocols::interpret_protocol_specs;
+
+	#[test]
+	fn update() {
+		let protocols = interpret_protocol_specs(vec!["ts:p0.5".to_owned(), "ts:p1".to_owned(), "tpsl:t0.47,0.45:s0.52".to_owned()]).unwrap();
+		let request = |spec: &str, id: Option<&str>| UpdateRequest {
+			spec: spec.to_owned(),
+			id: id.map(str::to_owned),
+		};
+
+		assert!(apply_update(&protocols, &request("ts:p0.7", None)).is_err());
+		assert!(apply_update(&protocols, &request("sar:s0.07:i0.02:m0.15:t1m", None)).is_err());
+		let ts_id = protocols[1].id();
+		apply_update(&protocols, &request("ts:p0.7", Some(&ts_id))).unwrap();
+		assert_eq!(protocols[1].id(), ts_id);
+		assert_ne!(protocols[1].signature(), ts_id);
+		assert_eq!(protocols[0].signature(), protocols[0].id());
+
+		// fills are tracked per TP level
+		assert!(apply_update(&protocols, &request("tpsl:t0.46:s0.52", None)).is_err());
+		apply_update(&protocols, &request("tpsl:t0.46,0.44:s0.53", None)).unwrap();
+		assert_ne!(protocols[2].signature(), protocols[2].id());
+	}
+}
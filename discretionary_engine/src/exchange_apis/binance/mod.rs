@@ -29,16 +29,19 @@ use tokio::{
 use tracing::{debug, instrument, warn};
 use url::Url;
 use uuid::Uuid;
-use v_utils::{Percent, trades::Ohlc};
+use v_utils::{
+	Percent,
+	trades::{Ohlc, Side},
+};
 
 use super::{
 	hub::{ExchangeToHub, HubToExchange},
 	order_types::{ConceptualMarket, ConceptualOrderType, IdRequirements},
 };
 use crate::{
-	MAX_CONNECTION_FAILURES, PositionOrderId,
+	ClientOrderId, MAX_CONNECTION_FAILURES, PositionOrderId,
 	config::LiveSettings,
-	exchange_apis::{Market, order_types::Order},
+	exchange_apis::{MarginType, Market, Symbol, order_types::Order},
 	utils::{deser_reqwest, report_connection_problem, unexpected_response_str},
 };
 type HmacSha256 = Hmac<Sha256>;
@@ -46,12 +49,31 @@ type HmacSha256 = Hmac<Sha256>;
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct BinanceExchange {
 	pub binance_futures_info: BinanceExchangeFutures,
+	/// Whether the futures account runs in dual-side (hedge) position mode. Detected once on init; switching it while the engine is running is not supported.
+	pub hedge_mode: bool,
+	/// Orders the runtime currently has deployed. Mirrors its own knowledge, so that it can be reconciled against the exchange.
+	pub engine_orders: Vec<BinanceOrder>,
+	/// Signed position qty accumulated through the engine's own fills, keyed the same way as [get_futures_positions].
+	pub engine_positions: HashMap<(String, BinancePositionSide), f64>,
 }
 impl BinanceExchange {
 	#[instrument(skip_all)]
 	pub async fn init(live_settings: Arc<LiveSettings>) -> Result<Self> {
+		use secrecy::ExposeSecret;
+		use v_exchanges::ExchangeName;
+
 		let binance_futures_info = BinanceExchangeFutures::init(live_settings.clone()).await?;
-		Ok(Self { binance_futures_info })
+
+		let config = live_settings.config()?;
+		let binance_config = config.get_exchange(ExchangeName::Binance)?;
+		let hedge_mode = get_position_mode(binance_config.api_pubkey.clone(), binance_config.api_secret.expose_secret().to_owned()).await?;
+		info!(hedge_mode, "Detected Binance Futures position mode");
+
+		Ok(Self {
+			binance_futures_info,
+			hedge_mode,
+			..Default::default()
+		})
 	}
 
 	// Finds all pairs with the given base asset, returns absolute minimal order trade size for it.
@@ -200,6 +222,7 @@ pub async fn get_balance(key: String, secret: String, market: Market) -> Result<
 
 			Ok(total_balance)
 		}
+		Market::BybitFutures => bail!("Not a Binance market: {market:?}"),
 	}
 }
 
@@ -253,18 +276,163 @@ pub async fn close_orders(key: String, secret: String, orders: &[BinanceOrder])
 	Ok(())
 }
 
+/// Sets initial leverage for the symbol. Validated against the leverage brackets beforehand, so that we fail with a readable error instead of whatever binance decides to return.
+#[instrument(skip(key, secret))]
+pub async fn set_leverage(key: String, secret: String, symbol: &str, leverage: u8, notional: f64) -> Result<()> {
+	let max_leverage = max_leverage_for_notional(key.clone(), secret.clone(), symbol, notional).await?;
+	if leverage as u32 > max_leverage {
+		bail!("Requested leverage {leverage}x exceeds maximum of {max_leverage}x allowed for {notional} notional on {symbol}");
+	}
+
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/leverage")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol.to_owned());
+	params.insert("leverage", leverage.to_string());
+	params.insert("recvWindow", "60000".to_owned());
+
+	let r = signed_request(Method::POST, url.as_str(), params, key, secret).await?;
+	let _: Value = deser_reqwest(r).await?;
+	Ok(())
+}
+
+#[instrument(skip(key, secret))]
+pub async fn set_margin_type(key: String, secret: String, symbol: &str, margin_type: MarginType) -> Result<()> {
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/marginType")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol.to_owned());
+	params.insert("marginType", BinanceMarginType::from(margin_type).to_string());
+	params.insert("recvWindow", "60000".to_owned());
+
+	match signed_request(Method::POST, url.as_str(), params, key, secret).await {
+		Ok(r) => {
+			let _: Value = deser_reqwest(r).await?;
+			Ok(())
+		}
+		Err(e) => {
+			// -4046: "No need to change margin type." Binance errors instead of no-op'ing.
+			let inner_unexpected_response_str = e.chain().last().unwrap();
+			if let Ok(error_value) = serde_json::from_str::<serde_json::Value>(&inner_unexpected_response_str.to_string())
+				&& let Some(error_code) = error_value.get("code")
+				&& error_code == -4046
+			{
+				debug!("Margin type is already {margin_type:?}");
+				return Ok(());
+			}
+			Err(e)
+		}
+	}
+}
+
+/// Max initial leverage of the bracket the given notional falls into.
+#[instrument(skip(key, secret))]
+pub async fn max_leverage_for_notional(key: String, secret: String, symbol: &str, notional: f64) -> Result<u32> {
+	let url = LeverageBracketsResponse::get_url();
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol.to_owned());
+	params.insert("recvWindow", "60000".to_owned());
+
+	let r = signed_request(Method::GET, url.as_str(), params, key, secret).await?;
+	let response: Vec<LeverageBracketsResponse> = deser_reqwest(r).await?;
+	let brackets = match response.into_iter().find(|b| b.symbol == symbol) {
+		Some(b) => b.brackets,
+		None => bail!("No leverage brackets returned for {symbol}"),
+	};
+	match brackets.iter().find(|b| b.notional_floor <= notional && notional < b.notional_cap) {
+		Some(b) => Ok(b.initial_leverage),
+		None => bail!("Notional {notional} is outside of all leverage brackets of {symbol}"),
+	}
+}
+
+/// Arms (or re-arms) auto-cancel of all open orders on the symbol after `countdown`. `None` disarms it.
+/// Heartbeat of the dead-man switch: as long as the engine is alive, it keeps pushing the countdown back.
+#[instrument(skip(key, secret))]
+pub async fn countdown_cancel_all(key: String, secret: String, symbol: &str, countdown: Option<std::time::Duration>) -> Result<()> {
+	let base_url = Market::BinanceFutures.get_base_url();
+	let url = base_url.join("/fapi/v1/countdownCancelAll")?;
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol.to_owned());
+	params.insert("countdownTime", countdown.map(|c| c.as_millis()).unwrap_or(0).to_string());
+	params.insert("recvWindow", "60000".to_owned());
+
+	let r = signed_request(Method::POST, url.as_str(), params, key, secret).await?;
+	let _: Value = deser_reqwest(r).await?;
+	Ok(())
+}
+
+/// All open orders on the futures account, including ones not placed by the engine.
+#[instrument(skip_all)]
+pub async fn get_open_orders(key: String, secret: String) -> Result<Vec<FuturesPositionResponse>> {
+	let base_url = Market::BinanceFutures.get_base_url();
+	let url = base_url.join("/fapi/v1/openOrders")?;
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("recvWindow", "60000".to_owned());
+
+	let r = signed_request(Method::GET, url.as_str(), params, key, secret).await?;
+	let open_orders: Vec<FuturesPositionResponse> = deser_reqwest(r).await?;
+	Ok(open_orders)
+}
+
+/// Returns `true` if the account is in dual-side (hedge) position mode.
+#[instrument(skip_all)]
+pub async fn get_position_mode(key: String, secret: String) -> Result<bool> {
+	let url = PositionModeResponse::get_url();
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("recvWindow", "60000".to_owned());
+
+	let r = signed_request(Method::GET, url.as_str(), params, key, secret).await?;
+	let response: PositionModeResponse = deser_reqwest(r).await?;
+	Ok(response.dual_side_position)
+}
+
+#[instrument(skip(key, secret))]
+pub async fn cancel_order(key: String, secret: String, symbol: &str, order_id: i64) -> Result<()> {
+	let url = FuturesPositionResponse::get_url();
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol.to_owned());
+	params.insert("orderId", order_id.to_string());
+	params.insert("recvWindow", "60000".to_owned());
+
+	let r = signed_request(Method::DELETE, url.as_str(), params, key, secret).await?;
+	let _: CancelOrdersResponse = deser_reqwest(r).await?;
+	Ok(())
+}
+
+/// Cancels every open order the engine has placed for the given position, identified by its client order id. Returns the number of cancelled orders.
+#[instrument(skip(key, secret))]
+pub async fn cancel_position_orders(key: String, secret: String, position_id: Uuid) -> Result<usize> {
+	let open_orders = get_open_orders(key.clone(), secret.clone()).await?;
+	let of_position = open_orders
+		.iter()
+		.filter(|o| {
+			o.client_order_id
+				.as_deref()
+				.and_then(|id| id.parse::<ClientOrderId>().ok())
+				.is_some_and(|id| id.position_id == position_id)
+		})
+		.collect::<Vec<_>>();
+	for o in &of_position {
+		cancel_order(key.clone(), secret.clone(), &o.symbol, o.order_id).await?;
+	}
+	Ok(of_position.len())
+}
+
+/// Signed position amounts, keyed by symbol and `positionSide`. In one-way mode all keys are `BinancePositionSide::Both`.
 #[instrument(skip_all)]
-pub async fn get_futures_positions(key: String, secret: String) -> Result<HashMap<String, f64>> {
+pub async fn get_futures_positions(key: String, secret: String) -> Result<HashMap<(String, BinancePositionSide), f64>> {
 	let url = FuturesAllPositionsResponse::get_url();
 
 	let r = signed_request(Method::GET, url.as_str(), HashMap::new(), key, secret).await?;
 	let positions: Vec<FuturesAllPositionsResponse> = deser_reqwest(r).await?;
 
-	let mut positions_map = HashMap::<String, f64>::new();
+	let mut positions_map = HashMap::<(String, BinancePositionSide), f64>::new();
 	for position in positions {
-		let symbol = position.symbol.clone();
+		let key = (position.symbol.clone(), position.positionSide);
 		let qty = position.positionAmt.parse::<f64>()?;
-		positions_map.entry(symbol).and_modify(|e| *e += qty).or_insert(qty);
+		positions_map.entry(key).and_modify(|e| *e += qty).or_insert(qty);
 	}
 	Ok(positions_map)
 }
@@ -275,7 +443,7 @@ pub async fn post_futures_order(key: String, secret: String, order: &Order<Posit
 	let url = FuturesPositionResponse::get_url();
 
 	let mut binance_order = BinanceOrder::from_standard(order.clone(), binance_exchange_arc).await;
-	let mut params = binance_order.to_params();
+	let mut params = binance_order.to_params()?;
 	params.insert("recvWindow", "60000".to_owned()); // dbg currently they/me are having some issues with response speed
 
 	let r = signed_request(reqwest::Method::POST, url.as_str(), params, key, secret).await?;
@@ -303,13 +471,13 @@ pub async fn poll_futures_order<S: AsRef<str>>(key: S, secret: S, binance_order:
 
 #[derive(Debug, Deserialize)]
 pub struct BinanceKline {
-	open_time: i64,
+	pub open_time: i64,
 	open: String,
 	high: String,
 	low: String,
 	close: String,
 	volume: String,
-	close_time: i64,
+	pub close_time: i64,
 	quote_asset_volume: String,
 	number_of_trades: i64,
 	taker_buy_base_asset_volume: String,
@@ -330,6 +498,11 @@ impl From<BinanceKline> for Ohlc {
 #[derive(Clone, Debug, Default, derive_new::new)]
 struct FillFromPolling {
 	order: Order<PositionOrderId>,
+	position_side: BinancePositionSide,
+	/// Difference to `executed_qty` of the previous poll
+	newly_filled: f64,
+	/// Average over just the `newly_filled`.
+	fill_price: Option<f64>,
 	market_response: FuturesPositionResponse, //HACK: harcodes futures
 }
 
@@ -397,7 +570,7 @@ pub async fn binance_runtime(
 			let mut rng = SmallRng::from_rng(&mut rand::rng());
 			orders.shuffle(&mut rng);
 
-			for (i, order) in orders.iter().enumerate() {
+			for order in orders.iter() {
 				// // temp thing until I transfer to websocket
 				let r: FuturesPositionResponse = match poll_futures_order(&pubkey_clone, &secret_clone, order).await {
 					Ok(r) => r,
@@ -410,11 +583,53 @@ pub async fn binance_runtime(
 				//
 
 				// All other info except amount filled notional will only be relevant during trade's post-execution analysis.
-				if r.executed_qty != order.notional_filled {
-					{
-						currently_deployed_clone.write().unwrap()[i].notional_filled = r.executed_qty;
+				// polled ones are shuffled, so have to find it back by id
+				let new_fill = currently_deployed_clone
+					.write()
+					.unwrap()
+					.iter_mut()
+					.find(|o| o.binance_id == order.binance_id)
+					.and_then(|o| o.record_fills(&r));
+				if let Some((newly_filled, fill_price)) = new_fill {
+					temp_fills_stack_tx
+						.send(FillFromPolling::new(order.base_info.clone(), order.position_side(), newly_filled, fill_price, r))
+						.await
+						.unwrap();
+				}
+			}
+		}
+	});
+
+	// Dead-man switch heartbeat
+	let hub_rx_clone = hub_rx.clone();
+	let (pubkey_clone, secret_clone) = (pubkey.clone(), secret.clone());
+	parent_js.spawn(async move {
+		let mut last_heartbeats: HashMap<Symbol, std::time::Instant> = HashMap::new();
+		//LOOP: for the entire lifetime of the runtime. Stopping to send heartbeats is the whole point of the thing.
+		loop {
+			tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+			let requested = hub_rx_clone.borrow().dead_man.clone();
+
+			for (symbol, window) in &requested {
+				// push back well before expiry, so that a single failed request doesn't trigger it
+				if last_heartbeats.get(symbol).is_some_and(|t| t.elapsed() < *window / 3) {
+					continue;
+				}
+				match countdown_cancel_all(pubkey_clone.clone(), secret_clone.clone(), &symbol.to_string(), Some(*window)).await {
+					Ok(_) => {
+						last_heartbeats.insert(symbol.clone(), std::time::Instant::now());
+					}
+					Err(e) => warn!("Failed to send dead-man heartbeat for {symbol}: {e:?}"),
+				}
+			}
+
+			let disarmed = last_heartbeats.keys().filter(|s| !requested.contains_key(*s)).cloned().collect::<Vec<_>>();
+			for symbol in disarmed {
+				match countdown_cancel_all(pubkey_clone.clone(), secret_clone.clone(), &symbol.to_string(), None).await {
+					Ok(_) => {
+						last_heartbeats.remove(&symbol);
 					}
-					temp_fills_stack_tx.send(FillFromPolling::new(order.base_info.clone(), r)).await.unwrap();
+					Err(e) => warn!("Failed to disarm dead-man switch for {symbol}: {e:?}"),
 				}
 			}
 		}
@@ -451,29 +666,40 @@ pub async fn binance_runtime(
 			Ok(_) = hub_rx.changed() => {
 				handle_hub_orders_update(&hub_rx, &mut last_reported_fill_key, &pubkey, &secret, currently_deployed.clone(), binance_exchange_arc.clone()).await;
 			},
-			_ = handle_temp_fills_stack(&mut temp_fills_stack_rx, &hub_callback, &mut last_reported_fill_key, currently_deployed.clone()) => {},
+			_ = handle_temp_fills_stack(&mut temp_fills_stack_rx, &hub_callback, &mut last_reported_fill_key, currently_deployed.clone(), binance_exchange_arc.clone()) => {},
 		}
 	}
 }
 
-#[instrument(skip(hub_callback))]
+#[instrument(skip(hub_callback, binance_exchange_arc))]
 async fn handle_temp_fills_stack(
 	temp_fills_stack_rx: &mut mpsc::Receiver<FillFromPolling>,
 	hub_callback: &mpsc::Sender<ExchangeToHub>,
 	last_reported_fill_key: &mut Uuid,
 	currently_deployed: Arc<RwLock<Vec<BinanceOrder>>>,
+	binance_exchange_arc: Arc<RwLock<BinanceExchange>>,
 ) {
 	while let Ok(f) = temp_fills_stack_rx.try_recv() {
 		let new_fill_key = Uuid::now_v7();
 		let r = f.market_response;
 
-		if r.status == OrderStatus::Filled {
-			let filled_id = &f.order.id;
-			let mut deployed_lock = currently_deployed.write().unwrap();
-			deployed_lock.retain(|o| o.base_info.id != *filled_id);
+		{
+			let mut binance_exchange_lock = binance_exchange_arc.write().unwrap();
+			let signed_fill = match f.order.side {
+				Side::Buy => f.newly_filled,
+				Side::Sell => -f.newly_filled,
+			};
+			*binance_exchange_lock.engine_positions.entry((f.order.symbol.to_string(), f.position_side)).or_default() += signed_fill;
+
+			if r.status == OrderStatus::Filled {
+				let filled_id = &f.order.id;
+				let mut deployed_lock = currently_deployed.write().unwrap();
+				deployed_lock.retain(|o| o.base_info.id != *filled_id);
+				binance_exchange_lock.engine_orders = deployed_lock.clone();
+			}
 		}
 
-		let callback = ExchangeToHub::new(new_fill_key, Market::BinanceFutures, r.executed_qty, f.order);
+		let callback = ExchangeToHub::new(new_fill_key, Market::BinanceFutures, f.newly_filled, f.fill_price, f.order);
 		debug!(?callback);
 		hub_callback.send(callback).await.unwrap();
 		*last_reported_fill_key = new_fill_key;
@@ -546,7 +772,9 @@ async fn handle_hub_orders_update(
 	info!(?just_deployed);
 
 	{
+		let mut binance_exchange_lock = binance_exchange_arc.write().unwrap();
 		let mut current_lock = currently_deployed.write().unwrap();
+		binance_exchange_lock.engine_orders = just_deployed.clone();
 		*current_lock = just_deployed;
 	}
 }
@@ -735,7 +963,7 @@ struct FuturesAllPositionsResponse {
 	isolatedWallet: String,
 	symbol: String,
 	unRealizedProfit: String,
-	positionSide: Value, // is "BOTH" in standard (non-hedge mode) requests, because designed by fucking morons. Apparently we now have negative values in `positionAmt`, if short.
+	positionSide: BinancePositionSide, // is "BOTH" in standard (non-hedge mode) requests, because designed by fucking morons. Apparently we now have negative values in `positionAmt`, if short.
 	updateTime: i64,
 }
 impl FuturesAllPositionsResponse {
@@ -745,6 +973,42 @@ impl FuturesAllPositionsResponse {
 	}
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LeverageBracketsResponse {
+	symbol: String,
+	brackets: Vec<LeverageBracket>,
+}
+impl LeverageBracketsResponse {
+	pub fn get_url() -> Url {
+		let base_url = Market::BinanceFutures.get_base_url();
+		base_url.join("/fapi/v1/leverageBracket").unwrap()
+	}
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LeverageBracket {
+	bracket: u32,
+	initial_leverage: u32,
+	notional_cap: f64,
+	notional_floor: f64,
+	maint_margin_ratio: f64,
+	cum: f64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PositionModeResponse {
+	dual_side_position: bool,
+}
+impl PositionModeResponse {
+	pub fn get_url() -> Url {
+		let base_url = Market::BinanceFutures.get_base_url();
+		base_url.join("/fapi/v1/positionSide/dual").unwrap()
+	}
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct CancelOrdersResponse {
@@ -3,13 +3,18 @@ use std::{
 	sync::{Arc, RwLock},
 };
 
+use color_eyre::eyre::Result;
 use serde::{Deserialize, Serialize};
-use tracing::instrument;
+use tracing::{debug, instrument};
+use v_utils::trades::Side;
 
-use super::BinanceExchange;
+use super::{BinanceExchange, FuturesPositionResponse};
 use crate::{
-	exchange_apis::order_types::{Order, OrderType, StopMarketOrder},
-	positions::PositionOrderId,
+	exchange_apis::{
+		MarginType,
+		order_types::{LimitOrder, Order, OrderType, StopMarketOrder},
+	},
+	positions::{ClientOrderId, PositionOrderId},
 };
 
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
@@ -17,20 +22,56 @@ pub struct BinanceOrder {
 	pub base_info: Order<PositionOrderId>,
 	pub binance_id: Option<i64>,
 	pub notional_filled: f64,
+	/// Total filled, in quote. Together with `notional_filled`, gives the price of each new fill, as polling only reports totals.
+	pub quote_filled: f64,
+	/// Whether the order is to be posted to an account in hedge mode. Determines the `positionSide` we send.
+	pub hedge_mode: bool,
 }
 impl BinanceOrder {
-	pub fn new(base_info: Order<PositionOrderId>) -> Self {
-		Self { base_info, ..Default::default() }
+	pub fn new(base_info: Order<PositionOrderId>, hedge_mode: bool) -> Self {
+		Self {
+			base_info,
+			hedge_mode,
+			..Default::default()
+		}
+	}
+
+	/// Records the order's state as per the poll, returning what got filled since the previous one and at what average price, if anything did.
+	pub fn record_fills(&mut self, r: &FuturesPositionResponse) -> Option<(f64, Option<f64>)> {
+		if r.executed_qty == self.notional_filled {
+			return None;
+		}
+		let newly_filled = r.executed_qty - self.notional_filled;
+		let fill_price = match r.cum_quote.parse::<f64>() {
+			Ok(quote_filled) => {
+				let fill_price = (quote_filled - self.quote_filled) / newly_filled;
+				self.quote_filled = quote_filled;
+				Some(fill_price).filter(|p| *p > 0.0)
+			}
+			Err(_) => None,
+		};
+		self.notional_filled = r.executed_qty;
+		Some((newly_filled, fill_price))
 	}
 
-	pub fn to_params(&self) -> HashMap<&'static str, String> {
+	pub fn position_side(&self) -> BinancePositionSide {
+		match self.hedge_mode {
+			true => self.base_info.position_side.into(),
+			false => BinancePositionSide::Both,
+		}
+	}
+
+	pub fn to_params(&self) -> Result<HashMap<&'static str, String>> {
 		let mut params = HashMap::<&'static str, String>::new();
 		params.insert("symbol", self.base_info.symbol.to_string());
 		params.insert("side", self.base_info.side.to_string());
 		params.insert("quantity", format!("{}", self.base_info.qty_notional));
+		params.insert("positionSide", self.position_side().to_string());
+		params.insert("newClientOrderId", ClientOrderId::try_from(&self.base_info.id)?.to_string());
 
 		let type_params = match &self.base_info.order_type {
-			OrderType::Market => {
+			// chases are only executed on Bybit, and get downgraded in [Self::from_standard]. Those with a max range are rejected by the hub before they get here.
+			OrderType::Market | OrderType::Chase(_) => {
 				let mut params = HashMap::<&'static str, String>::new();
 				params.insert("type", "MARKET".to_string());
 				params
@@ -41,21 +82,29 @@ impl BinanceOrder {
 				params.insert("stopPrice", sm.price.to_string());
 				params
 			}
+			OrderType::Limit(l) => {
+				let mut params = HashMap::<&'static str, String>::new();
+				params.insert("type", "LIMIT".to_string());
+				params.insert("price", l.price.to_string());
+				// GTX: post-only
+				params.insert("timeInForce", if l.post_only { "GTX" } else { "GTC" }.to_string());
+				params
+			}
 		};
 		params.extend(type_params);
 
-		params
+		Ok(params)
 	}
 
 	#[instrument(skip(binance_exchange_arc))]
 	pub async fn from_standard(mut order: Order<PositionOrderId>, binance_exchange_arc: Arc<RwLock<BinanceExchange>>) -> Self {
-		let futures_symbol = {
+		let (futures_symbol, hedge_mode) = {
 			let lock = binance_exchange_arc.read().unwrap();
 			let futures_symbol = lock
 				.binance_futures_info
 				.pair(&order.symbol.base, &order.symbol.quote)
 				.expect("coin should have been checked earlier");
-			futures_symbol.clone()
+			(futures_symbol.clone(), lock.hedge_mode)
 		};
 		fn precision(qty: f64, precision: i32) -> f64 {
 			let factor = 10_f64.powi(precision);
@@ -66,10 +115,69 @@ impl BinanceOrder {
 
 		let order_type = match &order.order_type {
 			OrderType::Market => OrderType::Market,
+			OrderType::Chase(_) => {
+				debug!("No chase execution on Binance, falling back to Market, which is what a chase without a max range ends with anyway");
+				OrderType::Market
+			}
 			OrderType::StopMarket(sm) => OrderType::StopMarket(StopMarketOrder::new(precision(sm.price, futures_symbol.price_precision as i32))),
+			OrderType::Limit(l) => OrderType::Limit(LimitOrder::new(precision(l.price, futures_symbol.price_precision as i32), l.post_only)),
 		};
 		order.order_type = order_type;
 
-		Self::new(order)
+		Self::new(order, hedge_mode)
+	}
+}
+
+/// `positionSide` of Binance Futures. One-way mode only accepts `BOTH`; hedge mode requires explicit `LONG` or `SHORT`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum BinancePositionSide {
+	#[default]
+	Both,
+	Long,
+	Short,
+}
+impl From<Side> for BinancePositionSide {
+	fn from(side: Side) -> Self {
+		match side {
+			Side::Buy => Self::Long,
+			Side::Sell => Self::Short,
+		}
+	}
+}
+impl std::fmt::Display for BinancePositionSide {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let s = match self {
+			Self::Both => "BOTH",
+			Self::Long => "LONG",
+			Self::Short => "SHORT",
+		};
+		write!(f, "{s}")
+	}
+}
+
+/// `marginType` param of `/fapi/v1/marginType`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum BinanceMarginType {
+	#[default]
+	Crossed,
+	Isolated,
+}
+impl From<MarginType> for BinanceMarginType {
+	fn from(margin_type: MarginType) -> Self {
+		match margin_type {
+			MarginType::Cross => Self::Crossed,
+			MarginType::Isolated => Self::Isolated,
+		}
+	}
+}
+impl std::fmt::Display for BinanceMarginType {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let s = match self {
+			Self::Crossed => "CROSSED",
+			Self::Isolated => "ISOLATED",
+		};
+		write!(f, "{s}")
 	}
 }
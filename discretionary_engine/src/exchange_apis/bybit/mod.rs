@@ -1,9 +1,401 @@
+//! Bybit linear perpetuals, behind the hub. Same contract as [super::binance::binance_runtime]: receives target orders from the hub, makes them so on the exchange, and reports fills back.
+use std::{
+	collections::HashMap,
+	sync::{Arc, RwLock},
+	time::Duration,
+};
+
+use color_eyre::eyre::{Result, bail, eyre};
+use futures_util::{StreamExt, pin_mut};
+use nautilus_bybit::websocket::{client::BybitWebSocketClient, messages::NautilusWsMessage};
+use nautilus_model::identifiers::InstrumentId;
 use serde::{Deserialize, Serialize};
+use tokio::{
+	select,
+	sync::{mpsc, watch},
+	task::JoinSet,
+};
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+use v_exchanges::ExchangeName;
+use v_utils::trades::Side;
+
+use super::{
+	MarginType, Market, Symbol,
+	hub::{ExchangeToHub, HubToExchange},
+	order_types::{ChaseOrder, Order, OrderType},
+};
+use crate::{
+	ClientOrderId, PositionOrderId,
+	bybit_common::{BybitApiError, BybitCategory, BybitSignedClient, InstrumentRules, fetch_instrument_rules},
+	config::LiveSettings,
+	ws_chase_limit::{ChaseControl, execute_ws_chase_limit, format_price, format_qty},
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct BybitExchange {
+	pub testnet: bool,
+	/// Trading rules per symbol. Fetched on first use, as unlike Binance we don't pull the entire exchange info.
+	pub instruments: HashMap<(BybitCategory, String), InstrumentRules>,
+}
+impl BybitExchange {
+	pub fn init(testnet: bool) -> Self {
+		Self { testnet, ..Default::default() }
+	}
+}
+
+/// Bybit rejects longer `orderLinkId`s.
+const ORDER_LINK_ID_MAX_LEN: usize = 36;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BybitOrder {
+	pub base_info: Order<PositionOrderId>,
+	/// [ClientOrderId] with a nonce appended, as Bybit wants `orderLinkId`s to be unique across the order history, not just among open orders.
+	pub order_link_id: String,
+	pub notional_filled: f64,
+}
+impl BybitOrder {
+	pub fn new(base_info: Order<PositionOrderId>) -> Result<Self> {
+		let mut order_link_id = ClientOrderId::try_from(&base_info.id)?.with_nonce(chrono::Utc::now().timestamp_millis() as u64);
+		// chases append `-f` for their final market leg, so need room for it. Leaves 3 chars of nonce, still parses back.
+		if let OrderType::Chase(_) = base_info.order_type {
+			order_link_id.truncate(ORDER_LINK_ID_MAX_LEN - 2);
+		}
+		Ok(Self {
+			base_info,
+			order_link_id,
+			notional_filled: 0.0,
+		})
+	}
+
+	pub fn category(&self) -> Result<BybitCategory> {
+		BybitCategory::from_market(self.base_info.symbol.market)
+	}
+
+	pub fn to_params(&self, category: BybitCategory, instrument: &InstrumentRules) -> serde_json::Value {
+		let side = match self.base_info.side {
+			Side::Buy => "Buy",
+			Side::Sell => "Sell",
+		};
+		let mut params = serde_json::json!({
+			"category": category.as_str(),
+			"symbol": self.base_info.symbol.to_string(),
+			"side": side,
+			"orderType": "Market",
+			"qty": format_qty(round_to_step(self.base_info.qty_notional, instrument.qty_step), instrument.qty_step),
+			"orderLinkId": self.order_link_id,
+		});
+		match &self.base_info.order_type {
+			OrderType::Market => {
+				params["timeInForce"] = "IOC".into();
+			}
+			OrderType::StopMarket(sm) => {
+				// 1: triggered when price rises to triggerPrice, 2: when it falls to it
+				let trigger_direction = match self.base_info.side {
+					Side::Buy => 1,
+					Side::Sell => 2,
+				};
+				params["triggerPrice"] = format_price(round_to_step(sm.price, instrument.tick_size), instrument.tick_size).into();
+				params["triggerDirection"] = trigger_direction.into();
+			}
+			OrderType::Limit(l) => {
+				params["orderType"] = "Limit".into();
+				params["price"] = format_price(round_to_step(l.price, instrument.tick_size), instrument.tick_size).into();
+				params["timeInForce"] = if l.post_only { "PostOnly" } else { "GTC" }.into();
+			}
+			OrderType::Chase(_) => unreachable!("Chases are driven over the websocket, see [spawn_chase]"),
+		}
+		params
+	}
+}
+
+fn round_to_step(value: f64, step: f64) -> f64 {
+	(value / step).round() * step
+}
+
+/// Returns cached trading rules for the symbol, fetching them if we haven't seen it yet.
+#[instrument(skip(bybit_exchange_arc))]
+pub async fn instrument(bybit_exchange_arc: Arc<RwLock<BybitExchange>>, category: BybitCategory, symbol: &str) -> Result<InstrumentRules> {
+	let (testnet, cached) = {
+		let lock = bybit_exchange_arc.read().unwrap();
+		(lock.testnet, lock.instruments.get(&(category, symbol.to_owned())).copied())
+	};
+	if let Some(instrument) = cached {
+		return Ok(instrument);
+	}
+	let instrument = fetch_instrument_rules(testnet, category, symbol).await?;
+	bybit_exchange_arc.write().unwrap().instruments.insert((category, symbol.to_owned()), instrument);
+	Ok(instrument)
+}
+
+/// NB: must be communicating back to the hub, can't shortcut and talk back directly to positions.
+///
+/// Only returns if it fails to get going.
+#[instrument(skip_all)]
+pub async fn bybit_runtime(
+	live_settings: Arc<LiveSettings>,
+	parent_js: &mut JoinSet<()>,
+	hub_callback: mpsc::Sender<ExchangeToHub>,
+	mut hub_rx: watch::Receiver<HubToExchange>,
+	bybit_exchange_arc: Arc<RwLock<BybitExchange>>,
+) -> Result<()> {
+	debug!("Bybit_runtime started");
+	let testnet = bybit_exchange_arc.read().unwrap().testnet;
+	let mut last_reported_fill_key = Uuid::default();
+	let mut currently_deployed: Vec<BybitOrder> = Vec::new();
+	// stop handles of chases, by orderLinkId
+	let mut running_chases: HashMap<String, watch::Sender<bool>> = HashMap::new();
+
+	let signed_client = Arc::new(BybitSignedClient::new(live_settings.clone(), ExchangeName::Bybit, testnet)?);
+
+	let (api_key, api_secret) = signed_client.credentials();
+	let mut trade_client = BybitWebSocketClient::new_trade(signed_client.environment(), Some(api_key), Some(api_secret), None, None);
+	trade_client.connect().await.map_err(|e| eyre!("Failed to connect Bybit trade websocket: {e}"))?;
+	trade_client.subscribe_orders().await.map_err(|e| eyre!("Failed to subscribe to Bybit order events: {e}"))?;
+	let trade_stream = trade_client.stream();
+	pin_mut!(trade_stream);
+
+	// Dead-man switch. Bybit only has an account-wide window, that starts counting when our private websocket drops, so there is nothing to heartbeat; just keep the window in sync.
+	let hub_rx_clone = hub_rx.clone();
+	let signed_client_clone = signed_client.clone();
+	parent_js.spawn(async move {
+		let mut current_window: Option<Duration> = None;
+		//LOOP: for the entire lifetime of the runtime
+		loop {
+			tokio::time::sleep(Duration::from_secs(1)).await;
+			let requested = hub_rx_clone.borrow().dead_man.values().min().copied();
+			if requested == current_window {
+				continue;
+			}
+			// there is no disarming it, so when no longer requested we fall back to the max window. Stays armed after we're gone too, which `--dead-man` warns about.
+			let window_s = requested.map(|w| w.as_secs() as u32).unwrap_or(300);
+			match signed_client_clone.set_disconnect_cancel_all(window_s).await {
+				Ok(_) => current_window = requested,
+				Err(e) => warn!("Failed to set Bybit disconnect-cancel-all window: {e:?}"),
+			}
+		}
+	});
+
+	//LOOP: Main loop of Bybit exchange
+	loop {
+		select! {
+			Ok(_) = hub_rx.changed() => {
+				if let Err(e) = handle_hub_orders_update(
+					parent_js,
+					&hub_rx,
+					&last_reported_fill_key,
+					&signed_client,
+					&mut currently_deployed,
+					&mut running_chases,
+					bybit_exchange_arc.clone(),
+				)
+				.await
+				{
+					tracing::error!("Error deploying orders on Bybit: {e:?}");
+				}
+			},
+			Some(msg) = trade_stream.next() => {
+				handle_trade_message(msg, &hub_callback, &mut last_reported_fill_key, &mut currently_deployed).await;
+			},
+		}
+	}
+}
+
+#[instrument(skip(hub_callback))]
+async fn handle_trade_message(msg: NautilusWsMessage, hub_callback: &mpsc::Sender<ExchangeToHub>, last_reported_fill_key: &mut Uuid, currently_deployed: &mut Vec<BybitOrder>) {
+	match msg {
+		NautilusWsMessage::FillReports(fills) =>
+			for fill in fills {
+				let Some(coid) = fill.client_order_id.as_ref().map(|c| c.to_string()) else { continue };
+				// chases append a suffix for their final market leg
+				let Some(i) = currently_deployed.iter().position(|o| coid.starts_with(&o.order_link_id)) else {
+					debug!("Fill for an order we don't track: {coid}");
+					continue;
+				};
+				currently_deployed[i].notional_filled += fill.last_qty.as_f64();
+				let order = currently_deployed[i].clone();
+				// fully filled, modulo float noise
+				if order.notional_filled >= order.base_info.qty_notional * (1.0 - 1e-9) {
+					currently_deployed.remove(i);
+				}
+
+				let new_fill_key = Uuid::now_v7();
+				let callback = ExchangeToHub::new(new_fill_key, Market::BybitFutures, fill.last_qty.as_f64(), Some(fill.last_px.as_f64()), order.base_info);
+				debug!(?callback);
+				hub_callback.send(callback).await.unwrap();
+				*last_reported_fill_key = new_fill_key;
+			},
+		NautilusWsMessage::OrderRejected(rejected) => {
+			warn!("Bybit rejected order {}: {}", rejected.client_order_id, rejected.reason);
+		}
+		NautilusWsMessage::Error(e) => {
+			warn!("Bybit trade stream error: {e:?}");
+		}
+		_ => {}
+	}
+}
+
+#[instrument(skip(parent_js, hub_rx, signed_client, currently_deployed, running_chases, bybit_exchange_arc))]
+async fn handle_hub_orders_update(
+	parent_js: &mut JoinSet<()>,
+	hub_rx: &watch::Receiver<HubToExchange>,
+	last_reported_fill_key: &Uuid,
+	signed_client: &Arc<BybitSignedClient>,
+	currently_deployed: &mut Vec<BybitOrder>,
+	running_chases: &mut HashMap<String, watch::Sender<bool>>,
+	bybit_exchange_arc: Arc<RwLock<BybitExchange>>,
+) -> Result<()> {
+	let target_orders: Vec<Order<PositionOrderId>> = {
+		let from_hub = hub_rx.borrow();
+		if from_hub.key != *last_reported_fill_key {
+			debug!("fill keys don't match.");
+			return Ok(());
+		}
+		from_hub.orders.clone()
+	};
+
+	// Restarting a chase would throw away its place in the queue, so ones still requested keep running. Their requested qty shrinks as they fill, which they already account for.
+	let is_same_chase = |deployed: &BybitOrder, target: &Order<PositionOrderId>| {
+		matches!(deployed.base_info.order_type, OrderType::Chase(_))
+			&& deployed.base_info.id == target.id
+			&& deployed.base_info.symbol == target.symbol
+			&& deployed.base_info.order_type == target.order_type
+	};
+	let (kept, to_cancel): (Vec<BybitOrder>, Vec<BybitOrder>) = currently_deployed.drain(..).partition(|o| target_orders.iter().any(|t| is_same_chase(o, t)));
+	for o in to_cancel {
+		if let OrderType::Chase(_) = o.base_info.order_type {
+			if let Some(stop) = running_chases.remove(&o.order_link_id) {
+				// the chase may have finished by itself already
+				let _ = stop.send(true);
+			}
+			continue;
+		}
+		// already filled or cancelled ones are fine
+		let cancelled = match o.category() {
+			Ok(category) => signed_client.cancel_order_by_link_id(category, &o.base_info.symbol.to_string(), &o.order_link_id).await,
+			Err(e) => Err(e),
+		};
+		if let Err(e) = cancelled {
+			warn!("Failed to cancel {}: {e:?}", o.order_link_id);
+		}
+	}
+	running_chases.retain(|link_id, _| kept.iter().any(|o| &o.order_link_id == link_id));
+	*currently_deployed = kept;
+
+	for o in target_orders {
+		if currently_deployed.iter().any(|d| is_same_chase(d, &o)) {
+			continue;
+		}
+		let symbol = o.symbol.to_string();
+		let category = BybitCategory::from_market(o.symbol.market)?;
+		let instrument = instrument(bybit_exchange_arc.clone(), category, &symbol).await?;
+		if o.qty_notional < instrument.min_order_qty {
+			debug!("Skipping order below min qty: {:?}", o);
+			continue;
+		}
+
+		let bybit_order = match BybitOrder::new(o) {
+			Ok(bybit_order) => bybit_order,
+			Err(e) => {
+				tracing::error!("Can't express order on Bybit: {e:?}");
+				continue;
+			}
+		};
+		if let OrderType::Chase(chase) = &bybit_order.base_info.order_type {
+			let (stop_tx, stop_rx) = watch::channel(false);
+			let control = ChaseControl {
+				order_link_id: bybit_order.order_link_id.clone(),
+				stop: stop_rx,
+			};
+			spawn_chase(parent_js, signed_client.clone(), &bybit_order, chase.clone(), category, instrument, control);
+			running_chases.insert(bybit_order.order_link_id.clone(), stop_tx);
+			currently_deployed.push(bybit_order);
+			continue;
+		}
+		if let Err(e) = signed_client.place_order(&bybit_order.to_params(category, &instrument)).await {
+			match e.downcast_ref::<BybitApiError>() {
+				Some(api_error) => tracing::error!("Error posting order {}: {}", bybit_order.order_link_id, api_error),
+				None => return Err(e),
+			}
+			continue;
+		}
+		currently_deployed.push(bybit_order);
+	}
+	info!(?currently_deployed);
+	Ok(())
+}
+
+/// Runs [execute_ws_chase_limit] for the order in the background. Its fills come through the runtime's own order stream like those of any other order, so all that is left for us is to stop it when no longer requested.
+fn spawn_chase(
+	parent_js: &mut JoinSet<()>,
+	signed_client: Arc<BybitSignedClient>,
+	order: &BybitOrder,
+	chase: ChaseOrder,
+	category: BybitCategory,
+	instrument: InstrumentRules,
+	control: ChaseControl,
+) {
+	let symbol = order.base_info.symbol.to_string();
+	let side = match order.base_info.side {
+		Side::Buy => "Buy",
+		Side::Sell => "Sell",
+	};
+	let qty = round_to_step(order.base_info.qty_notional, instrument.qty_step);
+	parent_js.spawn(async move {
+		let run = async {
+			let instrument_id = InstrumentId::from(format!("{}.BYBIT", symbol).as_str());
+			execute_ws_chase_limit(
+				&signed_client,
+				category,
+				&symbol,
+				instrument_id,
+				side,
+				qty,
+				instrument.qty_step,
+				instrument.tick_size,
+				Some(chase.duration),
+				chase.max_range,
+				Some(control),
+			)
+			.await
+		};
+		match run.await {
+			Ok(outcome) => debug!("Chase on {symbol} finished with {} out of {qty} filled, {} abandoned", outcome.filled, outcome.abandoned),
+			Err(e) => tracing::error!("Chase on {symbol} failed: {e:?}"),
+		}
+	});
+}
+
+/// Validates requested leverage against the instrument, then applies leverage and margin type. Bybit can only switch margin type together with setting leverage.
+#[instrument(skip(live_settings, bybit_exchange_arc))]
+pub async fn apply_position_settings(
+	live_settings: Arc<LiveSettings>,
+	bybit_exchange_arc: Arc<RwLock<BybitExchange>>,
+	symbol: &Symbol,
+	leverage: Option<u8>,
+	margin_type: Option<MarginType>,
+) -> Result<()> {
+	let testnet = bybit_exchange_arc.read().unwrap().testnet;
+	let Some(leverage) = leverage else {
+		if margin_type.is_some() {
+			bail!("Bybit requires leverage to be specified alongside margin type");
+		}
+		return Ok(());
+	};
+
+	let category = BybitCategory::from_market(symbol.market)?;
+	let symbol = symbol.to_string();
+	let instrument = instrument(bybit_exchange_arc, category, &symbol).await?;
+	let Some(max_leverage) = instrument.max_leverage else {
+		bail!("{symbol} ({category}) can't be traded with leverage");
+	};
+	if leverage as f64 > max_leverage {
+		bail!("Requested leverage {}x exceeds maximum of {}x for {}", leverage, max_leverage, symbol);
+	}
 
-/// Dummy to concept-prove design for now
-#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
-pub enum BybitMarket {
-	#[default]
-	Futures,
-	Spot,
+	let signed_client = BybitSignedClient::new(live_settings, ExchangeName::Bybit, testnet)?;
+	if let Some(margin_type) = margin_type {
+		signed_client.switch_margin_type(category, &symbol, margin_type, leverage).await?;
+	}
+	signed_client.set_leverage(category, &symbol, leverage).await
 }
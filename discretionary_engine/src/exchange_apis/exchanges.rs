@@ -6,24 +6,73 @@ use tracing::instrument;
 use super::{
 	Market,
 	binance::BinanceExchange,
+	bybit::BybitExchange,
+	market_data::MarketData,
 	order_types::{ConceptualOrderPercents, ConceptualOrderType, IdRequirements},
 };
-use crate::{config::LiveSettings, exchange_apis::binance};
+use crate::{
+	bybit_common::{BybitCategory, InstrumentRules, fetch_book_ticker},
+	config::LiveSettings,
+	exchange_apis::{Symbol, binance, bybit},
+	positions::PositionSpec,
+};
 
 /// [Exchange] itself is passed around as Arc<Self>, RwLock is only present at the level of individual exchanges, as to not lock it all at once when writing.
 #[derive(Clone, Debug, Default)]
 pub struct Exchanges {
 	pub binance: Arc<RwLock<BinanceExchange>>,
+	pub bybit: Arc<RwLock<BybitExchange>>,
+	/// Shared by the protocols of all positions.
+	pub market_data: MarketData,
 }
 impl Exchanges {
 	#[instrument]
-	pub async fn init(live_settings: Arc<LiveSettings>) -> Result<Self> {
+	pub async fn init(live_settings: Arc<LiveSettings>, testnet: bool) -> Result<Self> {
 		let binance = BinanceExchange::init(live_settings.clone()).await?;
+		let bybit = BybitExchange::init(testnet);
 		Ok(Self {
 			binance: Arc::new(RwLock::new(binance)),
+			bybit: Arc::new(RwLock::new(bybit)),
+			market_data: MarketData::default(),
 		})
 	}
 
+	/// Whether the account on the market keeps separate long and short positions per symbol. We place Bybit orders on its one-way `positionIdx`, so it never does there.
+	pub fn hedge_mode(&self, market: Market) -> bool {
+		match market {
+			Market::BybitFutures => false,
+			_ => self.binance.read().unwrap().hedge_mode,
+		}
+	}
+
+	/// Applies leverage and margin type requested by the Position to its symbol. Must be called before acquisition starts, as exchanges refuse to change these with open orders or positions on the symbol.
+	#[instrument(skip(s, live_settings))]
+	pub async fn apply_position_settings(s: Arc<Self>, live_settings: Arc<LiveSettings>, spec: &PositionSpec) -> Result<()> {
+		use secrecy::ExposeSecret;
+		use v_exchanges::ExchangeName;
+
+		if spec.leverage.is_none() && spec.margin_type.is_none() {
+			return Ok(());
+		}
+		if spec.market == Market::BybitFutures {
+			let symbol = Symbol::new(spec.asset.as_str(), "USDT", Market::BybitFutures);
+			return bybit::apply_position_settings(live_settings, s.bybit.clone(), &symbol, spec.leverage, spec.margin_type).await;
+		}
+
+		let config = live_settings.config()?;
+		let binance_config = config.get_exchange(ExchangeName::Binance)?;
+		let (key, secret) = (binance_config.api_pubkey.clone(), binance_config.api_secret.expose_secret().to_string());
+		let symbol = Symbol::new(spec.asset.as_str(), "USDT", Market::BinanceFutures).to_string();
+
+		if let Some(margin_type) = spec.margin_type {
+			binance::set_margin_type(key.clone(), secret.clone(), &symbol, margin_type).await?;
+		}
+		if let Some(leverage) = spec.leverage {
+			binance::set_leverage(key, secret, &symbol, leverage, spec.size_usdt).await?;
+		}
+		Ok(())
+	}
+
 	#[instrument(skip(_s, live_settings))]
 	pub async fn compile_total_balance(_s: Arc<Self>, live_settings: Arc<LiveSettings>) -> Result<f64> {
 		use secrecy::ExposeSecret;
@@ -45,12 +94,33 @@ impl Exchanges {
 		Ok(total_balance)
 	}
 
+	/// Fetches what [Self::compile_min_trade_qties] and [Self::min_qty_any_ordertype] need to know of the symbol, if the exchange isn't pulled in full on init. Must be called before a position on the symbol starts.
+	#[instrument(skip(s))]
+	pub async fn load_trading_rules(s: Arc<Self>, symbol: &Symbol) -> Result<()> {
+		if symbol.market == Market::BybitFutures {
+			bybit::instrument(s.bybit.clone(), BybitCategory::from_market(symbol.market)?, &symbol.to_string()).await?;
+		}
+		Ok(())
+	}
+
+	/// Last price of the symbol, on its own market.
+	#[instrument(skip(s))]
+	pub async fn price(s: Arc<Self>, symbol: &Symbol) -> Result<f64> {
+		let testnet = s.bybit.read().unwrap().testnet;
+		last_price(symbol, testnet).await
+	}
+
 	//TODO!!!!: non-market order's min qty often has another min based on quote_asset, account for that. And also, there often is max percentage-wise diff for how away from the price you can place the order, want to know if we're out of it here.
 	/// Returns the absolute minimum trade quantity for (order_type, base_asset) pair
 	///
 	/// // as min trade qty can depend on whever the order is market or not
 	#[instrument(skip(_s))]
-	pub fn compile_min_trade_qties(_s: Arc<Self>, base_asset: &str, orders: &[ConceptualOrderPercents]) -> Vec<f64> {
+	pub fn compile_min_trade_qties(_s: Arc<Self>, symbol: &Symbol, orders: &[ConceptualOrderPercents]) -> Vec<f64> {
+		if symbol.market == Market::BybitFutures {
+			// same for all order types
+			return vec![Self::bybit_instrument(&_s, symbol).min_order_qty; orders.len()];
+		}
+		let base_asset = symbol.base.as_str();
 		let ordertypes: Vec<ConceptualOrderType> = orders.iter().map(|o| o.order_type).collect();
 		let mut min_notional_qties_accross_exchanges = Vec::with_capacity(ordertypes.len());
 		for _ in 0..ordertypes.len() {
@@ -78,12 +148,31 @@ impl Exchanges {
 	///
 	/// We find max of the min_qty values for all order_types here, while for limits and stop markets we take the maximum distance from the price exchange allows for.
 	#[instrument(skip(_s))]
-	pub fn min_qty_any_ordertype(_s: Arc<Self>, base_asset: &str) -> f64 {
-		//- same for other exchanges
-
-		{
-			let binance_lock = _s.binance.read().unwrap();
-			binance_lock.min_qty_any_ordertype(base_asset)
+	pub fn min_qty_any_ordertype(_s: Arc<Self>, symbol: &Symbol) -> f64 {
+		match symbol.market {
+			Market::BybitFutures => Self::bybit_instrument(&_s, symbol).min_order_qty,
+			_ => {
+				let binance_lock = _s.binance.read().unwrap();
+				binance_lock.min_qty_any_ordertype(&symbol.base)
+			}
 		}
 	}
+
+	fn bybit_instrument(s: &Self, symbol: &Symbol) -> InstrumentRules {
+		let category = BybitCategory::from_market(symbol.market).expect("checked by caller");
+		*s.bybit
+			.read()
+			.unwrap()
+			.instruments
+			.get(&(category, symbol.to_string()))
+			.expect("trading rules are loaded before a position starts, see [Exchanges::load_trading_rules]")
+	}
+}
+
+/// Last price of the symbol, on its own market. For when there is no [Exchanges] at hand.
+pub async fn last_price(symbol: &Symbol, testnet: bool) -> Result<f64> {
+	match symbol.market {
+		Market::BybitFutures => Ok(fetch_book_ticker(testnet, BybitCategory::from_market(symbol.market)?, &symbol.to_string()).await?.last_price),
+		_ => binance::futures_price(&symbol.base).await,
+	}
 }
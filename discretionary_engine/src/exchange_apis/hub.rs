@@ -1,20 +1,22 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Report, Result, eyre};
 use tokio::{
 	select,
 	sync::{mpsc, watch},
 	task::JoinSet,
 };
-use tracing::{Span, debug, field::Empty, instrument};
+use tracing::{Span, debug, error, field::Empty, instrument, warn};
 use uuid::Uuid;
+use v_exchanges::ExchangeName;
+use v_utils::trades::Side;
 
 use super::exchanges::Exchanges;
 use crate::{
 	PositionOrderId,
 	config::LiveSettings,
 	exchange_apis::{
-		Market, binance, order_types,
+		Market, Symbol, binance, bybit, order_types,
 		order_types::{ConceptualOrder, ConceptualOrderType, Order, ProtocolOrderId},
 	},
 	positions::HubToPosition,
@@ -26,7 +28,10 @@ pub struct ExchangeToHub {
 	pub key: Uuid,
 	/// Market from which the fill comes
 	pub market: Market,
+	/// Filled since the previous report on the order, not in total. Positions sum these up.
 	pub fill_qty: f64,
+	/// Of this fill specifically. `None` if the exchange didn't report it.
+	pub fill_price: Option<f64>,
 	pub order: Order<PositionOrderId>,
 }
 
@@ -34,6 +39,8 @@ pub struct ExchangeToHub {
 pub struct HubToExchange {
 	pub key: Uuid,
 	pub orders: Vec<Order<PositionOrderId>>,
+	/// Symbols on which the dead-man switch is to be kept armed, with the shortest window any position on them requested.
+	pub dead_man: HashMap<Symbol, Duration>,
 }
 
 #[instrument(skip_all)]
@@ -53,7 +60,10 @@ pub struct PositionToHub {
 #[derive(Clone, Debug, derive_new::new)]
 struct PositionLocalKnowledge {
 	pub key: Uuid,
-	pub callback: mpsc::Sender<ProtocolFills>,
+	pub callback: mpsc::Sender<Result<ProtocolFills>>,
+	pub position_side: Side,
+	pub dead_man: Option<Duration>,
+	pub market: Market,
 	pub requested_orders: Vec<ConceptualOrder<ProtocolOrderId>>,
 }
 
@@ -69,19 +79,34 @@ pub async fn hub(live_settings: Arc<LiveSettings>, mut rx: mpsc::Receiver<Positi
 	//- init the runtime of exchanges
 
 	let (fills_tx, mut fills_rx) = mpsc::channel::<ExchangeToHub>(32);
-	let (orders_tx, orders_rx) = watch::channel::<HubToExchange>(HubToExchange::default());
+	let (binance_orders_tx, binance_orders_rx) = watch::channel::<HubToExchange>(HubToExchange::default());
+	let (bybit_orders_tx, bybit_orders_rx) = watch::channel::<HubToExchange>(HubToExchange::default());
+	let orders_txs = HashMap::from([(Market::BinanceFutures, binance_orders_tx), (Market::BybitFutures, bybit_orders_tx)]);
 	let mut js = JoinSet::new();
 
 	// Spawn Binance
 	let exchanges_clone = exchanges.clone();
 	let live_settings_clone = live_settings.clone();
+	let fills_tx_clone = fills_tx.clone();
 	js.spawn(async move {
 		let mut exchange_runtimes_js = JoinSet::new();
-		binance::binance_runtime(live_settings_clone, &mut exchange_runtimes_js, fills_tx, orders_rx, exchanges_clone.binance.clone()).await;
+		binance::binance_runtime(live_settings_clone, &mut exchange_runtimes_js, fills_tx_clone, binance_orders_rx, exchanges_clone.binance.clone()).await;
 		unreachable!();
 		//exchange_runtimes_js.join_all().await;
 	});
 
+	// Spawn Bybit, if there are credentials for it. Positions refuse to be opened there otherwise.
+	if live_settings.config()?.get_exchange(ExchangeName::Bybit).is_ok() {
+		let exchanges_clone = exchanges.clone();
+		let live_settings_clone = live_settings.clone();
+		js.spawn(async move {
+			let mut exchange_runtimes_js = JoinSet::new();
+			if let Err(e) = bybit::bybit_runtime(live_settings_clone, &mut exchange_runtimes_js, fills_tx, bybit_orders_rx, exchanges_clone.bybit.clone()).await {
+				error!("Bybit runtime failed to start, orders on Bybit won't be executed: {e:?}");
+			}
+		});
+	}
+
 	let mut positions_local_knowledge: HashMap<Uuid, PositionLocalKnowledge> = HashMap::new();
 	let mut exchanges_local_knowledge: HashMap<Market, ExchangeLocalKnowledge> = HashMap::new();
 
@@ -89,7 +114,8 @@ pub async fn hub(live_settings: Arc<LiveSettings>, mut rx: mpsc::Receiver<Positi
 	loop {
 		select! {
 			Some(update_from_position) = rx.recv() => {
-				handle_update_from_position(update_from_position, &mut positions_local_knowledge, &orders_tx, &mut exchanges_local_knowledge)?;
+				let hedge_mode = exchanges.hedge_mode(update_from_position.position_callback.market);
+				handle_update_from_position(update_from_position, &mut positions_local_knowledge, &orders_txs, &mut exchanges_local_knowledge, hedge_mode).await?;
 			},
 			Some(fill) = fills_rx.recv() => {
 				let exchange_local_knowledge = exchanges_local_knowledge.entry(fill.market).or_default();
@@ -106,18 +132,56 @@ pub async fn hub(live_settings: Arc<LiveSettings>, mut rx: mpsc::Receiver<Positi
 	Ok(())
 }
 
-#[instrument(skip(orders_tx, positions_local_knowledge), fields(position_local_knowledge = Empty))]
-fn handle_update_from_position(
+#[instrument(skip(orders_txs, positions_local_knowledge), fields(position_local_knowledge = Empty))]
+async fn handle_update_from_position(
 	hub_rx: PositionToHub,
 	positions_local_knowledge: &mut HashMap<Uuid, PositionLocalKnowledge>,
-	orders_tx: &tokio::sync::watch::Sender<HubToExchange>,
+	orders_txs: &HashMap<Market, watch::Sender<HubToExchange>>,
 	exchanges_local_knowledge: &mut HashMap<Market, ExchangeLocalKnowledge>,
+	hedge_mode: bool,
 ) -> Result<()> {
 	let position_id = hub_rx.position_callback.position_id;
-	let position_local_knowledge = positions_local_knowledge
-		.entry(position_id)
-		.or_insert(PositionLocalKnowledge::new(Uuid::default(), hub_rx.position_callback.sender, Vec::new()));
+	let position_side = hub_rx.position_callback.position_side;
+	let market = hub_rx.position_callback.market;
+
+	// In one-way mode the exchange nets everything on a symbol into a single position, so opposite-side Positions would be eating each other's fills.
+	if !hedge_mode
+		&& let Some(conflicting_symbol) = hub_rx.orders.iter().map(|o| &o.symbol).find(|symbol| {
+			positions_local_knowledge
+				.iter()
+				.any(|(id, plk)| *id != position_id && plk.market == market && plk.position_side != position_side && plk.requested_orders.iter().any(|o| o.symbol == **symbol))
+		}) {
+		warn!(%conflicting_symbol, "Opposite-side positions on the same symbol require hedge mode, rejecting the request.");
+		reject(
+			&hub_rx.position_callback,
+			eyre!("Another position is open on {conflicting_symbol} on the opposite side, which requires the {market:?} account to be in hedge mode"),
+		)
+		.await;
+		return Ok(());
+	}
+	// only Bybit runs chases; elsewhere they are market-filled right away, which is where a chase ends up anyway, unless it was to be abandoned on running too far
+	if market != Market::BybitFutures
+		&& hub_rx
+			.orders
+			.iter()
+			.any(|o| matches!(o.order_type, ConceptualOrderType::Chase(chase) if chase.max_range.is_some()))
+	{
+		warn!(?market, "Chase with max range requested where chases aren't executed, rejecting the request.");
+		reject(&hub_rx.position_callback, eyre!("Chases with a max range can't be executed on {market:?}, only on Bybit")).await;
+		return Ok(());
+	}
+
+	let position_local_knowledge = positions_local_knowledge.entry(position_id).or_insert(PositionLocalKnowledge::new(
+		Uuid::default(),
+		hub_rx.position_callback.sender,
+		position_side,
+		None,
+		market,
+		Vec::new(),
+	));
 	Span::current().record("position_local_knowledge", format!("{:?}", position_local_knowledge));
+	// not gated by the key: acquisition and followup of the same position can request different dead-man settings
+	position_local_knowledge.dead_man = hub_rx.position_callback.dead_man;
 
 	if position_local_knowledge.key != hub_rx.key {
 		// by internal convention, on init the key is Uuid::default()
@@ -127,36 +191,63 @@ fn handle_update_from_position(
 	position_local_knowledge.requested_orders = hub_rx.orders;
 
 	let mut requested_orders_all_positions: Vec<ConceptualOrder<PositionOrderId>> = Vec::new();
+	let mut position_sides: HashMap<Uuid, Side> = HashMap::new();
+	let mut dead_man: HashMap<Symbol, Duration> = HashMap::new();
 	for (position_id, plk) in positions_local_knowledge.iter() {
+		position_sides.insert(*position_id, plk.position_side);
+		// protocols always speak in terms of the market they read data from; execution goes wherever the position was opened
+		let on_position_market = |symbol: &Symbol| Symbol {
+			market: plk.market,
+			..symbol.clone()
+		};
+		if let Some(window) = plk.dead_man {
+			for o in &plk.requested_orders {
+				dead_man.entry(on_position_market(&o.symbol)).and_modify(|w| *w = (*w).min(window)).or_insert(window);
+			}
+		}
 		let remap_to_position_id = plk.requested_orders.iter().map(|o| {
 			let new_id = PositionOrderId::new_from_protocol_id(*position_id, o.id.clone());
-			ConceptualOrder { id: new_id, ..o.clone() }
+			ConceptualOrder {
+				id: new_id,
+				symbol: on_position_market(&o.symbol),
+				..o.clone()
+			}
 		});
 		requested_orders_all_positions.extend(remap_to_position_id);
 	}
-	let target_orders = hub_process_orders(requested_orders_all_positions);
+	let target_orders = hub_process_orders(requested_orders_all_positions, &position_sides);
 
 	debug!(?target_orders);
 
-	// // Binance Futures
-	let binance_futures_orders = target_orders
-		.iter()
-		.filter(|o| o.symbol.market == Market::BinanceFutures)
-		.cloned()
-		.collect::<Vec<Order<PositionOrderId>>>();
+	for (market, orders_tx) in orders_txs {
+		let market_orders = target_orders.iter().filter(|o| o.symbol.market == *market).cloned().collect::<Vec<Order<PositionOrderId>>>();
+		let market_dead_man = dead_man.iter().filter(|(symbol, _)| symbol.market == *market).map(|(s, w)| (s.clone(), *w)).collect();
 
-	let exchange_local_knowledge = exchanges_local_knowledge.entry(Market::BinanceFutures).or_default();
-	let passforward = HubToExchange::new(exchange_local_knowledge.key, binance_futures_orders);
-	orders_tx.send(passforward)?;
-	//
+		let exchange_local_knowledge = exchanges_local_knowledge.entry(*market).or_default();
+		let passforward = HubToExchange::new(exchange_local_knowledge.key, market_orders, market_dead_man);
+		// Only wake up runtimes whose slice of the target actually changed
+		orders_tx.send_if_modified(|current| {
+			if current.key == passforward.key && current.orders == passforward.orders && current.dead_man == passforward.dead_man {
+				return false;
+			}
+			*current = passforward;
+			true
+		});
+	}
 	Ok(())
 }
 
+/// Fails the position with `e`, instead of executing its request.
+async fn reject(position_callback: &HubToPosition, e: Report) {
+	// position could be gone already, nothing to do about it then
+	let _ = position_callback.sender.send(Err(e)).await;
+}
+
 #[instrument]
 async fn handle_fill(fill: ExchangeToHub, position_local_knowledge: &mut PositionLocalKnowledge) -> Result<()> {
 	position_local_knowledge.key = fill.key;
-	let vec_fill = vec![ProtocolFill::new(fill.order.id.into(), fill.fill_qty)];
-	position_local_knowledge.callback.send(ProtocolFills::new(position_local_knowledge.key, vec_fill)).await?;
+	let vec_fill = vec![ProtocolFill::new(fill.order.id.into(), fill.fill_qty, fill.fill_price)];
+	position_local_knowledge.callback.send(Ok(ProtocolFills::new(position_local_knowledge.key, vec_fill))).await?;
 	debug!("Sent fills to position");
 	Ok(())
 }
@@ -164,12 +255,13 @@ async fn handle_fill(fill: ExchangeToHub, position_local_knowledge: &mut Positio
 // HACK
 /// Thing that applies all the logic for deciding on how to best express ensemble of requested orders.
 #[instrument]
-fn hub_process_orders(conceptual_orders: Vec<ConceptualOrder<PositionOrderId>>) -> Vec<Order<PositionOrderId>> {
+fn hub_process_orders(conceptual_orders: Vec<ConceptualOrder<PositionOrderId>>, position_sides: &HashMap<Uuid, Side>) -> Vec<Order<PositionOrderId>> {
 	let mut orders: Vec<Order<PositionOrderId>> = Vec::new();
 	for o in conceptual_orders {
+		let position_side = *position_sides.get(&o.id.position_id).expect("Orders can only come from known positions");
 		match &o.order_type {
 			ConceptualOrderType::Market(_) => {
-				let order = Order::new(o.id, order_types::OrderType::Market, o.symbol.clone(), o.side, o.qty_notional);
+				let order = Order::new(o.id, order_types::OrderType::Market, o.symbol.clone(), o.side, position_side, o.qty_notional);
 				orders.push(order);
 			}
 			ConceptualOrderType::StopMarket(stop_market) => {
@@ -178,11 +270,33 @@ fn hub_process_orders(conceptual_orders: Vec<ConceptualOrder<PositionOrderId>>)
 					order_types::OrderType::StopMarket(order_types::StopMarketOrder::new(stop_market.price)),
 					o.symbol.clone(),
 					o.side,
+					position_side,
+					o.qty_notional,
+				);
+				orders.push(order);
+			}
+			ConceptualOrderType::Chase(chase) => {
+				let order = Order::new(
+					o.id,
+					order_types::OrderType::Chase(order_types::ChaseOrder::new(chase.duration, chase.max_range)),
+					o.symbol.clone(),
+					o.side,
+					position_side,
+					o.qty_notional,
+				);
+				orders.push(order);
+			}
+			ConceptualOrderType::Limit(limit) => {
+				let order = Order::new(
+					o.id,
+					order_types::OrderType::Limit(order_types::LimitOrder::new(limit.price, limit.limit_only)),
+					o.symbol.clone(),
+					o.side,
+					position_side,
 					o.qty_notional,
 				);
 				orders.push(order);
 			}
-			_ => panic!("Unsupported order type"),
 		}
 	}
 	orders
@@ -215,7 +329,8 @@ mod tests {
 			},
 		];
 
-		let converted = hub_process_orders(from_orders);
+		let position_sides = from_orders.iter().map(|o| (o.id.position_id, Side::Buy)).collect::<HashMap<_, _>>();
+		let converted = hub_process_orders(from_orders, &position_sides);
 		insta::assert_json_snapshot!(converted, @r###"
   [
     {
@@ -231,6 +346,7 @@ mod tests {
         "market": "BinanceFutures"
       },
       "side": "Buy",
+      "position_side": "Buy",
       "qty_notional": 100.0
     },
     {
@@ -250,6 +366,7 @@ mod tests {
         "market": "BinanceFutures"
       },
       "side": "Buy",
+      "position_side": "Buy",
       "qty_notional": 100.0
     }
   ]
@@ -0,0 +1,402 @@
+//! Single source of market data for all protocols of all positions.
+//!
+//! Streams are multiplexed over one websocket connection per symbol, and are only subscribed to once, no matter how many protocols read them.
+//! Connections never give up: they reconnect with backoff, resubscribe, and backfill klines that closed while they were down.
+use std::{
+	collections::{HashMap, HashSet},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{Result, eyre};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::{
+	select,
+	sync::{broadcast, mpsc},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, info, instrument, warn};
+use v_utils::trades::{Ohlc, Timeframe};
+
+use super::{Symbol, binance};
+use crate::utils::report_connection_problem;
+
+const BINANCE_FUTURES_WS: &str = "wss://fstream.binance.com/ws";
+const BINANCE_TIMEFRAMES: [&str; 19] = [
+	"1s", "5s", "15s", "30s", "1m", "3m", "5m", "15m", "30m", "1h", "2h", "4h", "6h", "8h", "12h", "1d", "3d", "1w", "1M",
+];
+/// How far a slow reader may fall behind before it starts missing updates.
+const FEED_CAPACITY: usize = 1024;
+/// We ping the exchange this often, so a healthy connection is never silent for longer.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Connection is considered dead and is reestablished after this long without hearing anything from the exchange.
+const STALE_AFTER: Duration = Duration::from_secs(45);
+/// Enough to cover a few hours of 1m klines, at the request weight of 2.
+const BACKFILL_LIMIT: usize = 500;
+
+#[derive(Clone, Copy, Debug)]
+pub struct KlineUpdate {
+	pub ohlc: Ohlc,
+	/// Unix ms. Identifies the kline across updates.
+	pub open_time: i64,
+	/// Whether this is the final update of the kline. Before that it's still forming.
+	pub closed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BookTop {
+	pub bid: f64,
+	pub ask: f64,
+}
+
+#[derive(Clone, Debug)]
+enum FeedSender {
+	Trades(broadcast::Sender<f64>),
+	Klines(broadcast::Sender<KlineUpdate>),
+	BookTicker(broadcast::Sender<BookTop>),
+}
+
+/// Keyed by binance stream name, e.g. "btcusdt@aggTrade".
+type Feeds = Arc<Mutex<HashMap<String, FeedSender>>>;
+
+/// Receiving end of one stream.
+#[derive(Debug)]
+pub struct Feed<T>(broadcast::Receiver<T>);
+impl<T: Clone> Feed<T> {
+	/// Waits for the next update. Updates missed from falling behind are skipped over, as only the latest state of the market matters.
+	pub async fn recv(&mut self) -> Option<T> {
+		loop {
+			match self.0.recv().await {
+				Ok(update) => return Some(update),
+				Err(broadcast::error::RecvError::Lagged(n)) => warn!("Market data reader fell behind, skipped {n} updates"),
+				Err(broadcast::error::RecvError::Closed) => return None,
+			}
+		}
+	}
+}
+
+/// Handle to the market data service. Cheap to clone, all clones share the same connections.
+#[derive(Clone, Debug, Default)]
+pub struct MarketData {
+	feeds: Feeds,
+	/// Requests to subscribe to more streams, per symbol connection.
+	connections: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>,
+	/// When each symbol's connection last heard from the exchange.
+	last_heard: Arc<Mutex<HashMap<String, Instant>>>,
+}
+impl MarketData {
+	/// Prices of aggregated trades.
+	pub fn trades(&self, symbol: &Symbol) -> Feed<f64> {
+		let stream = format!("{}@aggTrade", Self::stream_symbol(symbol));
+		self.subscribe(symbol, stream, FeedSender::Trades, |f| match f {
+			FeedSender::Trades(tx) => Some(tx),
+			_ => None,
+		})
+	}
+
+	/// All updates of the current kline, see [KlineUpdate::closed]. Klines that closed while the connection was down are replayed once it's back.
+	pub fn klines(&self, symbol: &Symbol, tf: Timeframe) -> Result<Feed<KlineUpdate>> {
+		let stream = format!("{}@kline_{}", Self::stream_symbol(symbol), binance_interval(tf)?);
+		Ok(self.subscribe(symbol, stream, FeedSender::Klines, |f| match f {
+			FeedSender::Klines(tx) => Some(tx),
+			_ => None,
+		}))
+	}
+
+	/// Last `n` closed klines, for warming indicators up. Keeps retrying until the exchange responds.
+	pub async fn closed_klines(&self, symbol: &Symbol, tf: Timeframe, n: usize) -> Result<Vec<Ohlc>> {
+		let interval = binance_interval(tf)?;
+		let mut backoff = Backoff::default();
+		loop {
+			// last one is still forming
+			match binance::get_historic_klines(symbol.to_string(), interval.clone(), n + 1).await {
+				Ok(mut klines) => {
+					klines.pop();
+					return Ok(klines.into_iter().map(Ohlc::from).collect());
+				}
+				Err(e) => {
+					report_connection_problem(e.wrap_err(format!("Failed to fetch {interval} klines of {symbol}"))).await;
+					backoff.wait().await;
+				}
+			}
+		}
+	}
+
+	/// How long the connection of the symbol has not heard anything from the exchange. `None` if nothing is subscribed to on it.
+	pub fn silent_for(&self, symbol: &Symbol) -> Option<Duration> {
+		self.last_heard.lock().unwrap().get(&Self::stream_symbol(symbol)).map(|t| t.elapsed())
+	}
+
+	/// Passes a price obtained elsewhere, such as polled over REST while the connection is down, on to readers of the symbol's trades.
+	pub fn push_trade(&self, symbol: &Symbol, price: f64) {
+		let stream = format!("{}@aggTrade", Self::stream_symbol(symbol));
+		if let Some(FeedSender::Trades(tx)) = self.feeds.lock().unwrap().get(&stream) {
+			let _ = tx.send(price);
+		}
+	}
+
+	pub fn book_ticker(&self, symbol: &Symbol) -> Feed<BookTop> {
+		let stream = format!("{}@bookTicker", Self::stream_symbol(symbol));
+		self.subscribe(symbol, stream, FeedSender::BookTicker, |f| match f {
+			FeedSender::BookTicker(tx) => Some(tx),
+			_ => None,
+		})
+	}
+
+	fn stream_symbol(symbol: &Symbol) -> String {
+		format!("{}{}", symbol.base, symbol.quote).to_lowercase()
+	}
+
+	fn subscribe<T: Clone>(&self, symbol: &Symbol, stream: String, new_feed: fn(broadcast::Sender<T>) -> FeedSender, as_sender: fn(&FeedSender) -> Option<&broadcast::Sender<T>>) -> Feed<T> {
+		let feed = register(&self.feeds, stream.clone(), new_feed, as_sender);
+
+		let mut connections = self.connections.lock().unwrap();
+		let symbol_key = Self::stream_symbol(symbol);
+		let needs_connection = connections.get(&symbol_key).is_none_or(|tx| tx.is_closed());
+		if needs_connection {
+			let (tx, subscribe_rx) = mpsc::unbounded_channel();
+			self.last_heard.lock().unwrap().insert(symbol_key.clone(), Instant::now());
+			tokio::spawn(run_connection(symbol_key.clone(), subscribe_rx, self.feeds.clone(), self.last_heard.clone()));
+			connections.insert(symbol_key.clone(), tx);
+		}
+		// the connection task dedups, so it's fine to request the same stream again
+		if connections[&symbol_key].send(stream.clone()).is_err() {
+			warn!("Market data connection for {symbol_key} died right as {stream} was requested");
+		}
+
+		feed
+	}
+}
+
+/// Reader of the stream, creating its sender if it's the first one. Does not touch the connection.
+fn register<T: Clone>(feeds: &Feeds, stream: String, new_feed: fn(broadcast::Sender<T>) -> FeedSender, as_sender: fn(&FeedSender) -> Option<&broadcast::Sender<T>>) -> Feed<T> {
+	let mut feeds = feeds.lock().unwrap();
+	let feed = feeds.entry(stream).or_insert_with(|| new_feed(broadcast::channel(FEED_CAPACITY).0));
+	Feed(as_sender(feed).expect("stream names are unique to their feed type").subscribe())
+}
+
+fn binance_interval(tf: Timeframe) -> Result<String> {
+	tf.try_as_predefined(&BINANCE_TIMEFRAMES)
+		.map(|s| s.to_string())
+		.ok_or_else(|| eyre!("Binance has no {tf} klines"))
+}
+
+/// Exponential, from 1s up to a minute.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Backoff(Duration);
+impl Default for Backoff {
+	fn default() -> Self {
+		Self(Duration::from_secs(1))
+	}
+}
+impl Backoff {
+	pub(crate) async fn wait(&mut self) {
+		tokio::time::sleep(self.0).await;
+		self.0 = (self.0 * 2).min(Duration::from_secs(60));
+	}
+}
+
+type WsStream = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// State of a symbol's connection that outlives reconnects.
+#[derive(Debug, Default)]
+struct ConnectionState {
+	subscribed: HashSet<String>,
+	/// Open time of the last closed kline seen, per kline stream
+	last_closed: HashMap<String, i64>,
+}
+
+/// Keeps the connection of one symbol alive for as long as the engine runs.
+#[instrument(skip(subscribe_rx, feeds, last_heard))]
+async fn run_connection(symbol_key: String, mut subscribe_rx: mpsc::UnboundedReceiver<String>, feeds: Feeds, last_heard: Arc<Mutex<HashMap<String, Instant>>>) {
+	let mut state = ConnectionState::default();
+	let mut backoff = Backoff::default();
+	loop {
+		let e = match connect_async(BINANCE_FUTURES_WS).await {
+			Ok((ws_stream, _)) => {
+				info!("Market data connection for {symbol_key} established");
+				backoff = Backoff::default();
+				serve_connection(ws_stream, &symbol_key, &mut subscribe_rx, &mut state, &feeds, &last_heard).await
+			}
+			Err(e) => e.into(),
+		};
+		report_connection_problem(e.wrap_err(format!("Market data connection for {symbol_key} is down"))).await;
+		backoff.wait().await;
+	}
+}
+
+/// Runs until the connection breaks, returning why.
+async fn serve_connection(
+	ws_stream: WsStream,
+	symbol_key: &str,
+	subscribe_rx: &mut mpsc::UnboundedReceiver<String>,
+	state: &mut ConnectionState,
+	feeds: &Feeds,
+	last_heard: &Mutex<HashMap<String, Instant>>,
+) -> eyre::Report {
+	let (mut write, mut read) = ws_stream.split();
+	let mut request_id = 0_u64;
+	let mut ping = tokio::time::interval(PING_INTERVAL);
+	let mut heard_at = Instant::now();
+
+	// we're reconnecting
+	if !state.subscribed.is_empty() {
+		request_id += 1;
+		let request = serde_json::json!({ "method": "SUBSCRIBE", "params": state.subscribed.iter().collect::<Vec<_>>(), "id": request_id });
+		if let Err(e) = write.send(Message::Text(request.to_string().into())).await {
+			return e.into();
+		}
+		backfill_klines(symbol_key, state, feeds).await;
+	}
+
+	loop {
+		select! {
+			Some(stream) = subscribe_rx.recv() => {
+				if state.subscribed.insert(stream.clone()) {
+					request_id += 1;
+					let request = serde_json::json!({ "method": "SUBSCRIBE", "params": [stream], "id": request_id });
+					if let Err(e) = write.send(Message::Text(request.to_string().into())).await {
+						return e.into();
+					}
+					debug!("Subscribed to {stream}");
+				}
+			},
+			msg = read.next() => {
+				let msg = match msg {
+					Some(Ok(msg)) => msg,
+					Some(Err(e)) => return e.into(),
+					None => return eyre!("Connection closed by the exchange"),
+				};
+				heard_at = Instant::now();
+				last_heard.lock().unwrap().insert(symbol_key.to_owned(), heard_at);
+				if msg.is_text()
+					&& let Some((stream, open_time)) = route(&msg.into_data(), feeds)
+				{
+					state.last_closed.insert(stream, open_time);
+				}
+			},
+			_ = ping.tick() => {
+				if heard_at.elapsed() > STALE_AFTER {
+					return eyre!("Nothing heard from the exchange for {:?}", heard_at.elapsed());
+				}
+				if let Err(e) = write.send(Message::Ping(Vec::new().into())).await {
+					return e.into();
+				}
+			},
+		}
+	}
+}
+
+/// Replays klines that closed while the connection was down, as readers expect to see every one of them.
+async fn backfill_klines(symbol_key: &str, state: &mut ConnectionState, feeds: &Feeds) {
+	for stream in &state.subscribed {
+		let (Some((_, interval)), Some(&last_seen)) = (stream.split_once("@kline_"), state.last_closed.get(stream)) else {
+			continue;
+		};
+		let klines = match binance::get_historic_klines(symbol_key.to_uppercase(), interval.to_owned(), BACKFILL_LIMIT).await {
+			Ok(klines) => klines,
+			Err(e) => {
+				warn!("Failed to backfill {stream}, its readers will skip over the gap: {e}");
+				continue;
+			}
+		};
+		if klines.first().is_some_and(|k| k.open_time > last_seen) {
+			warn!("Gap on {stream} is longer than {BACKFILL_LIMIT} klines, only the latest of them are backfilled");
+		}
+		let now = chrono::Utc::now().timestamp_millis();
+		let missed: Vec<_> = klines.into_iter().filter(|k| k.open_time > last_seen && k.close_time < now).collect();
+		debug!("Backfilling {} klines on {stream}", missed.len());
+
+		let feeds = feeds.lock().unwrap();
+		let Some(FeedSender::Klines(tx)) = feeds.get(stream) else { continue };
+		for kline in missed {
+			let open_time = kline.open_time;
+			let _ = tx.send(KlineUpdate {
+				ohlc: kline.into(),
+				open_time,
+				closed: true,
+			});
+			state.last_closed.insert(stream.clone(), open_time);
+		}
+	}
+}
+
+/// Passes the message on to readers of its stream, if any.
+///
+/// # Returns
+/// Stream name and open time of the kline, if the message closed one.
+fn route(data: &[u8], feeds: &Feeds) -> Option<(String, i64)> {
+	let json: Value = match serde_json::from_slice(data) {
+		Ok(json) => json,
+		Err(e) => {
+			warn!("Failed to parse market data message as JSON: {e}");
+			return None;
+		}
+	};
+	// responses to our SUBSCRIBE requests
+	if json.get("result").is_some() {
+		return None;
+	}
+	let symbol = json["s"].as_str()?.to_lowercase();
+	let parse = |v: &Value| v.as_str().and_then(|s| s.parse::<f64>().ok());
+
+	let feeds = feeds.lock().unwrap();
+	match json["e"].as_str() {
+		Some("aggTrade") =>
+			if let (Some(FeedSender::Trades(tx)), Some(price)) = (feeds.get(&format!("{symbol}@aggTrade")), parse(&json["p"])) {
+				let _ = tx.send(price);
+			},
+		Some("kline") => {
+			let k = &json["k"];
+			let stream = format!("{symbol}@kline_{}", k["i"].as_str()?);
+			if let (Some(FeedSender::Klines(tx)), Some(open), Some(high), Some(low), Some(close), Some(open_time)) =
+				(feeds.get(&stream), parse(&k["o"]), parse(&k["h"]), parse(&k["l"]), parse(&k["c"]), k["t"].as_i64())
+			{
+				let update = KlineUpdate {
+					ohlc: Ohlc { open, high, low, close },
+					open_time,
+					closed: k["x"].as_bool().unwrap_or(false),
+				};
+				let _ = tx.send(update);
+				return update.closed.then_some((stream, open_time));
+			}
+		}
+		Some("bookTicker") =>
+			if let (Some(FeedSender::BookTicker(tx)), Some(bid), Some(ask)) = (feeds.get(&format!("{symbol}@bookTicker")), parse(&json["b"]), parse(&json["a"])) {
+				let _ = tx.send(BookTop { bid, ask });
+			},
+		_ => debug!("Unhandled market data message: {json}"),
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn routes_by_stream() {
+		// registered directly, as subscribing would connect to the exchange and mix live updates in
+		let feeds = Feeds::default();
+		let mut trades = register(&feeds, "btcusdt@aggTrade".to_owned(), FeedSender::Trades, |f| match f {
+			FeedSender::Trades(tx) => Some(tx),
+			_ => None,
+		});
+		let mut klines = register(&feeds, "btcusdt@kline_1m".to_owned(), FeedSender::Klines, |f| match f {
+			FeedSender::Klines(tx) => Some(tx),
+			_ => None,
+		});
+
+		route(br#"{"e":"aggTrade","s":"BTCUSDT","p":"100.5","q":"1"}"#, &feeds);
+		let closed = route(br#"{"e":"kline","s":"BTCUSDT","k":{"t":60000,"i":"1m","o":"1","h":"3","l":"0.5","c":"2","x":true}}"#, &feeds);
+		assert_eq!(closed, Some(("btcusdt@kline_1m".to_string(), 60000)));
+		route(br#"{"e":"aggTrade","s":"ETHUSDT","p":"3000","q":"1"}"#, &feeds);
+
+		assert_eq!(trades.recv().await, Some(100.5));
+		let kline = klines.recv().await.unwrap();
+		assert!(kline.closed);
+		assert_eq!(kline.ohlc.close, 2.0);
+		assert!(trades.0.is_empty());
+	}
+}
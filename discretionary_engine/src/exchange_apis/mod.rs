@@ -1,8 +1,10 @@
 //! Individual exchange APIs expose methods and frameworks for interacting with their respective exchanges. At this level we have [hub.rs] and [all_exchanges.rs] which interpret the information passed up by the individual exchanges in the manner necessary for the task. [all_exchanges.rs] exposes information for Protocols and Positions, [hub.rs] uses it to construct the optimal execution strategy.
 
 pub mod binance;
+pub mod bybit;
 pub mod exchanges;
 pub mod hub;
+pub mod market_data;
 pub mod order_types;
 
 use color_eyre::eyre::{Result, bail};
@@ -17,6 +19,7 @@ pub enum Market {
 	BinanceFutures,
 	BinanceSpot,
 	BinanceMargin,
+	BybitFutures,
 }
 impl Market {
 	pub fn get_base_url(&self) -> Url {
@@ -24,6 +27,7 @@ impl Market {
 			Market::BinanceFutures => Url::parse("https://fapi.binance.com/").unwrap(),
 			Market::BinanceSpot => Url::parse("https://api.binance.com/").unwrap(),
 			Market::BinanceMargin => Url::parse("https://api.binance.com/").unwrap(),
+			Market::BybitFutures => Url::parse("https://api.bybit.com/").unwrap(),
 		}
 	}
 
@@ -32,6 +36,7 @@ impl Market {
 			Market::BinanceFutures => symbol.to_owned().to_uppercase() + "USDT",
 			Market::BinanceSpot => symbol.to_owned().to_uppercase() + "USDT",
 			Market::BinanceMargin => symbol.to_owned().to_uppercase() + "USDT",
+			Market::BybitFutures => symbol.to_owned().to_uppercase() + "USDT",
 		}
 	}
 }
@@ -44,11 +49,20 @@ impl std::str::FromStr for Market {
 			_ if graphemics!(BinanceFutures).contains(&s) => Ok(Market::BinanceFutures),
 			_ if graphemics!(BinanceSpot).contains(&s) => Ok(Market::BinanceSpot),
 			_ if graphemics!(BinanceMargin).contains(&s) => Ok(Market::BinanceMargin),
+			_ if graphemics!(BybitFutures).contains(&s) => Ok(Market::BybitFutures),
 			_ => bail!("Unknown market: {}", s),
 		}
 	}
 }
 
+/// Margin mode of a derivatives position. Exchange-specific naming is handled by the individual exchange modules.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize, clap::ValueEnum)]
+pub enum MarginType {
+	#[default]
+	Cross,
+	Isolated,
+}
+
 /// Contains information sufficient to identify the exact orderbook.
 /// ```rust
 /// let symbol = "BTC-USDT-BinanceFutures".parse::<discretionary_engine::exchange_apis::Symbol>().unwrap();
@@ -1,6 +1,6 @@
-use std::hash::Hash;
+use std::{fmt, hash::Hash, time::Duration};
 
-use color_eyre::eyre::{Result, bail};
+use color_eyre::eyre::{Result, bail, ensure};
 use derive_new::new;
 use serde::{Deserialize, Serialize};
 use v_utils::{Percent, trades::Side};
@@ -19,6 +19,8 @@ pub struct Order<Id: IdRequirements> {
 	pub order_type: OrderType,
 	pub symbol: Symbol,
 	pub side: Side,
+	/// Side of the Position owning the order. Only matters for accounts in hedge (dual-side) mode, where the exchange needs it to know which of the two positions is affected.
+	pub position_side: Side,
 	pub qty_notional: f64,
 }
 
@@ -28,7 +30,8 @@ pub enum OrderType {
 	#[default]
 	Market,
 	StopMarket(StopMarketOrder),
-	// Limit(LimitOrder),
+	Chase(ChaseOrder),
+	Limit(LimitOrder),
 	// StopLimit(StopLimitOrder),
 	// TrailingStop(TrailingStopOrder),
 	// TWAP(TWAPOrder),
@@ -42,6 +45,19 @@ pub struct StopMarketOrder {
 	pub price: f64,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, new)]
+pub struct LimitOrder {
+	pub price: f64,
+	/// Rejected by the exchange rather than filled as a taker, should it cross the book on arrival.
+	pub post_only: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, new)]
+pub struct ChaseOrder {
+	pub duration: Duration,
+	pub max_range: Option<PriceDistance>,
+}
+
 //=============================================================================
 // Conceptual Orders
 //=============================================================================
@@ -73,6 +89,7 @@ impl<Id: IdRequirements> ConceptualOrder<Id> {
 	pub fn price(&self) -> Result<f64> {
 		match &self.order_type {
 			ConceptualOrderType::Market(_) => bail!("Market orders don't have a price"),
+			ConceptualOrderType::Chase(_) => bail!("Chase orders don't have a price"),
 			ConceptualOrderType::Limit(l) => Ok(l.price),
 			ConceptualOrderType::StopMarket(s) => Ok(s.price),
 		}
@@ -85,6 +102,7 @@ pub enum ConceptualOrderType {
 	Market(ConceptualMarket),
 	Limit(ConceptualLimit),
 	StopMarket(ConceptualStopMarket),
+	Chase(ConceptualChase),
 }
 impl Default for ConceptualOrderType {
 	fn default() -> Self {
@@ -104,6 +122,66 @@ pub struct ConceptualStopMarket {
 	pub price: f64,
 }
 
+/// Limit order kept at the top of the book for `duration`, after which whatever is left gets market-filled. Market-like: it is expected to get filled, just cheaper.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, new)]
+pub struct ConceptualChase {
+	pub duration: Duration,
+	/// How far the price may run from where it was on placement before we stop chasing it. `None` chases indefinitely.
+	pub max_range: Option<PriceDistance>,
+}
+
+/// Distance from some reference price, such as the arrival or the entry one.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum PriceDistance {
+	/// In units of price.
+	Absolute(f64),
+	/// Fraction of the reference price.
+	Percent(Percent),
+}
+impl Default for PriceDistance {
+	fn default() -> Self {
+		PriceDistance::Absolute(0.0)
+	}
+}
+impl PriceDistance {
+	/// In units of price.
+	pub fn distance(&self, reference_price: f64) -> f64 {
+		match self {
+			PriceDistance::Absolute(d) => *d,
+			PriceDistance::Percent(p) => reference_price * **p,
+		}
+	}
+
+	/// "50" for absolute, "0.5%" for percents of the reference price. Negative distances are always refused, zero only if not `allow_zero`.
+	pub fn parse(s: &str, allow_zero: bool) -> Result<Self> {
+		let distance = match s.strip_suffix('%') {
+			Some(percent) => PriceDistance::Percent(Percent(percent.parse::<f64>()? / 100.0)),
+			None => PriceDistance::Absolute(s.parse()?),
+		};
+		match allow_zero {
+			true => ensure!(distance.distance(1.0) >= 0.0, "Distance can't be negative, got {s}"),
+			false => ensure!(distance.distance(1.0) > 0.0, "Distance must be positive, got {s}"),
+		}
+		Ok(distance)
+	}
+}
+/// Positive distance, see [PriceDistance::parse].
+impl std::str::FromStr for PriceDistance {
+	type Err = eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Self::parse(s, false)
+	}
+}
+impl fmt::Display for PriceDistance {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PriceDistance::Absolute(d) => write!(f, "{d}"),
+			PriceDistance::Percent(p) => write!(f, "{}%", **p * 100.0),
+		}
+	}
+}
+
 #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, new)]
 pub struct ConceptualLimit {
 	pub price: f64,
@@ -9,10 +9,12 @@ mod adjust_pos;
 mod bybit_common;
 mod chase_limit;
 pub mod config;
+mod control;
 pub mod exchange_apis;
 mod nuke;
 pub mod positions;
 pub mod protocols;
+mod reconcile;
 mod risk;
 mod shell_init;
 pub mod utils;
@@ -25,10 +27,10 @@ use std::{
 use clap::{Args, Parser, Subcommand};
 use color_eyre::eyre::{Context, Result, bail};
 use config::{LiveSettings, SettingsFlags};
-use exchange_apis::{exchanges::Exchanges, hub, hub::PositionToHub};
+use exchange_apis::{MarginType, Market, exchanges::Exchanges, hub, hub::PositionToHub};
 use positions::*;
 use tokio::{sync::mpsc, task::JoinSet};
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use v_utils::{
 	trades::{Side, Timeframe},
 	utils::exit_on_error,
@@ -58,6 +60,8 @@ enum Commands {
 	AdjustPos(adjust_pos::AdjustPosArgs),
 	/// Close position completely
 	Nuke(nuke::NukeArgs),
+	/// Change params of a protocol of a running position
+	UpdateProtocol(control::UpdateProtocolArgs),
 	/// Risk management commands
 	Risk {
 		#[command(subcommand)]
@@ -72,18 +76,39 @@ struct PositionArgs {
 	#[arg(short, long)]
 	size_usdt: f64,
 	/// timeframe, in the format of "1m", "1h", "3M", etc.
-	/// determines the target period for which we expect the edge to persist.
+	/// determines the target period for which we expect the edge to persist. Acted upon by the `time` followup protocol.
 	#[arg(short, long)]
 	tf: Option<Timeframe>,
 	/// _only_ the coin name itself. e.g. "BTC" or "ETH". Providing full symbol currently will error on the stage of making price requests for the coin.
 	#[arg(short, long)]
 	coin: String,
+	/// Market to execute on, e.g. "BinanceFutures" or "BybitFutures". Defaults to Binance Futures.
+	#[arg(long)]
+	market: Option<Market>,
 	/// acquisition protocols parameters, in the format of "<protocol>-<params>", e.g. "ts:p0.5". Params consist of their starting letter followed by the value, e.g. "p0.5" for 0.5% offset. If multiple params are required, they are separated by '-'.
+	/// Any protocol also takes a `w<weight>` param, setting its share of the size relative to other protocols of the same type, e.g. "ts:p0.5:w0.7". Defaults to 1.
 	#[arg(short, long)]
 	acquisition_protocols: Vec<String>,
 	/// followup protocols parameters, in the format of "<protocol>-<params>", e.g. "ts:p0.5". Params consist of their starting letter followed by the value, e.g. "p0.5" for 0.5% offset. If multiple params are required, they are separated by '-'.
 	#[arg(short, long)]
 	followup_protocols: Vec<String>,
+	/// Leverage to set on the symbol before acquisition. Keeps the exchange's current setting if not provided.
+	#[arg(short, long)]
+	leverage: Option<u8>,
+	/// Margin type to set on the symbol before acquisition. Keeps the exchange's current setting if not provided.
+	#[arg(short, long)]
+	margin_type: Option<MarginType>,
+	/// Arm the exchange's auto-cancel of the position's orders, triggered if the engine stops sending heartbeats for this long. Only covers acquisition, unless `--dead-man-followup` is set.
+	///
+	/// Exchanges don't cancel by position: Binance cancels everything on the symbol, Bybit everything on the account's derivatives, and keeps its switch armed at 5m after the engine is gone. Refuses to arm while other positions are running in that scope, unless `--dead-man-shared` is set.
+	#[arg(long)]
+	dead_man: Option<Timeframe>,
+	/// Keep the dead-man switch armed during followup too, meaning protective stops get cancelled along with everything else.
+	#[arg(long, requires = "dead_man")]
+	dead_man_followup: bool,
+	/// Arm the dead-man switch even with other positions running in its scope, and their orders (stops included) getting cancelled along with ours. Positions opened once it's armed are exposed to it either way.
+	#[arg(long, requires = "dead_man")]
+	dead_man_shared: bool,
 }
 
 // TODO: change to initializing exchange sockets once, then just have a loop listening on localhost, that accepts new positions or modification requests.
@@ -117,6 +142,12 @@ async fn main() -> Result<()> {
 		return Ok(());
 	}
 
+	// Only talks to an already running engine
+	if let Commands::UpdateProtocol(args) = cli.command {
+		exit_on_error(control::main(args, live_settings).await);
+		return Ok(());
+	}
+
 	// Validate positions_dir exists
 	let initial_config = live_settings.initial();
 	std::fs::create_dir_all(&initial_config.positions_dir).wrap_err_with(|| format!("Failed to create positions directory at {:?}", initial_config.positions_dir))?;
@@ -132,17 +163,21 @@ async fn main() -> Result<()> {
 	utils::init_subscriber(log_path);
 	let mut js = JoinSet::new();
 	let exchanges_arc = Arc::new(
-		Exchanges::init(live_settings.clone())
+		Exchanges::init(live_settings.clone(), cli.testnet)
 			.await
 			.wrap_err_with(|| "Error initializing Exchanges, likely indicative of bad internet connection")?,
 	);
 	let tx = hub::init_hub(live_settings.clone(), &mut js, exchanges_arc.clone());
 
+	if let Commands::Run(_) = cli.command {
+		js.spawn(reconcile::reconciliation_loop(live_settings.clone(), exchanges_arc.clone(), tx.clone()));
+	}
+
 	exit_on_error(match cli.command {
 		Commands::Run(args) => command_new(args, live_settings.clone(), tx, exchanges_arc).await,
 		Commands::AdjustPos(adjust_pos_args) => adjust_pos::main(adjust_pos_args, live_settings.clone(), cli.testnet).await,
 		Commands::Nuke(nuke_args) => nuke::main(nuke_args, live_settings.clone(), cli.testnet).await,
-		Commands::Risk { .. } | Commands::Init(_) => unreachable!(),
+		Commands::Risk { .. } | Commands::Init(_) | Commands::UpdateProtocol(_) => unreachable!(),
 	});
 
 	Ok(())
@@ -172,10 +207,58 @@ async fn command_new(position_args: PositionArgs, live_settings: Arc<LiveSetting
 	let followup_protocols = protocols::interpret_protocol_specs(position_args.followup_protocols).wrap_err("Failed to interpret followup protocols")?;
 	let acquisition_protocols = protocols::interpret_protocol_specs(position_args.acquisition_protocols).wrap_err("Failed to interpret acquisition protocols")?;
 
-	let spec = PositionSpec::new(position_args.coin, side, target_size);
+	let dead_man = position_args.dead_man.map(|tf| DeadManSwitch::new(Duration::from_millis(tf.0), position_args.dead_man_followup));
+	let spec = PositionSpec::new(
+		position_args.coin,
+		side,
+		target_size,
+		position_args.market.unwrap_or_default(),
+		position_args.leverage,
+		position_args.margin_type,
+		dead_man,
+		position_args.tf.map(|tf| Duration::from_millis(tf.0)),
+	);
+	// the hub only runs the Bybit runtime if it's configured
+	if spec.market == Market::BybitFutures {
+		live_settings
+			.config()?
+			.get_exchange(v_exchanges::ExchangeName::Bybit)
+			.wrap_err("Positions on Bybit require its credentials")?;
+	}
+	if spec.dead_man.is_some() && !position_args.dead_man_shared {
+		let positions_dir = live_settings.config()?.positions_dir;
+		// sockets don't tell the market, so on Bybit any running position could be in the scope
+		let sharing_scope = control::live_positions(&positions_dir)
+			.await?
+			.into_iter()
+			.filter(|(asset, _)| spec.market == Market::BybitFutures || *asset == spec.asset)
+			.map(|(asset, id)| format!("{asset} {id}"))
+			.collect::<Vec<_>>();
+		if !sharing_scope.is_empty() {
+			bail!(
+				"The dead-man switch would cancel the orders of other running positions too: {}. Pass `--dead-man-shared` to arm it regardless",
+				sharing_scope.join(", ")
+			);
+		}
+	}
+	Exchanges::apply_position_settings(exchanges_arc.clone(), live_settings.clone(), &spec)
+		.await
+		.wrap_err("Failed to apply leverage and margin type")?;
+	println!("Position id: {}", spec.id);
+	// shares params with the protocols passed on to the position
+	let all_protocols = [acquisition_protocols.clone(), followup_protocols.clone()].concat();
+	let positions_dir = live_settings.config()?.positions_dir;
+	let spec_clone = spec.clone();
+	let control = tokio::spawn(async move {
+		if let Err(e) = control::serve(positions_dir, &spec_clone, all_protocols).await {
+			warn!("Protocol updates are unavailable for the position: {e:?}");
+		}
+	});
+
 	//let acquired = PositionAcquisition::dbg_new(spec).await?;
 	let acquired = PositionAcquisition::do_acquisition(spec, acquisition_protocols, tx.clone(), exchanges_arc.clone()).await?;
 	let _followed = PositionFollowup::do_followup(acquired, followup_protocols, tx.clone(), exchanges_arc.clone()).await?;
+	control.abort();
 
 	Ok(())
 }
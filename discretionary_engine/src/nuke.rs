@@ -1,16 +1,11 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use color_eyre::eyre::{Context, Result, bail};
-use nautilus_bybit::{
-	common::enums::{BybitEnvironment, BybitPositionSide, BybitProductType},
-	http::query::BybitPositionListParamsBuilder,
-};
 use nautilus_model::identifiers::InstrumentId;
-use secrecy::ExposeSecret;
 use v_exchanges::Ticker;
 use v_utils::{log, trades::Timeframe};
 
-use crate::{bybit_common::*, config::LiveSettings};
+use crate::{bybit_common::*, config::LiveSettings, exchange_apis::order_types::PriceDistance, ws_chase_limit::format_qty};
 
 #[derive(clap::Args, Debug)]
 pub(crate) struct NukeArgs {
@@ -20,150 +15,136 @@ pub(crate) struct NukeArgs {
 	/// Optional duration over which to close the position (for MM trailing strategy)
 	#[arg(short, long)]
 	duration: Option<Timeframe>,
-}
-
-pub(crate) async fn main(args: NukeArgs, live_settings: Arc<LiveSettings>, testnet: bool) -> Result<()> {
-	log!("Nuke command for ticker: {:?}", args.ticker);
-
-	// Create Bybit HTTP client
-	let exchange_name = args.ticker.exchange_name;
-	let (_raw_client, client) = create_bybit_clients(live_settings.clone(), exchange_name.clone(), testnet)?;
 
-	// Convert symbol format (twt-usdt.p -> TWTUSDT)
-	let symbol_raw = args.ticker.symbol.to_string();
-	let symbol = convert_symbol_to_bybit(&symbol_raw); //wtf
+	/// Stop chasing once price runs this far from where it was on start, either in price units or in percents (e.g. "50" or "0.5%"). What's left unfilled is abandoned instead of market-filled.
+	#[arg(long, requires = "duration")]
+	max_range: Option<PriceDistance>,
 
-	if args.duration.is_some() {
-		log!("Duration: {:?} (chase-limit strategy)", args.duration);
-
-		// Get current position
-		let params = BybitPositionListParamsBuilder::default()
-			.category(BybitProductType::Linear)
-			.symbol(symbol.clone())
-			.build()
-			.context("Failed to build position list params")?;
-
-		let position_response = client.get_positions(&params).await.context("Failed to fetch positions")?;
-
-		if position_response.result.list.is_empty() {
-			log!("No position to close for {}", symbol);
-			return Ok(());
-		}
-
-		let position = &position_response.result.list[0];
-		let position_size: f64 = position.size.parse().context("Failed to parse position size")?;
-
-		if position_size == 0.0 {
-			log!("No position to close for {}", symbol);
-			return Ok(());
-		}
+	/// When to cancel the symbol's open orders (conditional ones included) relative to closing the position. Cancelling before prevents resting orders from filling mid-close; after, lets them keep protecting the position until it's gone.
+	#[arg(long, default_value = "before")]
+	cancel_orders: CancelOrders,
+}
 
-		log!("Current position: {:?} {} {}", position.side, position_size, symbol);
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum CancelOrders {
+	#[default]
+	Before,
+	After,
+}
 
-		// Determine order side (opposite of position side)
-		let order_side = if position.side == BybitPositionSide::Buy { "Sell" } else { "Buy" };
+pub(crate) async fn main(args: NukeArgs, live_settings: Arc<LiveSettings>, testnet: bool) -> Result<()> {
+	log!("Nuke command for ticker: {:?}", args.ticker);
 
-		// Get instrument info for qty step
-		use nautilus_bybit::http::query::BybitInstrumentsInfoParamsBuilder;
+	let exchange_name = args.ticker.exchange_name.clone();
+	let signed_client = BybitSignedClient::new(live_settings.clone(), exchange_name, testnet)?;
+	let (symbol, category) = bybit_symbol(&args.ticker)?;
 
-		let instruments_params = BybitInstrumentsInfoParamsBuilder::default()
-			.category(BybitProductType::Linear)
-			.symbol(symbol.clone())
-			.build()
-			.context("Failed to build instruments info params")?;
+	if args.cancel_orders == CancelOrders::Before {
+		cancel_all_orders(&signed_client, category, &symbol).await?;
+	}
+	flatten(&args, &signed_client).await?;
+	if args.cancel_orders == CancelOrders::After {
+		cancel_all_orders(&signed_client, category, &symbol).await?;
+	}
 
-		let instruments_response: nautilus_bybit::http::models::BybitInstrumentLinearResponse = _raw_client
-			.get_instruments::<nautilus_bybit::http::models::BybitInstrumentLinearResponse>(&instruments_params)
-			.await
-			.context("Failed to fetch instrument info")?;
+	// Make sure nothing was left over, be it from an abandoned chase or a fill of some resting order we've raced with
+	// position updates lag slightly behind fills
+	tokio::time::sleep(Duration::from_millis(500)).await;
+	let remaining = signed_client.held_qty(category, &symbol, &base_coin(&args.ticker)).await?;
+	// spot balances can keep dust below the minimum order size, which can't be sold
+	let dust = match category.has_positions() {
+		true => 0.0,
+		false => fetch_instrument_rules(testnet, category, &symbol).await?.min_order_qty,
+	};
+	if remaining.abs() > dust {
+		bail!("{} is not flat after nuke: {} still open", symbol, remaining);
+	}
+	println!("   Verified {} is flat", symbol);
+	Ok(())
+}
 
-		let instrument = instruments_response
-			.result
-			.list
-			.get(0)
-			.ok_or_else(|| color_eyre::eyre::eyre!("No instrument info found for {}", symbol))?;
+async fn cancel_all_orders(signed_client: &BybitSignedClient, category: BybitCategory, symbol: &str) -> Result<()> {
+	let cancelled = signed_client.cancel_all_orders(category, symbol).await?;
+	println!("   Cancelled {} open orders on {}", cancelled, symbol);
+	Ok(())
+}
 
-		let qty_step: f64 = instrument.lot_size_filter.qty_step.parse().context("Failed to parse qtyStep")?;
-		let tick_size: f64 = instrument.price_filter.tick_size.parse().context("Failed to parse tickSize")?;
+async fn flatten(args: &NukeArgs, signed_client: &BybitSignedClient) -> Result<()> {
+	// Convert symbol format (twt-usdt.p -> TWTUSDT, linear)
+	let (symbol, category) = bybit_symbol(&args.ticker)?;
+	let rules = fetch_instrument_rules(signed_client.testnet(), category, &symbol).await?;
+
+	// Get current position. On spot, that's all of the base coin we hold, bar what's below the minimum order
+	let held = signed_client.held_qty(category, &symbol, &base_coin(&args.ticker)).await?;
+	let position_size = match category.has_positions() {
+		true => held.abs(),
+		false => (held / rules.qty_step).floor() * rules.qty_step,
+	};
+	if position_size == 0.0 || (!category.has_positions() && position_size < rules.min_order_qty) {
+		log!("No position to close for {}", symbol);
+		return Ok(());
+	}
+	log!("Current position: {} {} ({})", held, symbol, category);
 
-		// Get API credentials for WebSocket
-		let config = live_settings.config()?;
-		let exchange_config = config.get_exchange(exchange_name)?;
-		let api_key = exchange_config.api_pubkey.clone();
-		let api_secret = exchange_config.api_secret.expose_secret().to_string();
+	// Determine order side (opposite of position side)
+	let order_side = if held > 0.0 { "Sell" } else { "Buy" };
 
-		// Determine environment
-		let environment = if testnet { BybitEnvironment::Testnet } else { BybitEnvironment::Mainnet };
+	if args.duration.is_some() {
+		log!("Duration: {:?} (chase-limit strategy)", args.duration);
 
 		// Create InstrumentId for ticker subscription
 		let instrument_id = InstrumentId::from(format!("{}.BYBIT", symbol).as_str());
 
 		// Execute using WebSocket chase-limit
-		let filled_qty = crate::ws_chase_limit::execute_ws_chase_limit(
-			&_raw_client, api_key, api_secret, environment, &symbol, instrument_id, order_side, position_size, qty_step, tick_size, args.duration,
+		let outcome = crate::ws_chase_limit::execute_ws_chase_limit(
+			signed_client,
+			category,
+			&symbol,
+			instrument_id,
+			order_side,
+			position_size,
+			rules.qty_step,
+			rules.tick_size,
+			args.duration.map(|tf| Duration::from_millis(tf.0)),
+			args.max_range,
+			None,
 		)
 		.await
 		.context("WebSocket chase-limit execution failed")?;
 
-		println!("✅ Position closed using chase-limit!");
-		println!("   Closed: {:.6} {}", filled_qty, symbol);
+		if outcome.abandoned > 0.0 {
+			println!("⚠️ Price ran past max range, chase abandoned");
+			println!("   Closed: {:.6} {}, left open: {:.6}", outcome.filled, symbol, outcome.abandoned);
+		} else {
+			println!("✅ Position closed using chase-limit!");
+			println!("   Closed: {:.6} {}", outcome.filled, symbol);
+		}
 		Ok(())
 	} else {
-		// Market close: get current position and close it using nautilus client
+		// Market close
 		log!("Closing position with market order");
 
-		// Get current position
-		let params = BybitPositionListParamsBuilder::default()
-			.category(BybitProductType::Linear)
-			.symbol(symbol.clone())
-			.build()
-			.context("Failed to build position list params")?;
-
-		let position_response = client.get_positions(&params).await.context("Failed to fetch positions")?;
-
-		if position_response.result.list.is_empty() {
-			log!("No position to close for {}", symbol);
-			return Ok(());
-		}
-
-		let position = &position_response.result.list[0];
-		let position_size: f64 = position.size.parse().context("Failed to parse position size")?;
-
-		if position_size == 0.0 {
-			log!("No position to close for {}", symbol);
-			return Ok(());
-		}
-
-		log!("Current position: {:?} {} {}", position.side, position_size, symbol);
-
-		// Determine order side (opposite of position side)
-		let order_side = if position.side == BybitPositionSide::Buy { "Sell" } else { "Buy" };
-
 		// Place reduce-only market order to close
-		let order_request = serde_json::json!({
-			"category": "linear",
+		let mut order_request = serde_json::json!({
+			"category": category.as_str(),
 			"symbol": symbol,
 			"side": order_side,
 			"orderType": "Market",
-			"qty": position_size.to_string(),
+			"qty": format_qty(position_size, rules.qty_step),
 			"timeInForce": "IOC",
 			"orderLinkId": format!("nuke-{}", uuid::Uuid::new_v4()),
-			"reduceOnly": true,
 		});
+		match category.has_positions() {
+			true => order_request["reduceOnly"] = true.into(),
+			false => order_request["marketUnit"] = category.market_unit().into(),
+		}
 
 		log!("Submitting market {} order to close {} {}", order_side, position_size, symbol);
 
-		let order_response = client.place_order(&order_request).await.context("Failed to place order")?;
-
-		if order_response.ret_code == 0 {
-			println!("✅ Position closed successfully!");
-			if let Some(order_id) = order_response.result.order_id {
-				println!("   Order ID: {}", order_id);
-			}
-			println!("   Closed: {} {}", position_size, symbol);
-			Ok(())
-		} else {
-			bail!("Order failed: {} (code: {})", order_response.ret_msg, order_response.ret_code);
-		}
+		let placed = signed_client.place_order(&order_request).await?;
+		println!("✅ Position closed successfully!");
+		println!("   Order ID: {}", placed.order_id);
+		println!("   Closed: {} {}", position_size, symbol);
+		Ok(())
 	}
 }
@@ -1,39 +1,101 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use color_eyre::eyre::Result;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Result, bail, eyre};
 use serde::{Deserialize, Serialize};
-use tokio::{select, sync::mpsc, task::JoinSet};
-use tracing::{Span, debug, field::Empty, info, instrument};
+use tokio::{
+	select,
+	sync::mpsc,
+	task::{JoinHandle, JoinSet},
+};
+use tracing::{Span, debug, error, field::Empty, info, instrument, warn};
 use uuid::Uuid;
 use v_utils::trades::Side;
 
 use crate::{
 	exchange_apis::{
-		binance,
+		MarginType, Market, Symbol, binance,
 		exchanges::Exchanges,
 		hub::PositionToHub,
+		market_data::MarketData,
 		order_types::{ConceptualOrder, ConceptualOrderPercents, ConceptualOrderType, ProtocolOrderId},
 	},
-	protocols::{Protocol, ProtocolDynamicInfo, ProtocolFills, ProtocolOrders, ProtocolType, RecalculateOrdersPerOrderInfo},
+	protocols::{PositionContext, Protocol, ProtocolDynamicInfo, ProtocolFills, ProtocolOrders, ProtocolType, RecalculateOrdersPerOrderInfo},
 };
 
+/// How long market data of the position's symbol may stay silent before we alert and fall back to polling prices over REST.
+const MARKET_DATA_DEAD_AFTER: Duration = Duration::from_secs(120);
+/// How often prices are polled while market data is dead.
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 /// What the Position *is*_
 #[derive(Clone, Debug, Default)]
 pub struct PositionSpec {
 	pub asset: String,
 	pub side: Side,
 	pub size_usdt: f64,
+	/// Where the position is to be executed. Protocols keep reading market data from Binance regardless.
+	pub market: Market,
 	pub id: Uuid,
+	/// If `None`, whatever is currently set on the exchange for the symbol is kept.
+	pub leverage: Option<u8>,
+	/// If `None`, whatever is currently set on the exchange for the symbol is kept.
+	pub margin_type: Option<MarginType>,
+	pub dead_man: Option<DeadManSwitch>,
+	/// Target period for which we expect the edge to persist.
+	pub tf: Option<Duration>,
+	pub opened_at: DateTime<Utc>,
 }
 impl PositionSpec {
-	pub fn new(asset: String, side: Side, size_usdt: f64) -> Self {
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(
+		asset: String,
+		side: Side,
+		size_usdt: f64,
+		market: Market,
+		leverage: Option<u8>,
+		margin_type: Option<MarginType>,
+		dead_man: Option<DeadManSwitch>,
+		tf: Option<Duration>,
+	) -> Self {
 		Self {
 			asset,
 			side,
 			size_usdt,
+			market,
 			id: Uuid::now_v7(),
+			leverage,
+			margin_type,
+			dead_man,
+			tf,
+			opened_at: Utc::now(),
 		}
 	}
+
+	fn protocol_context(&self, entry_price: Option<f64>, exchanges: &Exchanges) -> PositionContext {
+		let testnet = exchanges.bybit.read().unwrap().testnet;
+		PositionContext::new(entry_price, self.opened_at, self.tf, exchanges.market_data.clone(), self.symbol(), testnet)
+	}
+
+	/// Where the position's orders are executed.
+	fn symbol(&self) -> Symbol {
+		Symbol::new(self.asset.clone(), "USDT".to_owned(), self.market)
+	}
+
+	/// What the protocols read market data for.
+	fn data_symbol(&self) -> Symbol {
+		Symbol::new(self.asset.clone(), "USDT".to_owned(), Market::BinanceFutures)
+	}
+}
+
+/// Have the exchange cancel the Position's orders if the engine stops sending heartbeats for `window`, so a dead engine can't leave stale orders working.
+///
+/// What gets cancelled is up to the exchange, and is never just the Position's: all orders on the symbol on Binance, all derivatives orders of the account on Bybit.
+#[derive(Clone, Copy, Debug, Default, derive_new::new)]
+pub struct DeadManSwitch {
+	pub window: Duration,
+	/// Keep it armed during followup too. Off by default, as protective stops disappearing together with the engine is usually worse than them going stale.
+	pub include_followup: bool,
 }
 
 #[allow(dead_code)]
@@ -43,6 +105,8 @@ pub struct PositionAcquisition {
 	notional: f64,
 	protocols: Vec<Protocol>,
 	fill_key: Uuid,
+	/// Volume-weighted over the acquisition fills. `None` if none came with a price.
+	entry_price: Option<f64>,
 }
 impl PositionAcquisition {
 	// dbg
@@ -55,44 +119,62 @@ impl PositionAcquisition {
 			notional: target_coin_quantity,
 			protocols: Vec::new(),
 			fill_key: Uuid::default(),
+			entry_price: Some(current_price),
 		})
 	}
 
+	/// Treat a position that already exists on the exchange as acquired, so it can be handed over to [PositionFollowup]. Used when adopting positions opened outside of the engine.
+	pub fn adopt(spec: PositionSpec, notional: f64, entry_price: Option<f64>) -> Self {
+		Self {
+			__spec: spec,
+			notional,
+			protocols: Vec::new(),
+			fill_key: Uuid::default(),
+			entry_price,
+		}
+	}
+
 	#[instrument(skip(hub_tx, exchanges))]
 	pub async fn do_acquisition(__spec: PositionSpec, protocols: Vec<Protocol>, hub_tx: mpsc::Sender<PositionToHub>, exchanges: Arc<Exchanges>) -> Result<Self> {
 		let mut js = JoinSet::new();
-		let (mut rx_orders, mut position_protocols_dynamic_info) = init_protocols(&mut js, &protocols, &__spec.asset, __spec.side);
+		let (mut rx_orders, mut position_protocols_dynamic_info) = init_protocols(&mut js, &protocols, &__spec.asset, __spec.side, __spec.protocol_context(None, &exchanges));
 
-		// HACK
-		let current_price = binance::futures_price(&__spec.asset).await?;
+		let symbol = __spec.symbol();
+		Exchanges::load_trading_rules(exchanges.clone(), &symbol).await?;
+		let current_price = Exchanges::price(exchanges.clone(), &symbol).await?;
 		let target_coin_quantity = __spec.size_usdt / current_price;
 
-		let (tx_fills, mut rx_fills) = mpsc::channel::<ProtocolFills>(256);
-		let position_callback = HubToPosition::new(tx_fills, __spec.id);
+		let (tx_fills, mut rx_fills) = mpsc::channel::<Result<ProtocolFills>>(256);
+		let position_callback = HubToPosition::new(tx_fills, __spec.id, __spec.side, __spec.dead_man.map(|d| d.window), __spec.market);
 
 		let mut executed_notional = 0.0;
 		let mut last_fill_key = Uuid::default();
+		let mut entry_vwap = FillsVwap::default();
 
-		let min_qty_any_ordertype = Exchanges::min_qty_any_ordertype(exchanges.clone(), &__spec.asset);
+		let min_qty_any_ordertype = Exchanges::min_qty_any_ordertype(exchanges.clone(), &symbol);
+		let mut market_data_watch = MarketDataWatch::new(__spec.data_symbol());
 
 		//LOOP: Main acquisition loop, break when executed_notional is sufficient
 		loop {
 			select! {
 				Some(protocol_orders) = rx_orders.recv() => {
 					process_protocol_orders_update(protocol_orders, &mut position_protocols_dynamic_info).await?;
-					let new_target_orders = recalculate_protocol_orders(&__spec.asset, min_qty_any_ordertype, target_coin_quantity - executed_notional, __spec.side, &position_protocols_dynamic_info, exchanges.clone());
+					let new_target_orders = recalculate_protocol_orders(&symbol, min_qty_any_ordertype, target_coin_quantity, target_coin_quantity - executed_notional, __spec.side, &position_protocols_dynamic_info, exchanges.clone());
 					send_orders_to_hub(hub_tx.clone(), position_callback.clone(), last_fill_key, new_target_orders).await?;
 				},
 				Some(protocol_fills) = rx_fills.recv() => {
+					let protocol_fills = protocol_fills?;
 					last_fill_key = protocol_fills.key;
+					protocol_fills.fills.iter().for_each(|f| entry_vwap.push(f.qty, f.price));
 					process_fills_update(protocol_fills, &mut position_protocols_dynamic_info, &mut executed_notional).await?;
 					debug!(executed_notional);
 					if executed_notional > target_coin_quantity - min_qty_any_ordertype {
 						break;
 					}
-					let new_target_orders = recalculate_protocol_orders(&__spec.asset,min_qty_any_ordertype,  target_coin_quantity - executed_notional, __spec.side, &position_protocols_dynamic_info, exchanges.clone());
+					let new_target_orders = recalculate_protocol_orders(&symbol, min_qty_any_ordertype, target_coin_quantity, target_coin_quantity - executed_notional, __spec.side, &position_protocols_dynamic_info, exchanges.clone());
 					send_orders_to_hub(hub_tx.clone(), position_callback.clone(), last_fill_key, new_target_orders).await?;
 				},
+				_ = market_data_watch.tick() => market_data_watch.check(&exchanges.market_data),
 				Some(_) = js.join_next() => { unreachable!("All protocols are endless, this is here only for structured concurrency, as all tasks should be actively awaited.")},
 				else => unreachable!("hub outlives positions"),
 			}
@@ -104,6 +186,7 @@ impl PositionAcquisition {
 			notional: executed_notional,
 			protocols,
 			fill_key: last_fill_key,
+			entry_price: entry_vwap.price(),
 		})
 	}
 }
@@ -117,42 +200,62 @@ pub struct PositionFollowup {
 
 #[derive(Clone, Debug, derive_new::new)]
 pub struct HubToPosition {
-	pub sender: mpsc::Sender<ProtocolFills>,
+	/// Fills of the position's orders, or why the hub refuses to execute them.
+	pub sender: mpsc::Sender<Result<ProtocolFills>>,
 	pub position_id: Uuid,
+	/// Side of the position itself, not of the orders it requests (followup orders are on the opposite one).
+	pub position_side: Side,
+	/// Window of the dead-man switch to keep armed for the symbol while the position's orders are live, if any.
+	pub dead_man: Option<Duration>,
+	/// Market the hub is to route the position's orders to.
+	pub market: Market,
 }
 
 impl PositionFollowup {
 	#[instrument(skip(hub_tx, exchanges_arc))]
 	pub async fn do_followup(__acquisition: PositionAcquisition, protocols: Vec<Protocol>, hub_tx: mpsc::Sender<PositionToHub>, exchanges_arc: Arc<Exchanges>) -> Result<Self> {
 		let mut js = JoinSet::new();
-		let (mut rx_orders, mut position_protocols_dynamic_info) = init_protocols(&mut js, &protocols, &__acquisition.__spec.asset, !__acquisition.__spec.side);
-
-		let (tx_fills, mut rx_fills) = mpsc::channel::<ProtocolFills>(256);
-		let position_callback = HubToPosition::new(tx_fills, __acquisition.__spec.id);
+		let context = __acquisition.__spec.protocol_context(__acquisition.entry_price, &exchanges_arc);
+		let (mut rx_orders, mut position_protocols_dynamic_info) = init_protocols(&mut js, &protocols, &__acquisition.__spec.asset, !__acquisition.__spec.side, context);
+
+		let (tx_fills, mut rx_fills) = mpsc::channel::<Result<ProtocolFills>>(256);
+		let position_callback = HubToPosition::new(
+			tx_fills,
+			__acquisition.__spec.id,
+			__acquisition.__spec.side,
+			__acquisition.__spec.dead_man.filter(|d| d.include_followup).map(|d| d.window),
+			__acquisition.__spec.market,
+		);
 
 		let mut executed_notional = 0.0;
 		let mut last_fill_key = __acquisition.fill_key;
 
-		let min_qty_any_ordertype = Exchanges::min_qty_any_ordertype(exchanges_arc.clone(), &__acquisition.__spec.asset);
+		// adopted positions skip acquisition
+		let symbol = __acquisition.__spec.symbol();
+		Exchanges::load_trading_rules(exchanges_arc.clone(), &symbol).await?;
+		let min_qty_any_ordertype = Exchanges::min_qty_any_ordertype(exchanges_arc.clone(), &symbol);
+		let mut market_data_watch = MarketDataWatch::new(__acquisition.__spec.data_symbol());
 
 		//LOOP: Main followup loop, break when executed_notional is sufficient
 		loop {
 			select! {
 				Some(protocol_orders) = rx_orders.recv() => {
 					process_protocol_orders_update(protocol_orders, &mut position_protocols_dynamic_info).await?;
-					let new_target_orders = recalculate_protocol_orders(&__acquisition.__spec.asset, min_qty_any_ordertype, __acquisition.notional - executed_notional, __acquisition.__spec.side, &position_protocols_dynamic_info, exchanges_arc.clone());
+					let new_target_orders = recalculate_protocol_orders(&symbol, min_qty_any_ordertype, __acquisition.notional, __acquisition.notional - executed_notional, __acquisition.__spec.side, &position_protocols_dynamic_info, exchanges_arc.clone());
 					send_orders_to_hub(hub_tx.clone(), position_callback.clone(), last_fill_key, new_target_orders).await?;
 				},
 				Some(protocol_fills) = rx_fills.recv() => {
+					let protocol_fills = protocol_fills?;
 					last_fill_key = protocol_fills.key;
 					process_fills_update(protocol_fills, &mut position_protocols_dynamic_info, &mut executed_notional).await?;
 					debug!(executed_notional);
 					if executed_notional > __acquisition.notional - min_qty_any_ordertype {
 						break;
 					}
-					let new_target_orders = recalculate_protocol_orders(&__acquisition.__spec.asset, min_qty_any_ordertype, __acquisition.notional - executed_notional, __acquisition.__spec.side, &position_protocols_dynamic_info, exchanges_arc.clone());
+					let new_target_orders = recalculate_protocol_orders(&symbol, min_qty_any_ordertype, __acquisition.notional, __acquisition.notional - executed_notional, __acquisition.__spec.side, &position_protocols_dynamic_info, exchanges_arc.clone());
 					send_orders_to_hub(hub_tx.clone(), position_callback.clone(), last_fill_key, new_target_orders).await?;
 				},
+				_ = market_data_watch.tick() => market_data_watch.check(&exchanges_arc.market_data),
 				Some(_) = js.join_next() => { unreachable!("All protocols are endless, this is here only for structured concurrency, as all tasks should be actively awaited.")},
 				else => unreachable!("hub outlives positions"),
 			}
@@ -168,20 +271,29 @@ impl PositionFollowup {
 }
 
 #[instrument(skip(parent_js))]
-fn init_protocols(parent_js: &mut JoinSet<Result<()>>, protocols: &[Protocol], asset: &str, protocols_side: Side) -> (mpsc::Receiver<ProtocolOrders>, PositionProtocolsDynamicInfo) {
+fn init_protocols(
+	parent_js: &mut JoinSet<Result<()>>,
+	protocols: &[Protocol],
+	asset: &str,
+	protocols_side: Side,
+	context: PositionContext,
+) -> (mpsc::Receiver<ProtocolOrders>, PositionProtocolsDynamicInfo) {
 	let (tx_orders, rx_orders) = mpsc::channel::<ProtocolOrders>(256);
 	for protocol in protocols {
-		protocol.attach(parent_js, tx_orders.clone(), asset.to_owned(), protocols_side).unwrap();
+		protocol.attach(parent_js, tx_orders.clone(), asset.to_owned(), protocols_side, context.clone()).unwrap();
 	}
 
 	let mut protocol_type_mapped_order: HashMap<ProtocolType, HashMap<String, Option<ProtocolDynamicInfo>>> = HashMap::new();
+	let mut weights = HashMap::new();
 	for protocol in protocols {
-		let subtype = protocol.get_type();
-		let map_entry = protocol_type_mapped_order.entry(subtype).or_default();
-		map_entry.insert(protocol.signature(), None);
+		for (subtype, id) in protocol.sizing_legs() {
+			let map_entry = protocol_type_mapped_order.entry(subtype).or_default();
+			weights.insert(id.clone(), protocol.weight());
+			map_entry.insert(id, None);
+		}
 	}
 
-	(rx_orders, PositionProtocolsDynamicInfo(protocol_type_mapped_order))
+	(rx_orders, PositionProtocolsDynamicInfo::new(protocol_type_mapped_order, weights))
 }
 
 #[instrument(skip(hub_tx))]
@@ -226,23 +338,114 @@ async fn process_fills_update(protocol_fills: ProtocolFills, dyn_info: &mut Posi
 	Ok(())
 }
 
-#[derive(Clone, Debug, Default)]
-struct PositionProtocolsDynamicInfo(pub HashMap<ProtocolType, HashMap<String, Option<ProtocolDynamicInfo>>>);
+/// Watches market data the position's protocols rely on. Once it's been dead for [MARKET_DATA_DEAD_AFTER], alerts and polls prices over REST into the trades feed until it's back.
+///
+/// Only trades are substituted: protocols reading klines or the book stay blind meanwhile, their last orders working on the exchange unadjusted.
+#[derive(Debug)]
+struct MarketDataWatch {
+	symbol: Symbol,
+	interval: tokio::time::Interval,
+	/// Polling task, while market data is dead.
+	fallback: Option<JoinHandle<()>>,
+}
+impl MarketDataWatch {
+	fn new(symbol: Symbol) -> Self {
+		Self {
+			symbol,
+			interval: tokio::time::interval(Duration::from_secs(5)),
+			fallback: None,
+		}
+	}
+
+	async fn tick(&mut self) {
+		self.interval.tick().await;
+	}
+
+	fn check(&mut self, market_data: &MarketData) {
+		let dead = market_data.silent_for(&self.symbol).is_some_and(|silence| silence > MARKET_DATA_DEAD_AFTER);
+		self.update(dead, market_data);
+	}
+
+	fn update(&mut self, dead: bool, market_data: &MarketData) {
+		match (self.fallback.take(), dead) {
+			(None, true) => {
+				error!(
+					"Market data for {} has been dead for over {MARKET_DATA_DEAD_AFTER:?}, falling back to polling its price",
+					self.symbol
+				);
+				self.fallback = Some(tokio::spawn(poll_prices(self.symbol.clone(), market_data.clone())));
+			}
+			(Some(fallback), false) => {
+				info!("Market data for {} is back", self.symbol);
+				fallback.abort();
+			}
+			(fallback, _) => self.fallback = fallback,
+		}
+	}
+}
+impl Drop for MarketDataWatch {
+	fn drop(&mut self) {
+		if let Some(fallback) = self.fallback.take() {
+			fallback.abort();
+		}
+	}
+}
+
+async fn poll_prices(symbol: Symbol, market_data: MarketData) {
+	let mut interval = tokio::time::interval(FALLBACK_POLL_INTERVAL);
+	loop {
+		interval.tick().await;
+		match binance::futures_price(&symbol.base).await {
+			Ok(price) => market_data.push_trade(&symbol, price),
+			Err(e) => warn!("Failed to poll the price of {symbol}: {e}"),
+		}
+	}
+}
+
+/// Volume-weighted price of the fills that came with one.
+#[derive(Clone, Copy, Debug, Default)]
+struct FillsVwap {
+	qty: f64,
+	value: f64,
+}
+impl FillsVwap {
+	fn push(&mut self, qty: f64, price: Option<f64>) {
+		if let Some(price) = price {
+			self.qty += qty;
+			self.value += qty * price;
+		}
+	}
+
+	fn price(&self) -> Option<f64> {
+		(self.qty > 0.0).then(|| self.value / self.qty)
+	}
+}
+
+#[derive(Clone, Debug, Default, derive_new::new)]
+struct PositionProtocolsDynamicInfo {
+	infos: HashMap<ProtocolType, HashMap<String, Option<ProtocolDynamicInfo>>>,
+	/// Of each protocol, by the id it sends orders under.
+	weights: HashMap<String, f64>,
+}
 impl PositionProtocolsDynamicInfo {
 	pub fn iter(&self) -> impl Iterator<Item = (&ProtocolType, &HashMap<String, Option<ProtocolDynamicInfo>>)> {
-		self.0.iter()
+		self.infos.iter()
+	}
+
+	pub fn weight(&self, protocol_id: &str) -> f64 {
+		self.weights.get(protocol_id).copied().unwrap_or(1.0)
 	}
 }
 impl std::ops::Deref for PositionProtocolsDynamicInfo {
 	type Target = HashMap<ProtocolType, HashMap<String, Option<ProtocolDynamicInfo>>>;
 
 	fn deref(&self) -> &Self::Target {
-		&self.0
+		&self.infos
 	}
 }
 impl std::ops::DerefMut for PositionProtocolsDynamicInfo {
 	fn deref_mut(&mut self) -> &mut Self::Target {
-		&mut self.0
+		&mut self.infos
 	}
 }
 
@@ -251,8 +454,9 @@ impl std::ops::DerefMut for PositionProtocolsDynamicInfo {
 /// If `Position` has [Protocol]s of different subtypes, we don't care to have them mix, - from the orders produced here (in full size for each `Protocol` subtype) position will choose the closest ones, ignoring the rest.
 #[instrument(skip(exchanges_arc))]
 fn recalculate_protocol_orders(
-	parent_position_asset: &str,
+	symbol: &Symbol,
 	min_qty_any_ordertype: f64,
+	target_notional: f64,
 	left_to_target_notional: f64,
 	side: Side,
 	dyn_info: &PositionProtocolsDynamicInfo,
@@ -262,61 +466,23 @@ fn recalculate_protocol_orders(
 	let mut stop_orders = Vec::new();
 	let mut limit_orders = Vec::new();
 
-	//PERF: (n^(n/2)), but it's fine, as n is small.
-	for (_protocol_type, protocols_map) in dyn_info.iter() {
-		let mut in_play_protocols_map = protocols_map.clone();
-		let accumulated_leftovers = 0.0;
-
-		for (i, (signature, info)) in in_play_protocols_map.clone().iter().enumerate() {
-			// clone to avoid using unsafe on `remove`
-			let info = match info.as_ref() {
-				Some(info) => info,
-				None => continue, // Protocol is _yet to_ send orders. We assume it's always intentional.
-			};
-			let orders = &info.protocol_orders.__orders;
-			let size_multiplier = 1.0 / protocols_map.len() as f64; //NB: not in_play_protocols_map.len()
-			let protocol_controlled_notional = (left_to_target_notional + accumulated_leftovers) * size_multiplier;
-
-			let qties_payload: Vec<ConceptualOrderPercents> = orders.iter().flatten().cloned().collect();
-			let asset_min_trade_qties = Exchanges::compile_min_trade_qties(exchanges_arc.clone(), parent_position_asset, &qties_payload);
-
-			let per_order_infos: Vec<RecalculateOrdersPerOrderInfo> = info
-				.fills
-				.iter()
-				.enumerate()
-				.map(|(i, filled)| {
-					let min_possible_qty = asset_min_trade_qties[i];
-					RecalculateOrdersPerOrderInfo::new(*filled, min_possible_qty)
-				})
-				.collect();
-
-			let recalculated_allocation = info
-				.protocol_orders
-				.recalculate_protocol_orders_allocation(&per_order_infos, protocol_controlled_notional, min_qty_any_ordertype);
-
-			match recalculated_allocation.leftovers {
-				Some(offset) => {
-					match i {
-						x if x == in_play_protocols_map.len() - 1 => {
-							debug!("Discarding leftovers for {:?}", _protocol_type);
-						}
-						_ => {
-							// Note: we break immediately so no need to update accumulated_leftovers
-							in_play_protocols_map.remove(signature);
-							break;
-						}
-					}
-				}
-				None => {
-					recalculated_allocation.orders.into_iter().for_each(|o| match o.order_type {
-						ConceptualOrderType::StopMarket(_) => stop_orders.push(o),
-						ConceptualOrderType::Limit(_) => limit_orders.push(o),
-						ConceptualOrderType::Market(_) => market_orders.push(o),
-					});
-				}
-			}
-		}
-	}
+	let min_trade_qties = |protocol_orders: &ProtocolOrders| {
+		let qties_payload: Vec<ConceptualOrderPercents> = protocol_orders.__orders.iter().flatten().cloned().collect();
+		let mut payload_min_qties = Exchanges::compile_min_trade_qties(exchanges_arc.clone(), symbol, &qties_payload).into_iter();
+		// `None`s are never placed, so whatever is fine for them
+		protocol_orders
+			.__orders
+			.iter()
+			.map(|o| o.as_ref().and_then(|_| payload_min_qties.next()).unwrap_or(0.0))
+			.collect()
+	};
+	allocate_protocols(dyn_info, target_notional, min_qty_any_ordertype, min_trade_qties)
+		.into_iter()
+		.for_each(|o| match o.order_type {
+			ConceptualOrderType::StopMarket(_) => stop_orders.push(o),
+			ConceptualOrderType::Limit(_) => limit_orders.push(o),
+			ConceptualOrderType::Market(_) | ConceptualOrderType::Chase(_) => market_orders.push(o),
+		});
 
 	/// NB: Market-like orders MUST be ran first
 	fn update_order_selection(extendable: &mut Vec<ConceptualOrder<ProtocolOrderId>>, incoming: &[ConceptualOrder<ProtocolOrderId>], left_to_target: &mut f64) {
@@ -354,6 +520,91 @@ fn recalculate_protocol_orders(
 	new_target_orders
 }
 
+/// Orders of all protocols still in play, each sized to the share of `target_notional` it controls.
+///
+/// Protocols are done once what's left of their share is below `min_qty_any_ordertype`. Whatever they leave over is redistributed amongst the protocols of the same type still in play, proportionally to their weights. Leftovers of an overfilled protocol are negative, so they reduce the shares of the others the same way.
+///
+/// Types with none left in play pass their leftovers on to all other types, but as each type already controls the whole of `target_notional`, they are capped at it: only overfills carry over.
+fn allocate_protocols(
+	dyn_info: &PositionProtocolsDynamicInfo,
+	target_notional: f64,
+	min_qty_any_ordertype: f64,
+	min_trade_qties: impl Fn(&ProtocolOrders) -> Vec<f64>,
+) -> Vec<ConceptualOrder<ProtocolOrderId>> {
+	// Leftovers of the protocols found to be done, not including what was redistributed to them.
+	let mut done: HashMap<&str, f64> = HashMap::new();
+	let mut cross_type_leftovers = 0.0;
+
+	//LOOP: every pass either finds more protocols done, or settles on the cross-type leftovers, so at most `2n + 1` passes
+	//PERF: n is small
+	loop {
+		let mut orders = Vec::new();
+		let mut newly_done = Vec::new();
+		let mut orphaned_leftovers = 0.0;
+		let cross_type_weight: f64 = dyn_info
+			.values()
+			.flat_map(|m| m.keys())
+			.filter(|id| !done.contains_key(id.as_str()))
+			.map(|id| dyn_info.weight(id))
+			.sum();
+
+		// no type is to control more than the whole target
+		let cross_type_share = cross_type_leftovers.min(0.0);
+		for (protocol_type, protocols_map) in dyn_info.iter() {
+			let total_weight: f64 = protocols_map.keys().map(|id| dyn_info.weight(id)).sum();
+			let type_leftovers: f64 = protocols_map.keys().filter_map(|id| done.get(id.as_str())).sum();
+			let in_play: Vec<(&String, &Option<ProtocolDynamicInfo>)> = protocols_map.iter().filter(|(id, _)| !done.contains_key(id.as_str())).collect();
+			let in_play_weight: f64 = in_play.iter().map(|(id, _)| dyn_info.weight(id)).sum();
+			if in_play.is_empty() {
+				orphaned_leftovers += type_leftovers;
+				continue;
+			}
+
+			for (id, info) in in_play {
+				let info = match info.as_ref() {
+					Some(info) => info,
+					None => continue, // Protocol is _yet to_ send orders. We assume it's always intentional, so it keeps its share.
+				};
+				let weight = dyn_info.weight(id);
+				let redistributed = type_leftovers * weight / in_play_weight + cross_type_share * weight / cross_type_weight;
+				let protocol_controlled_notional = target_notional * weight / total_weight + redistributed;
+
+				let per_order_infos: Vec<RecalculateOrdersPerOrderInfo> = info
+					.fills
+					.iter()
+					.zip(min_trade_qties(&info.protocol_orders))
+					.map(|(filled, min_possible_qty)| RecalculateOrdersPerOrderInfo::new(*filled, min_possible_qty))
+					.collect();
+				let recalculated_allocation = info
+					.protocol_orders
+					.recalculate_protocol_orders_allocation(&per_order_infos, protocol_controlled_notional, min_qty_any_ordertype);
+
+				match recalculated_allocation.leftovers {
+					Some(leftovers) => {
+						debug!("{id} ({protocol_type:?}) is done, leaving over {leftovers}");
+						newly_done.push((id.as_str(), leftovers - redistributed));
+					}
+					None => orders.extend(recalculated_allocation.orders),
+				}
+			}
+		}
+
+		if !newly_done.is_empty() {
+			done.extend(newly_done);
+			continue;
+		}
+		if orphaned_leftovers != cross_type_leftovers {
+			if cross_type_weight == 0.0 {
+				debug!("No protocols left in play, discarding leftovers of {orphaned_leftovers}");
+				return orders;
+			}
+			cross_type_leftovers = orphaned_leftovers;
+			continue;
+		}
+		return orders;
+	}
+}
+
 #[instrument(skip(protocol_orders_update))]
 async fn process_protocol_orders_update(protocol_orders_update: ProtocolOrders, dyn_info: &mut PositionProtocolsDynamicInfo) -> Result<()> {
 	debug!(
@@ -384,7 +635,246 @@ impl PositionOrderId {
 	}
 }
 
+/// [PositionOrderId], encoded to fit into exchanges' client order id fields (36 chars on both Binance and Bybit), so that orders can be attributed after a restart.
+///
+/// Fixed-width layout, all base62: `de` prefix (2) + position_id (22) + hash of protocol_id (5) + ordinal (2) = 31 chars, leaving 5 for [ClientOrderId::with_nonce].
+/// Protocol signatures are arbitrarily long, so only their hash is carried; attribute to a known protocol with [ClientOrderId::matches].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ClientOrderId {
+	pub position_id: Uuid,
+	pub protocol_hash: u32,
+	pub ordinal: usize,
+}
+impl ClientOrderId {
+	const HASH_LEN: usize = 5;
+	pub const LEN: usize = 31;
+	const NONCE_LEN: usize = 5;
+	const ORDINAL_LEN: usize = 2;
+	pub const PREFIX: &str = "de";
+
+	pub fn matches(&self, id: &PositionOrderId) -> bool {
+		Self::try_from(id).is_ok_and(|encoded| encoded == *self)
+	}
+
+	/// For exchanges that want client order ids unique across the entire order history. Only the nonce's last [Self::NONCE_LEN] base62 digits are kept (~10 days of millis), which together with the rest of the id is plenty; comes out at exactly 36 chars, and still parses back into [ClientOrderId].
+	pub fn with_nonce(&self, nonce: u64) -> String {
+		format!("{self}{}", to_base62(nonce as u128 % 62_u128.pow(Self::NONCE_LEN as u32), Self::NONCE_LEN))
+	}
+}
+impl TryFrom<&PositionOrderId> for ClientOrderId {
+	type Error = eyre::Report;
+
+	fn try_from(id: &PositionOrderId) -> Result<Self> {
+		if id.ordinal >= 62_usize.pow(Self::ORDINAL_LEN as u32) {
+			bail!("Ordinal {} of {} doesn't fit into a client order id", id.ordinal, id.protocol_id);
+		}
+		Ok(Self {
+			position_id: id.position_id,
+			protocol_hash: fnv1a(id.protocol_id.as_bytes()) % 62_u32.pow(Self::HASH_LEN as u32),
+			ordinal: id.ordinal,
+		})
+	}
+}
+impl std::fmt::Display for ClientOrderId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}{}{}{}",
+			Self::PREFIX,
+			to_base62(self.position_id.as_u128(), 22),
+			to_base62(self.protocol_hash as u128, Self::HASH_LEN),
+			to_base62(self.ordinal as u128, Self::ORDINAL_LEN)
+		)
+	}
+}
+impl std::str::FromStr for ClientOrderId {
+	type Err = eyre::Report;
+
+	/// Anything past [ClientOrderId::LEN] is ignored, as exchanges requiring unique ids get a nonce appended.
+	fn from_str(s: &str) -> Result<Self> {
+		let Some(body) = s.strip_prefix(Self::PREFIX) else {
+			bail!("Not an engine client order id: {s}");
+		};
+		if s.len() < Self::LEN || !s.is_char_boundary(Self::LEN) {
+			bail!("Client order id too short: {s}");
+		}
+		let (position_id, rest) = body.split_at(22);
+		let (protocol_hash, rest) = rest.split_at(Self::HASH_LEN);
+		let ordinal = &rest[..Self::ORDINAL_LEN];
+		Ok(Self {
+			position_id: Uuid::from_u128(from_base62(position_id)?),
+			protocol_hash: u32::try_from(from_base62(protocol_hash)?)?,
+			ordinal: from_base62(ordinal)? as usize,
+		})
+	}
+}
+
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn to_base62(mut n: u128, width: usize) -> String {
+	let mut chars = vec![b'0'; width];
+	for c in chars.iter_mut().rev() {
+		*c = BASE62[(n % 62) as usize];
+		n /= 62;
+	}
+	debug_assert_eq!(n, 0, "value doesn't fit into {width} base62 chars");
+	String::from_utf8(chars).unwrap()
+}
+
+fn from_base62(s: &str) -> Result<u128> {
+	s.bytes().try_fold(0_u128, |acc, b| {
+		let Some(digit) = BASE62.iter().position(|c| *c == b) else {
+			bail!("Invalid base62 char: {}", b as char);
+		};
+		acc.checked_mul(62).and_then(|acc| acc.checked_add(digit as u128)).ok_or_else(|| eyre!("base62 overflow: {s}"))
+	})
+}
+
+/// FNV-1a; need it to be stable across runs and versions, which std's hasher doesn't guarantee.
+fn fnv1a(bytes: &[u8]) -> u32 {
+	bytes.iter().fold(0x811c9dc5_u32, |hash, b| (hash ^ *b as u32).wrapping_mul(0x01000193))
+}
+
 // pub struct PositionClosed {
 // 	_followup: PositionFollowup,
 // 	t_closed: DateTime<Utc>,
 //}
+
+#[cfg(test)]
+mod tests {
+	use v_utils::Percent;
+
+	use super::*;
+	use crate::exchange_apis::order_types::ConceptualMarket;
+
+	/// Protocol sending a single market order for all it controls, having filled `filled` of it.
+	fn market_protocol(id: &str, filled: f64) -> Option<ProtocolDynamicInfo> {
+		let order = ConceptualOrderPercents::new(
+			ConceptualOrderType::Market(ConceptualMarket::new(Percent(1.0))),
+			Symbol::new("BTC", "USDT", Market::BinanceFutures),
+			Side::Buy,
+			Percent::new(1.0),
+		);
+		let mut info = ProtocolDynamicInfo::new(ProtocolOrders::new(id.to_owned(), vec![Some(order)]));
+		info.update_fill_at(0, filled);
+		Some(info)
+	}
+
+	fn allocated(dyn_info: &PositionProtocolsDynamicInfo) -> Vec<(String, f64)> {
+		let mut orders: Vec<(String, f64)> = allocate_protocols(dyn_info, 10.0, 0.1, |o| vec![0.0; o.__orders.len()])
+			.into_iter()
+			.map(|o| (o.id.protocol_signature, (o.qty_notional * 1e9).round() / 1e9))
+			.collect();
+		orders.sort_by(|a, b| a.0.cmp(&b.0));
+		orders
+	}
+
+	#[tokio::test]
+	async fn market_data_fallback() {
+		let market_data = MarketData::default();
+		let mut watch = MarketDataWatch::new(Symbol::new("BTC", "USDT", Market::BinanceFutures));
+
+		watch.update(true, &market_data);
+		let fallback = watch.fallback.as_ref().expect("dead market data is polled for").abort_handle();
+		watch.update(true, &market_data);
+		assert!(!fallback.is_finished());
+
+		watch.update(false, &market_data);
+		assert!(watch.fallback.is_none());
+		tokio::task::yield_now().await;
+		assert!(fallback.is_finished());
+	}
+
+	#[test]
+	fn weighted_shares() {
+		let infos = HashMap::from([(
+			ProtocolType::Momentum,
+			HashMap::from([("a".to_owned(), market_protocol("a", 0.0)), ("b".to_owned(), market_protocol("b", 1.0))]),
+		)]);
+		let weights = HashMap::from([("a".to_owned(), 3.0)]);
+		assert_eq!(allocated(&PositionProtocolsDynamicInfo::new(infos, weights)), vec![("a".to_owned(), 7.5), ("b".to_owned(), 1.5)]);
+	}
+
+	#[test]
+	fn leftovers_redistribution() {
+		let momentum = |a_filled: f64| {
+			HashMap::from([
+				("a".to_owned(), market_protocol("a", a_filled)),
+				("b".to_owned(), market_protocol("b", 0.0)),
+				("c".to_owned(), market_protocol("c", 0.0)),
+			])
+		};
+
+		// `a` is under min qty, so what it hasn't filled is split between the rest
+		let underfilled = PositionProtocolsDynamicInfo::new(HashMap::from([(ProtocolType::Momentum, momentum(3.3))]), HashMap::new());
+		assert_eq!(allocated(&underfilled), vec![("b".to_owned(), 3.35), ("c".to_owned(), 3.35)]);
+
+		// `a` overdid it, so the rest are to do less
+		let overfilled = PositionProtocolsDynamicInfo::new(HashMap::from([(ProtocolType::Momentum, momentum(5.0))]), HashMap::new());
+		assert_eq!(allocated(&overfilled), vec![("b".to_owned(), 2.5), ("c".to_owned(), 2.5)]);
+
+		// overfill large enough to eat all of `b`'s share makes it done too, with `c` absorbing the rest
+		let weights = HashMap::from([("b".to_owned(), 0.5), ("c".to_owned(), 1.5)]);
+		let cascading = PositionProtocolsDynamicInfo::new(HashMap::from([(ProtocolType::Momentum, momentum(9.8))]), weights);
+		assert_eq!(allocated(&cascading), vec![("c".to_owned(), 0.2)]);
+
+		// no protocols of the type left in play, so leftovers go to the other types, which can't be taken past the target
+		let cross_type = |tp_filled: f64| {
+			let infos = HashMap::from([
+				(ProtocolType::TP, HashMap::from([("tp".to_owned(), market_protocol("tp", tp_filled))])),
+				(ProtocolType::SL, HashMap::from([("sl".to_owned(), market_protocol("sl", 0.0))])),
+			]);
+			PositionProtocolsDynamicInfo::new(infos, HashMap::new())
+		};
+		assert_eq!(allocated(&cross_type(9.95)), vec![("sl".to_owned(), 10.0)]);
+		assert_eq!(allocated(&cross_type(10.5)), vec![("sl".to_owned(), 9.5)]);
+	}
+
+	#[test]
+	fn entry_vwap_over_partial_fills() {
+		use crate::exchange_apis::binance::{BinanceOrder, FuturesPositionResponse};
+
+		let mut order = BinanceOrder::default();
+		let poll = |executed_qty: f64, cum_quote: f64| FuturesPositionResponse {
+			executed_qty,
+			cum_quote: cum_quote.to_string(),
+			..Default::default()
+		};
+		let mut vwap = FillsVwap::default();
+		// 1 @ 100, then 2 more @ 110: exchange reports the totals
+		for r in [poll(1.0, 100.0), poll(1.0, 100.0), poll(3.0, 320.0)] {
+			if let Some((qty, price)) = order.record_fills(&r) {
+				vwap.push(qty, price);
+			}
+		}
+		assert_eq!(vwap.qty, 3.0);
+		assert_eq!(vwap.price(), Some(320.0 / 3.0));
+	}
+
+	#[test]
+	fn client_order_id() {
+		let id = PositionOrderId::new(Uuid::parse_str("058a3b5d-7ce0-465c-9339-b43261e99b19").unwrap(), "ts:p0.02".to_string(), 1);
+		let encoded = ClientOrderId::try_from(&id).unwrap().to_string();
+		insta::assert_snapshot!(encoded, @"de0AS8IHKyFgXPRLDPdapPXlDHCRa01");
+		assert_eq!(encoded.len(), ClientOrderId::LEN);
+
+		let decoded: ClientOrderId = encoded.parse().unwrap();
+		assert!(decoded.matches(&id));
+		assert!(!decoded.matches(&PositionOrderId { ordinal: 0, ..id.clone() }));
+		assert!(!decoded.matches(&PositionOrderId {
+			protocol_id: "ts:p0.03".to_string(),
+			..id.clone()
+		}));
+
+		let with_nonce: ClientOrderId = format!("{encoded}-1729").parse().unwrap();
+		assert_eq!(with_nonce, decoded);
+		assert!("web_Kj3b9aL1".parse::<ClientOrderId>().is_err());
+
+		// Bybit's `orderLinkId` limit
+		let nonced = decoded.with_nonce(u64::MAX);
+		assert!(nonced.len() <= 36, "{nonced} is {} chars", nonced.len());
+		assert_eq!(nonced.parse::<ClientOrderId>().unwrap(), decoded);
+
+		assert!(ClientOrderId::try_from(&PositionOrderId { ordinal: 3844, ..id }).is_err());
+	}
+}
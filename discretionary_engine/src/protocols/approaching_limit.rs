@@ -1,18 +1,17 @@
 use chrono::{DateTime, Utc};
 use color_eyre::eyre::Result;
 use discretionary_engine_macros::ProtocolWrapper;
-use futures_util::StreamExt;
-use serde_json::Value;
-use tokio::{sync::mpsc, task::JoinSet};
-use tokio_tungstenite::connect_async;
-use v_utils::{macros::CompactFormat, trades::Side};
+use tokio::{select, sync::mpsc, task::JoinSet};
+use v_utils::{Percent, macros::CompactFormat, trades::Side};
 
 use crate::{
 	exchange_apis::{Market, Symbol, order_types::*},
-	protocols::{ProtocolOrders, ProtocolTrait, ProtocolType},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
 };
 
 /// Assumes laplace distribution, maximizes expected realized price difference by gradually moving limit order towards current price.
+///
+/// With price changes over the remaining time being Laplace with scale `b`, a limit `d` away fills with probability `exp(-d/b)/2`, so the expected improvement `d·exp(-d/b)/2` peaks at `d = b = σ·sqrt(remaining)/√2`. By the deadline the limit is at the price.
 #[derive(Clone, CompactFormat, Copy, Debug, Default, ProtocolWrapper, derive_new::new)]
 pub struct ApproachingLimit {
 	deadline: DateTime<Utc>,
@@ -21,56 +20,32 @@ pub struct ApproachingLimit {
 impl ProtocolTrait for ApproachingLimitWrapper {
 	type Params = ApproachingLimit;
 
-	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side) -> Result<()> {
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
 		let symbol = Symbol {
 			base: asset,
 			quote: "USDT".to_owned(),
 			market: Market::BinanceFutures,
 		};
-		let address = format!("wss://fstream.binance.com/ws/{}@aggTrade", symbol.to_string().to_lowercase());
-
-		let params = self.0.clone();
-		let (tx, mut rx) = tokio::sync::mpsc::channel::<f64>(256);
+		let (params, updated, protocol_id) = (self.0.clone(), self.1.clone(), self.id());
+		let mut trades = position.market_data.trades(&symbol);
 		position_js.spawn(async move {
-			let mut js = JoinSet::new();
-			js.spawn(async move {
-				let (ws_stream, _) = connect_async(address).await.unwrap();
-				let (_, mut read) = ws_stream.split();
-
-				while let Some(msg) = read.next().await {
-					let data = msg.unwrap().into_data();
-					match serde_json::from_slice::<Value>(&data) {
-						Ok(json) =>
-							if let Some(price_str) = json.get("p") {
-								let price: f64 = price_str.as_str().unwrap().parse().unwrap();
-								tx.send(price).await.unwrap();
-							},
-						Err(e) => {
-							println!("Failed to parse message as JSON: {}", e);
-						}
-					}
-				}
-			});
-
-			js.spawn(async move {
-				let mut al_indicator = ApproachingLimitIndicator::new();
-				while let Some(price) = rx.recv().await {
-					let maybe_order = al_indicator.step(price, params.read().unwrap().deadline, protocol_side, &symbol);
-					if let Some(order) = maybe_order {
-						let protocol_spec = params.read().unwrap().to_string();
-						let protocol_orders = ProtocolOrders::new(protocol_spec, vec![Some(order)]);
-						tx_orders.send(protocol_orders).await.unwrap();
-					}
+			let mut al_indicator = ApproachingLimitIndicator::new();
+			loop {
+				let maybe_order = select! {
+					Some(price) = trades.recv() => al_indicator.step(price, Utc::now(), params.read().unwrap().deadline, protocol_side, &symbol),
+					_ = updated.notified() => al_indicator.requote(Utc::now(), params.read().unwrap().deadline, protocol_side, &symbol),
+				};
+				if let Some(order) = maybe_order {
+					let protocol_orders = ProtocolOrders::new(protocol_id.clone(), vec![Some(order)]);
+					tx_orders.send(protocol_orders).await?;
 				}
-			});
-			js.join_all().await;
-			Ok(())
+			}
 		});
 		Ok(())
 	}
 
 	fn update_params(&self, new_params: ApproachingLimit) -> Result<()> {
-		*self.0.write().unwrap() = new_params;
+		self.set_params(new_params);
 		Ok(())
 	}
 
@@ -79,62 +54,210 @@ impl ProtocolTrait for ApproachingLimitWrapper {
 	}
 }
 
+/// Weight of the newest observation in the EWMA of variance.
+const VARIANCE_EWMA_ALPHA: f64 = 0.05;
+/// Fraction of the current optimal distance the limit has to move by to be re-sent.
+const REPLACE_THRESHOLD: f64 = 0.1;
+/// Floor on the re-send threshold, relative to price, so that we don't spam replacements once the distance is ~0.
+const MIN_REPLACE_THRESHOLD: f64 = 0.0001;
+
 #[derive(Clone, Copy, Debug, Default)]
 struct ApproachingLimitIndicator {
-	top: f64,
-	bottom: f64,
+	last_observation: Option<(DateTime<Utc>, f64)>,
+	/// EWMA of squared log-returns, per second.
+	variance: Option<f64>,
+	last_limit: Option<f64>,
 }
 impl ApproachingLimitIndicator {
 	fn new() -> Self {
-		Self { top: 0.0, bottom: 0.0 }
+		Self::default()
+	}
+
+	fn observe(&mut self, price: f64, now: DateTime<Utc>) {
+		let Some((last_t, last_price)) = self.last_observation else {
+			self.last_observation = Some((now, price));
+			return;
+		};
+		let dt = (now - last_t).num_milliseconds() as f64 / 1000.0;
+		// trades landing on the same millisecond are folded into the next observation
+		if dt <= 0.0 {
+			return;
+		}
+		let r = (price / last_price).ln();
+		let sample = r * r / dt;
+		self.variance = Some(match self.variance {
+			Some(v) => VARIANCE_EWMA_ALPHA * sample + (1.0 - VARIANCE_EWMA_ALPHA) * v,
+			None => sample,
+		});
+		self.last_observation = Some((now, price));
 	}
 
-	fn step(&mut self, price: f64, deadline: DateTime<Utc>, side: Side, symbol: &Symbol) -> Option<ConceptualOrderPercents> {
-		unimplemented!();
+	/// Distance from the price at which the limit maximizes expected improvement, in units of price.
+	fn optimal_distance(&self, price: f64, now: DateTime<Utc>, deadline: DateTime<Utc>) -> Option<f64> {
+		let variance = self.variance?;
+		let remaining_s = ((deadline - now).num_milliseconds() as f64 / 1000.0).max(0.0);
+		Some(price * (variance * remaining_s).sqrt() / std::f64::consts::SQRT_2)
+	}
+
+	fn step(&mut self, price: f64, now: DateTime<Utc>, deadline: DateTime<Utc>, side: Side, symbol: &Symbol) -> Option<ConceptualOrderPercents> {
+		self.observe(price, now);
+		self.quote(price, now, deadline, side, symbol)
+	}
+
+	/// Re-sends the limit for the last seen price no matter how little it moved, as after the deadline was changed.
+	fn requote(&mut self, now: DateTime<Utc>, deadline: DateTime<Utc>, side: Side, symbol: &Symbol) -> Option<ConceptualOrderPercents> {
+		let (_, price) = self.last_observation?;
+		self.last_limit = None;
+		self.quote(price, now, deadline, side, symbol)
+	}
+
+	fn quote(&mut self, price: f64, now: DateTime<Utc>, deadline: DateTime<Utc>, side: Side, symbol: &Symbol) -> Option<ConceptualOrderPercents> {
+		let distance = self.optimal_distance(price, now, deadline)?;
+		let limit_price = match side {
+			Side::Buy => price - distance,
+			Side::Sell => price + distance,
+		};
+
+		let threshold = (distance * REPLACE_THRESHOLD).max(price * MIN_REPLACE_THRESHOLD);
+		if self.last_limit.is_some_and(|last| (limit_price - last).abs() <= threshold) {
+			return None;
+		}
+		self.last_limit = Some(limit_price);
+
+		let limit = ConceptualLimit::new(limit_price, false);
+		Some(ConceptualOrderPercents::new(ConceptualOrderType::Limit(limit), symbol.clone(), side, Percent::new(1.0)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn converges_to_price_by_deadline() {
+		let symbol = Symbol::new("BTC", "USDT", Market::BinanceFutures);
+		let start = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+		let deadline = start + chrono::Duration::seconds(1000);
+		let prices = v_utils::distributions::laplace_random_walk(100.0, 1000, 0.1, 0.0, Some(42));
+
+		let mut al = ApproachingLimitIndicator::new();
+		let mut distances = Vec::new();
+		for (i, price) in prices.iter().enumerate() {
+			let now = start + chrono::Duration::seconds(i as i64);
+			if let Some(order) = al.step(*price, now, deadline, Side::Buy, &symbol) {
+				let limit = order.unsafe_limit().price;
+				assert!(limit <= *price, "buy limit {limit} above price {price}");
+				distances.push(*price - limit);
+			}
+		}
+		assert!(!distances.is_empty());
+		let at_deadline = al.step(prices[999] * 0.9, deadline, deadline, Side::Buy, &symbol).unwrap();
+		assert_eq!(at_deadline.unsafe_limit().price, prices[999] * 0.9);
+	}
+
+	#[test]
+	fn internals() {
+		let symbol = Symbol::new("BTC", "USDT", Market::BinanceFutures);
+		let start = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+		let deadline = start + chrono::Duration::seconds(1000);
+		let prices = v_utils::distributions::laplace_random_walk(100.0, 1000, 0.1, 0.0, Some(42));
+
+		let mut al = ApproachingLimitIndicator::new();
+		let mut orders = Vec::new();
+		for (i, price) in prices.iter().enumerate() {
+			let now = start + chrono::Duration::seconds(i as i64);
+			if let Some(order) = al.step(*price, now, deadline, Side::Buy, &symbol) {
+				orders.push((i, Some(order.unsafe_limit().price)));
+			}
+		}
+		let plot = snapshot_fonts::snapshot_plot_orders(&prices, &orders, false);
+		insta::assert_snapshot!(plot, @r"
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󳎶󶅇󶭞󴞪󰧥󰧲󰧥󰨫󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥103.50
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󵾊󰧥󰩌󿿽󿿽󿿽󿿘󿌯󻭛󱨃󻊈󼔁󸎂󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󹁽󿿽󺂇󺺜󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󵸷󰧥󰧥󰧥󰧥󰧥󰧥󰧥󵞂󻇶󷆰󸒛      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧴󷦎󶩡󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿾴󺄞󹽴󻃋󴀁󰧥󰧥󲸠󿿽󿿽󿿽󿿽      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰪮󹶼󹨅󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󸪉󼟬󿿽󿿽󿿽󿿽󿿽      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧵󾹗󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰩰󲴱󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰨅󴜪󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰨏󸱧󷰁󲆇󸍹󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󱮊󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󺖢󾩫󿿺󵼲󲉳󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧿󲦑󱽷󰧥󰧥󹨅󿿽󼷋󸌤󰧥󰧥󰨦󱈦󰧻󾖄󿿽󿿽󿿽󿾓󲎄󰧭󺊼󰧥󰧥󰧥󰧥󵇖󹺟󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󳫫󵱁󰧥󰧩󴺇󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󷲒󿿽󿿽󿿽󿾷󿿽󽧁󰧥󰧥󰧥󰩶󰨪󻛄󻯏󼠚󿿽󿿹󼣂󻩌󿿽󿿽󿿽󿿽󻘩󺎈󺲦󿿽󾂝󿿽󿿽󿿽󿿽󿿽󿿽󽪻󿿽󼼓󰧥󳠠󰹸󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󿾳󿾽󵐆󹌨󿿥󼕪󻥻󰧥󵭆󰧥󰧥󰧥󰧥󰧥󰧥󰩱󿿽󿿽󿿽󿿽󿿽󿿽󿼳󽚂󰧥󽵂󿰑󹳶󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󻜋󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿘟󿿄󼝚󵾛󰧥󱆽󰧥󰩬󼜟󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󸈙󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󽋃󿿤󼉝󽧀󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽98.73
+		──────────────────────────────────────────────────────────────────────────────────────────
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰨅󰧥󰧿󶦿󷰞󻜣󹚥󹇙󸘓󱧫󹧵󽍺󶑁󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰨔󶙪󹪉󹯚󻯻102.53
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󱃬󳌚󷔍󿅈󼓰󾱡󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿋯󻔒󼗡󻿝󺊃󴟖󷙃󼠚󿿽󿿽󿿽󿿽      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰵪󺂯󸰚󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰨒󲥥󰧥󰧦󰧥󰧥󰧥󰧥󰧥󰨍󸓒󶳿󴷈󶿝󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󲩡󳮇󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󰨿󲡊󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󱐂󰨶󴲞󱚤󰧥󰧥󰧥󰧥󰧥󰧥󰧥󰧥󳡕󲵕󲾃󵃳󷸎󷼯󽃭󿿽󺞘󽧀󳻗󰧴󴴎󺪋󸏱󻊈󿿽󿿽󿿽󿿺󽙗󸨈󽲍󳧰󰧥󰧥󰩈󼷇󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󰫟󿽶󱎽󴴐󼎡󷔥󶤀󱽷󰧥󲑞󰧥󰧥󰧥󰧥󰧥󰧥󳊎󿿽󿜪󿿽󿿃󻝮󿸈󱿋󰧥󳌽󱁕󵖱󿏻󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿞󽮶󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿌󴑀󼌬󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽      
+		󰫟󿿽󿿽󿿽󿿽󿿽󿿗󿾗󽮑󿾕󽚥󹞥󼛥󼘦󴧡󼊻󿿽󿿽󿿽󿿽󿿽󿿽󿿐󿿽󹧢󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽󿿽97.11
+		");
+	}
+
+	/// Quotes of a hand-picked path, so that they can be checked by hand too.
+	#[test]
+	fn quotes_by_hand() {
+		let symbol = Symbol::new("BTC", "USDT", Market::BinanceFutures);
+		let start = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+		let deadline = start + chrono::Duration::seconds(12);
+		let prices = [100.0, 100.4, 100.1, 100.1, 100.1, 100.2, 100.2, 99.6, 99.6, 99.7, 99.7, 99.7, 99.7];
+
+		let mut al = ApproachingLimitIndicator::new();
+		let mut orders = Vec::new();
+		for (i, price) in prices.iter().enumerate() {
+			let now = start + chrono::Duration::seconds(i as i64);
+			if let Some(order) = al.step(*price, now, deadline, Side::Buy, &symbol) {
+				orders.push((i, order.unsafe_limit().price));
+			}
+		}
+		insta::assert_debug_snapshot!(orders, @r###"
+		[
+		    (
+		        1,
+		        99.46004317829532,
+		    ),
+		    (
+		        2,
+		        99.21630403551444,
+		    ),
+		    (
+		        4,
+		        99.34911838574786,
+		    ),
+		    (
+		        5,
+		        99.51343851742848,
+		    ),
+		    (
+		        6,
+		        99.58046215204978,
+		    ),
+		    (
+		        7,
+		        99.01266246784051,
+		    ),
+		    (
+		        8,
+		        99.08797101031364,
+		    ),
+		    (
+		        9,
+		        99.26649706965273,
+		    ),
+		    (
+		        10,
+		        99.35500864639947,
+		    ),
+		    (
+		        11,
+		        99.46223111814604,
+		    ),
+		    (
+		        12,
+		        99.7,
+		    ),
+		]
+		"###);
 	}
 }
-//
-//#[cfg(test)]
-// mod tests {
-// 	use super::*;
-//
-// 	#[tokio::test]
-// 	async fn internals() {
-// 		let mut ts = ApproachingLimitIndicator::new();
-// 		let mut orders = Vec::new();
-// 		let prices = v_utils::distributions::laplace_random_walk(100.0, 1000, 0.1, 0.0, Some(42));
-// 		for (i, price) in prices.iter().enumerate() {
-// 			if let Some(order) = ts.step(*price, Utc::now()/*dbg*/, Side::Buy, &Symbol::new("BTC", "USDT", Market::BinanceFutures)) {
-// 				let ConceptualOrderPercents { order_type, .. } = order;
-// 				if let ConceptualOrderType::StopMarket(sm) = order_type {
-// 					orders.push((i, Some(sm.price)));
-// 				} else {
-// 					panic!("Expected StopMarket order type");
-// 				}
-// 			}
-// 		}
-// 		let plot = v_utils::utils::snapshot_plot_orders(&prices, &orders);
-// 		insta::assert_snapshot!(plot, @r###"
-//                                                                      ▂▃▄▃                  103.50
-//                                                                   ▃  █████▆▁▆▇▄
-//                                                                  ▅█▅▆██████████▃       ▃▆▄▄
-//                                                                ▄▄███████████████▅▅▆▂  ▂████
-//                                                              ▅▅█████████████████████▅▇█████
-//                                                             ███████████████████████████████
-//                     ▂                ▂        ▅▄▁▄         ▁███████████████████████████████
-//                   ▆██▃▁         ▂▁  ▅█▇▄   ▁ █████▁ ▅    ▃▅████████████████████████████████
-//  ▂▃  ▃           ▄█████▇     ▆▆▇██▇▆████▆▅▆█▇██████▇█▇ ▂▁██████████████████████████████████
-//  ██▃▅█▇▆ ▃       ███████▇ ▇█▅█████████████████████████▆████████████████████████████████████
-//  █████████▇▃ ▁  ▇████████▄█████████████████████████████████████████████████████████████████
-//  ███████████▇█▇▇███████████████████████████████████████████████████████████████████████████98.73
-//  ──────────────────────────────────────────────────────────────────────────────────────────
-//                                                                      ▃▆▆███████████████████101.41
-//                                                                  ▁▆▆▆██████████████████████
-//                                                               ▁▃▅██████████████████████████
-//                                                              ▆█████████████████████████████
-//                                                 ▁▁▁▁▁▁▁▁▁▁▁▁███████████████████████████████
-//                   ▂▅▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇█████████████████████████████████████████████████████
-//  ▁▁▁▁▁▁▁▁▁▁▁▁▁▁▁▁▂█████████████████████████████████████████████████████████████████████████97.98
-//  "###);
-// 	}
-//}
@@ -0,0 +1,198 @@
+#[allow(unused_imports)] // RA bug
+use std::str::FromStr;
+
+use color_eyre::eyre::{Result, bail};
+use discretionary_engine_macros::ProtocolWrapper;
+use tokio::{select, sync::mpsc, task::JoinSet};
+use tracing::{debug, instrument};
+use v_utils::{
+	Percent,
+	macros::CompactFormat,
+	trades::{Ohlc, Side, Timeframe},
+};
+
+use crate::{
+	exchange_apis::{Market, Symbol, order_types::*},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
+};
+
+/// Stop kept at `multiplier`×ATR from the extreme price reached since attaching. Only ever tightens.
+#[derive(Clone, CompactFormat, Copy, Debug, Default, ProtocolWrapper, derive_new::new)]
+pub struct Atr {
+	/// NB: Can't be updated on a running protocol, as it's what the kline stream is subscribed to.
+	timeframe: Timeframe,
+	/// Number of klines ATR is smoothed over.
+	/// NB: Can't be updated on a running protocol, as ATR is warmed up under it.
+	period: usize,
+	multiplier: f64,
+}
+
+impl ProtocolTrait for AtrWrapper {
+	type Params = Atr;
+
+	#[instrument(skip(position_js, tx_orders))]
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
+		let symbol = Symbol {
+			base: asset,
+			quote: "USDT".to_owned(),
+			market: Market::BinanceFutures,
+		};
+		let tf = { self.0.read().unwrap().timeframe };
+		let mut klines = position.market_data.klines(&symbol, tf)?;
+		let (params_arc, updated, protocol_id) = (self.0.clone(), self.1.clone(), self.id());
+		position_js.spawn(async move {
+			let period = { params_arc.read().unwrap().period };
+			let init_ohlcs = position.market_data.closed_klines(&symbol, tf, (period * 3).max(100)).await?;
+			debug!("initialized klines");
+			// a freshly listed symbol has no history yet, then it's seeded on the first kline to close
+			let mut atr = AtrIndicator::init(&init_ohlcs, period);
+
+			let mut last_order = None;
+			loop {
+				let params = *params_arc.read().unwrap();
+				let maybe_order = atr.as_mut().and_then(|atr| atr.order(params.multiplier, &symbol, protocol_side));
+				if maybe_order.is_some() && last_order != maybe_order {
+					tx_orders.send(ProtocolOrders::new(protocol_id.clone(), vec![maybe_order.clone()])).await?;
+					last_order = maybe_order;
+				}
+
+				select! {
+					Some(kline) = klines.recv() => {
+						// ATR is defined over complete klines only
+						if kline.closed {
+							match atr.as_mut() {
+								Some(atr) => atr.step(kline.ohlc, period),
+								None => atr = AtrIndicator::init(&[kline.ohlc], period),
+							}
+						}
+					},
+					// new multiplier applies right away
+					_ = updated.notified() => {},
+				}
+			}
+		});
+
+		Ok(())
+	}
+
+	fn update_params(&self, new_params: Atr) -> Result<()> {
+		let current_tf = self.0.read().unwrap().timeframe;
+		if new_params.timeframe != current_tf {
+			bail!("Can't change the timeframe of a running atr ({current_tf} -> {})", new_params.timeframe);
+		}
+		let current_period = self.0.read().unwrap().period;
+		if new_params.period != current_period {
+			bail!("Can't change the period of a running atr ({current_period} -> {})", new_params.period);
+		}
+		self.set_params(new_params);
+		Ok(())
+	}
+
+	fn get_type(&self) -> ProtocolType {
+		ProtocolType::Momentum
+	}
+}
+
+/// Wilder-smoothed ATR, along with the extreme prices reached and the stop derived from them.
+#[derive(Clone, Copy, Debug, Default)]
+struct AtrIndicator {
+	atr: f64,
+	prev_close: f64,
+	/// Highest high since init. Extreme in favour of a long position.
+	top: f64,
+	/// Lowest low since init. Extreme in favour of a short position.
+	bottom: f64,
+	/// Tightest stop emitted so far, if any
+	stop: Option<f64>,
+}
+impl AtrIndicator {
+	/// Warms ATR up on historic klines. Extremes start from the last close, as we only care for those reached while attached. `None` if there are none to seed it on.
+	fn init(init_klines: &[Ohlc], period: usize) -> Option<Self> {
+		let first = init_klines.first()?;
+		let mut atr_indicator = Self {
+			atr: first.high - first.low,
+			prev_close: first.close,
+			..Default::default()
+		};
+		for ohlc in &init_klines[1..] {
+			atr_indicator.update_atr(*ohlc, period);
+		}
+		atr_indicator.top = atr_indicator.prev_close;
+		atr_indicator.bottom = atr_indicator.prev_close;
+		Some(atr_indicator)
+	}
+
+	fn update_atr(&mut self, ohlc: Ohlc, period: usize) {
+		let true_range = (ohlc.high - ohlc.low).max((ohlc.high - self.prev_close).abs()).max((ohlc.low - self.prev_close).abs());
+		let period = period.max(1) as f64;
+		self.atr = (self.atr * (period - 1.0) + true_range) / period;
+		self.prev_close = ohlc.close;
+	}
+
+	fn step(&mut self, ohlc: Ohlc, period: usize) {
+		self.update_atr(ohlc, period);
+		self.top = self.top.max(ohlc.high);
+		self.bottom = self.bottom.min(ohlc.low);
+	}
+
+	/// `side` is that of the stop order, so opposite to the position's.
+	fn order(&mut self, multiplier: f64, symbol: &Symbol, side: Side) -> Option<ConceptualOrderPercents> {
+		let distance = multiplier * self.atr;
+		let stop = match side {
+			Side::Sell => {
+				let candidate = self.top - distance;
+				self.stop.map_or(candidate, |s| s.max(candidate))
+			}
+			Side::Buy => {
+				let candidate = self.bottom + distance;
+				self.stop.map_or(candidate, |s| s.min(candidate))
+			}
+		};
+		if stop <= 0.0 {
+			return None;
+		}
+		self.stop = Some(stop);
+		Some(ConceptualOrderPercents::new(
+			ConceptualOrderType::StopMarket(ConceptualStopMarket::new(stop)),
+			symbol.clone(),
+			side,
+			Percent::new(1.0),
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use v_utils::trades::mock_p_to_ohlc;
+
+	use super::*;
+
+	#[test]
+	fn stop_only_tightens() {
+		let atr_wrapper = AtrWrapper::from_str("atr:t5m:p14:m2").unwrap();
+		let params = *atr_wrapper.0.read().unwrap();
+
+		let init_p = v_utils::distributions::laplace_random_walk(100.0, 1000, 0.2, 0.0, Some(123));
+		let init_ohlc = mock_p_to_ohlc(&init_p, 10);
+		let test_data_p = v_utils::distributions::laplace_random_walk(100.0, 1000, 0.2, 0.0, Some(42));
+		let test_data_ohlc = mock_p_to_ohlc(&test_data_p, 10);
+
+		for side in [Side::Sell, Side::Buy] {
+			let mut atr = AtrIndicator::init(&init_ohlc, params.period).unwrap();
+			assert!(atr.atr > 0.0);
+			let mut stops = vec![atr.order(params.multiplier, &Symbol::default(), side).unwrap().unsafe_stop_market().price];
+			for ohlc in &test_data_ohlc {
+				atr.step(*ohlc, params.period);
+				if let Some(order) = atr.order(params.multiplier, &Symbol::default(), side) {
+					stops.push(order.unsafe_stop_market().price);
+				}
+			}
+			let tightened = stops.windows(2).all(|w| match side {
+				Side::Sell => w[1] >= w[0],
+				Side::Buy => w[1] <= w[0],
+			});
+			assert!(tightened, "{side:?} stop loosened: {stops:?}");
+		}
+		assert!(AtrIndicator::init(&[], params.period).is_none());
+	}
+}
@@ -0,0 +1,229 @@
+use std::{fmt, str::FromStr};
+
+use color_eyre::eyre::{Result, bail};
+use discretionary_engine_macros::ProtocolWrapper;
+use tokio::{select, sync::mpsc, task::JoinSet};
+use tracing::warn;
+use v_utils::{Percent, trades::Side};
+
+use crate::{
+	exchange_apis::{Market, Symbol, order_types::*},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
+};
+
+/// Moves the stop to the entry (plus `buffer`, to cover the fees) once price has gone `trigger` in our favour. Never loosens it afterwards.
+///
+/// Format: `be:t<trigger>[:r<risk>][:b<buffer>]`, e.g. `be:t1%:b0.1%` or `be:t1.5R:r0.02`.
+/// - `t`: either percents of the entry price (`1%`), or multiples of `risk` (`1.5R`).
+/// - `r`: distance from entry to the initial stop, in price units or percents. Required for R-multiple triggers, and only accepted with them.
+/// - `b`: how far past the entry to put the stop, in price units or percents. Defaults to 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ProtocolWrapper, derive_new::new)]
+pub struct BreakEven {
+	trigger: Trigger,
+	buffer: PriceDistance,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+	Percent(Percent),
+	/// `multiple` of the `risk` distance.
+	R {
+		multiple: f64,
+		risk: PriceDistance,
+	},
+}
+impl Default for Trigger {
+	fn default() -> Self {
+		Trigger::Percent(Percent(0.01))
+	}
+}
+
+impl BreakEven {
+	/// Distance price has to travel from the entry in our favour before the stop is moved.
+	fn trigger_distance(&self, entry_price: f64) -> f64 {
+		match self.trigger {
+			Trigger::Percent(p) => entry_price * *p,
+			Trigger::R { multiple, risk } => multiple * risk.distance(entry_price),
+		}
+	}
+}
+
+impl FromStr for BreakEven {
+	type Err = eyre::Report;
+
+	fn from_str(spec: &str) -> Result<Self> {
+		let mut parts = spec.split(':');
+		if parts.next() != Some("be") {
+			bail!("Not a be spec: {spec}");
+		}
+		let (mut trigger, mut risk, mut buffer) = (None, None, PriceDistance::default());
+		for part in parts {
+			match part.split_at_checked(1) {
+				Some(("t", v)) => trigger = Some(v),
+				Some(("r", v)) => risk = Some(v.parse::<PriceDistance>()?),
+				Some(("b", v)) => buffer = PriceDistance::parse(v, true)?,
+				_ => bail!("Unknown be param: {part}"),
+			}
+		}
+		let Some(trigger) = trigger else {
+			bail!("be requires a `t` param, got: {spec}");
+		};
+		let trigger = match (trigger.strip_suffix('%'), trigger.strip_suffix('R'), risk) {
+			(Some(percent), _, None) => Trigger::Percent(Percent(percent.parse::<f64>()? / 100.0)),
+			(Some(_), _, Some(_)) => bail!("`r` only applies to R-multiple triggers, got: {spec}"),
+			(_, Some(multiple), Some(risk)) => Trigger::R { multiple: multiple.parse()?, risk },
+			(_, Some(_), None) => bail!("R-multiple trigger requires `r`, got: {spec}"),
+			_ => bail!("Trigger must be either in percents (`1%`) or R-multiples (`1.5R`), got {trigger}"),
+		};
+		Ok(Self { trigger, buffer })
+	}
+}
+
+impl fmt::Display for BreakEven {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.trigger {
+			Trigger::Percent(p) => write!(f, "be:t{}%", *p * 100.0)?,
+			Trigger::R { multiple, risk } => write!(f, "be:t{multiple}R:r{risk}")?,
+		}
+		if self.buffer != PriceDistance::default() {
+			write!(f, ":b{}", self.buffer)?;
+		}
+		Ok(())
+	}
+}
+
+impl ProtocolTrait for BreakEvenWrapper {
+	type Params = BreakEven;
+
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
+		let symbol = Symbol {
+			base: asset,
+			quote: "USDT".to_owned(),
+			market: Market::BinanceFutures,
+		};
+		let mut trades = position.market_data.trades(&symbol);
+		let (params, updated, protocol_id) = (self.0.clone(), self.1.clone(), self.id());
+		position_js.spawn(async move {
+			let entry_price = match position.entry_price {
+				Some(p) => p,
+				None => {
+					let price = position.price().await;
+					warn!("Entry price of the position on {symbol} is unknown, taking break-even to be the current {price}");
+					price
+				}
+			};
+
+			let mut be_indicator = BreakEvenIndicator::new(entry_price);
+			let mut last_price = None;
+			loop {
+				select! {
+					Some(price) = trades.recv() => last_price = Some(price),
+					// re-evaluate the last price under the new params
+					_ = updated.notified() => {},
+				}
+				let Some(price) = last_price else { continue };
+				let current = *params.read().unwrap();
+				if let Some(order) = be_indicator.step(price, &current, protocol_side, &symbol) {
+					let protocol_orders = ProtocolOrders::new(protocol_id.clone(), vec![Some(order)]);
+					tx_orders.send(protocol_orders).await?;
+				}
+			}
+		});
+		Ok(())
+	}
+
+	fn update_params(&self, new_params: BreakEven) -> Result<()> {
+		self.set_params(new_params);
+		Ok(())
+	}
+
+	fn get_type(&self) -> ProtocolType {
+		ProtocolType::SL
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BreakEvenIndicator {
+	entry_price: f64,
+	triggered: bool,
+	/// Last stop price sent out, if any
+	stop: Option<f64>,
+}
+impl BreakEvenIndicator {
+	fn new(entry_price: f64) -> Self {
+		Self {
+			entry_price,
+			triggered: false,
+			stop: None,
+		}
+	}
+
+	/// `side` is that of the stop order, so opposite to the position's. Returns an order only when the stop moves.
+	fn step(&mut self, price: f64, params: &BreakEven, side: Side, symbol: &Symbol) -> Option<ConceptualOrderPercents> {
+		// a long is closed by selling, so for it "in our favour" is up
+		let direction = match side {
+			Side::Sell => 1.0,
+			Side::Buy => -1.0,
+		};
+		if !self.triggered {
+			self.triggered = (price - self.entry_price) * direction >= params.trigger_distance(self.entry_price);
+		}
+		if !self.triggered {
+			return None;
+		}
+
+		let candidate = self.entry_price + direction * params.buffer.distance(self.entry_price);
+		let stop = match self.stop {
+			Some(s) if (candidate - s) * direction <= 0.0 => return None,
+			_ => candidate,
+		};
+		self.stop = Some(stop);
+		let sm = ConceptualStopMarket::new(stop);
+		Some(ConceptualOrderPercents::new(ConceptualOrderType::StopMarket(sm), symbol.clone(), side, Percent::new(1.0)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse() {
+		let percent = BreakEven::from_str("be:t1%:b0.1%").unwrap();
+		assert!((percent.trigger_distance(200.0) - 2.0).abs() < 1e-9);
+		assert!((percent.buffer.distance(200.0) - 0.2).abs() < 1e-9);
+		let r = BreakEven::from_str("be:t1.5R:r2").unwrap();
+		assert_eq!(r.trigger_distance(100.0), 3.0);
+		assert_eq!(BreakEven::from_str(&r.to_string()).unwrap(), r);
+
+		assert!(BreakEven::from_str("be:t1.5R").is_err());
+		assert!(BreakEven::from_str("be:t1%:r2").is_err());
+		assert!(BreakEven::from_str("be:b0.1%").is_err());
+	}
+
+	#[test]
+	fn never_loosens() {
+		let tight = BreakEven::from_str("be:t1.5R:r2:b0.5").unwrap();
+		let loose = BreakEven::from_str("be:t1.5R:r2:b0.1").unwrap();
+		let mut be = BreakEvenIndicator::new(100.0);
+		let mut stops = Vec::new();
+		for (price, params) in [(101.0, &tight), (103.0, &tight), (99.0, &tight), (104.0, &loose), (101.0, &loose)] {
+			stops.push(be.step(price, params, Side::Sell, &Symbol::default()).map(|o| o.unsafe_stop_market().price));
+		}
+		insta::assert_debug_snapshot!(stops, @r###"
+  [
+      None,
+      Some(
+          100.5,
+      ),
+      None,
+      None,
+      None,
+  ]
+  "###);
+
+		let mut short_be = BreakEvenIndicator::new(100.0);
+		assert_eq!(short_be.step(98.0, &tight, Side::Buy, &Symbol::default()).map(|o| o.unsafe_stop_market().price), None);
+		assert_eq!(short_be.step(97.0, &tight, Side::Buy, &Symbol::default()).map(|o| o.unsafe_stop_market().price), Some(99.5));
+	}
+}
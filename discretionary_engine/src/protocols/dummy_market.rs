@@ -5,7 +5,7 @@ use v_utils::{Percent, macros::CompactFormat, trades::Side};
 
 use crate::{
 	exchange_apis::{Market, Symbol, order_types::*},
-	protocols::{ProtocolOrders, ProtocolTrait, ProtocolType},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
 };
 
 /// Literally just sends one market order.
@@ -17,7 +17,7 @@ pub struct DummyMarket {
 impl ProtocolTrait for DummyMarketWrapper {
 	type Params = DummyMarket;
 
-	fn attach(&self, set: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side) -> Result<()> {
+	fn attach(&self, set: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, _position: PositionContext) -> Result<()> {
 		let symbol = Symbol {
 			base: asset,
 			quote: "USDT".to_owned(),
@@ -26,10 +26,9 @@ impl ProtocolTrait for DummyMarketWrapper {
 		let m = ConceptualMarket::new(Percent(1.0));
 		let order = ConceptualOrderPercents::new(ConceptualOrderType::Market(m), symbol.clone(), protocol_side, Percent::new(1.0));
 
-		let protocol_spec = self.0.read().unwrap().to_string();
-		let protocol_orders = ProtocolOrders::new(protocol_spec, vec![Some(order)]);
+		let protocol_orders = ProtocolOrders::new(self.id(), vec![Some(order)]);
 		set.spawn(async move {
-			tx_orders.send(protocol_orders).await.unwrap();
+			tx_orders.send(protocol_orders).await?;
 			//LOOP: it's a dummy protocol, relax
 			loop {
 				tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
@@ -40,8 +39,10 @@ impl ProtocolTrait for DummyMarketWrapper {
 		Ok(())
 	}
 
-	fn update_params(&self, _params: Self::Params) -> Result<()> {
-		unimplemented!()
+	/// Has nothing to update, as the order is sent right on attaching.
+	fn update_params(&self, params: Self::Params) -> Result<()> {
+		self.set_params(params);
+		Ok(())
 	}
 
 	fn get_type(&self) -> ProtocolType {
@@ -1,24 +1,40 @@
 mod approaching_limit;
+mod atr;
+mod break_even;
 mod dummy_market;
 mod sar;
+mod time;
+mod tpsl;
 mod trailing_stop;
-use std::{collections::HashSet, str::FromStr};
+use std::{collections::HashSet, str::FromStr, time::Duration};
 
 use approaching_limit::{ApproachingLimit, ApproachingLimitWrapper};
+use atr::{Atr, AtrWrapper};
+use break_even::{BreakEven, BreakEvenWrapper};
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{Result, bail};
-use dummy_market::DummyMarketWrapper;
+use dummy_market::{DummyMarket, DummyMarketWrapper};
 use sar::{Sar, SarWrapper};
+use time::{Time, TimeWrapper};
 use tokio::{sync::mpsc, task::JoinSet};
+use tpsl::{TpSl, TpSlWrapper};
 use tracing::instrument;
 use trailing_stop::{TrailingStop, TrailingStopWrapper};
 use uuid::Uuid;
-use v_utils::{Percent, trades::Side};
+use v_utils::trades::Side;
 
-use crate::exchange_apis::order_types::{ConceptualOrder, ConceptualOrderPercents, ProtocolOrderId};
+use crate::{
+	exchange_apis::{
+		Symbol, exchanges,
+		market_data::{Backoff, MarketData},
+		order_types::{ConceptualOrder, ConceptualOrderPercents, ProtocolOrderId},
+	},
+	utils::report_connection_problem,
+};
 
 /// Used when determining sizing or the changes in it, in accordance to the current distribution of rm on types of algorithms.
 ///
-/// Size is by default equally distributed amongst the protocols of the same `ProtocolType`, to total 101% for each type with at least one representative.
+/// Size is distributed amongst the protocols of the same `ProtocolType` proportionally to their weights (equally by default), to total 101% for each type with at least one representative.
 /// Note that total size is is 101% for both the stop and normal orders (because they are on the different sides of the price).
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, derive_new::new)]
 pub enum ProtocolType {
@@ -26,12 +42,45 @@ pub enum ProtocolType {
 	TP,
 	SL,
 	StopEntry,
+	/// Closes the position once it has outlived its edge.
+	Expiry,
+}
+
+/// What protocols get handed on attaching to a Position.
+#[derive(Clone, Debug, Default, derive_new::new)]
+pub struct PositionContext {
+	/// Average price the position was acquired at. `None` during the acquisition itself, or if the exchange never reported fill prices.
+	pub entry_price: Option<f64>,
+	pub opened_at: DateTime<Utc>,
+	/// Period for which the edge of the position is expected to persist, if it was specified.
+	pub tf: Option<Duration>,
+	/// Where protocols are to get their price data from.
+	pub market_data: MarketData,
+	/// Where the position is executed. Price data of [Self::market_data] is always that of Binance Futures, regardless.
+	pub symbol: Symbol,
+	pub testnet: bool,
+}
+impl PositionContext {
+	/// Last price on the position's own market. Keeps retrying until the exchange responds, as protocols have no way to recover from an error.
+	pub async fn price(&self) -> f64 {
+		let mut backoff = Backoff::default();
+		loop {
+			match exchanges::last_price(&self.symbol, self.testnet).await {
+				Ok(price) => return price,
+				Err(e) => {
+					report_connection_problem(e.wrap_err(format!("Failed to fetch the price of {}", self.symbol))).await;
+					backoff.wait().await;
+				}
+			}
+		}
+	}
 }
 
 pub trait ProtocolTrait {
 	type Params;
-	/// Requested orders are being sent over the mspc with uuid of the protocol on each batch, as we want to replace the previous requested batch if any.
-	fn attach(&self, set: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side) -> Result<()>;
+	/// Requested orders are being sent over the mspc with id of the protocol on each batch, as we want to replace the previous requested batch if any.
+	fn attach(&self, set: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()>;
+	/// Applies to the running protocol, which is to re-emit its orders under the new params right away. Errors if they can't be applied without restarting it.
 	fn update_params(&self, params: Self::Params) -> Result<()>;
 	fn get_type(&self) -> ProtocolType;
 }
@@ -41,8 +90,12 @@ pub trait ProtocolTrait {
 pub enum Protocol {
 	TrailingStop(TrailingStopWrapper),
 	Sar(SarWrapper),
+	Atr(AtrWrapper),
 	ApproachingLimit(ApproachingLimitWrapper),
 	DummyMarket(DummyMarketWrapper),
+	TpSl(TpSlWrapper),
+	Time(TimeWrapper),
+	BreakEven(BreakEvenWrapper),
 }
 impl FromStr for Protocol {
 	type Err = eyre::Report;
@@ -52,22 +105,34 @@ impl FromStr for Protocol {
 			Ok(Protocol::TrailingStop(ts))
 		} else if let Ok(sar) = SarWrapper::from_str(spec) {
 			Ok(Protocol::Sar(sar))
+		} else if let Ok(atr) = AtrWrapper::from_str(spec) {
+			Ok(Protocol::Atr(atr))
 		} else if let Ok(al) = ApproachingLimitWrapper::from_str(spec) {
 			Ok(Protocol::ApproachingLimit(al))
 		} else if let Ok(dm) = DummyMarketWrapper::from_str(spec) {
 			Ok(Protocol::DummyMarket(dm))
+		} else if let Ok(tpsl) = TpSlWrapper::from_str(spec) {
+			Ok(Protocol::TpSl(tpsl))
+		} else if let Ok(time) = TimeWrapper::from_str(spec) {
+			Ok(Protocol::Time(time))
+		} else if let Ok(be) = BreakEvenWrapper::from_str(spec) {
+			Ok(Protocol::BreakEven(be))
 		} else {
 			bail!("Could not convert string to any Protocol\nString: {spec}")
 		}
 	}
 }
 impl Protocol {
-	pub fn attach(&self, position_set: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side) -> Result<()> {
+	pub fn attach(&self, position_set: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
 		match self {
-			Protocol::TrailingStop(ts) => ts.attach(position_set, tx_orders, asset, protocol_side),
-			Protocol::Sar(sar) => sar.attach(position_set, tx_orders, asset, protocol_side),
-			Protocol::ApproachingLimit(al) => al.attach(position_set, tx_orders, asset, protocol_side),
-			Protocol::DummyMarket(dm) => dm.attach(position_set, tx_orders, asset, protocol_side),
+			Protocol::TrailingStop(ts) => ts.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::Sar(sar) => sar.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::Atr(atr) => atr.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::ApproachingLimit(al) => al.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::DummyMarket(dm) => dm.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::TpSl(tpsl) => tpsl.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::Time(time) => time.attach(position_set, tx_orders, asset, protocol_side, position),
+			Protocol::BreakEven(be) => be.attach(position_set, tx_orders, asset, protocol_side, position),
 		}
 	}
 
@@ -81,11 +146,30 @@ impl Protocol {
 				ProtocolParams::Sar(sar_params) => sar.update_params(sar_params),
 				_ => Err(eyre::Report::msg("Mismatched params")),
 			},
+			Protocol::Atr(atr) => match params {
+				ProtocolParams::Atr(atr_params) => atr.update_params(atr_params),
+				_ => Err(eyre::Report::msg("Mismatched params")),
+			},
 			Protocol::ApproachingLimit(al) => match params {
 				ProtocolParams::ApproachingLimit(al_params) => al.update_params(al_params),
 				_ => Err(eyre::Report::msg("Mismatched params")),
 			},
-			Protocol::DummyMarket(_) => Ok(()),
+			Protocol::DummyMarket(dm) => match params {
+				ProtocolParams::DummyMarket(dm_params) => dm.update_params(dm_params),
+				_ => Err(eyre::Report::msg("Mismatched params")),
+			},
+			Protocol::TpSl(tpsl) => match params {
+				ProtocolParams::TpSl(tpsl_params) => tpsl.update_params(tpsl_params),
+				_ => Err(eyre::Report::msg("Mismatched params")),
+			},
+			Protocol::Time(time) => match params {
+				ProtocolParams::Time(time_params) => time.update_params(time_params),
+				_ => Err(eyre::Report::msg("Mismatched params")),
+			},
+			Protocol::BreakEven(be) => match params {
+				ProtocolParams::BreakEven(be_params) => be.update_params(be_params),
+				_ => Err(eyre::Report::msg("Mismatched params")),
+			},
 		}
 	}
 
@@ -93,8 +177,12 @@ impl Protocol {
 		match self {
 			Protocol::TrailingStop(ts) => ts.get_type(),
 			Protocol::Sar(sar) => sar.get_type(),
+			Protocol::Atr(atr) => atr.get_type(),
 			Protocol::ApproachingLimit(al) => al.get_type(),
 			Protocol::DummyMarket(dm) => dm.get_type(),
+			Protocol::TpSl(tpsl) => tpsl.get_type(),
+			Protocol::Time(time) => time.get_type(),
+			Protocol::BreakEven(be) => be.get_type(),
 		}
 	}
 
@@ -102,8 +190,70 @@ impl Protocol {
 		match self {
 			Protocol::TrailingStop(ts) => ts.signature(),
 			Protocol::Sar(sar) => sar.signature(),
+			Protocol::Atr(atr) => atr.signature(),
 			Protocol::ApproachingLimit(al) => al.signature(),
 			Protocol::DummyMarket(dm) => dm.signature(),
+			Protocol::TpSl(tpsl) => tpsl.signature(),
+			Protocol::Time(time) => time.signature(),
+			Protocol::BreakEven(be) => be.signature(),
+		}
+	}
+
+	/// Stays the same through param updates, unlike the [signature](Self::signature). Positions key everything they know of the protocol by it.
+	pub fn id(&self) -> String {
+		match self {
+			Protocol::TrailingStop(ts) => ts.id(),
+			Protocol::Sar(sar) => sar.id(),
+			Protocol::Atr(atr) => atr.id(),
+			Protocol::ApproachingLimit(al) => al.id(),
+			Protocol::DummyMarket(dm) => dm.id(),
+			Protocol::TpSl(tpsl) => tpsl.id(),
+			Protocol::Time(time) => time.id(),
+			Protocol::BreakEven(be) => be.id(),
+		}
+	}
+
+	pub fn params(&self) -> ProtocolParams {
+		match self {
+			Protocol::TrailingStop(ts) => ProtocolParams::TrailingStop(*ts.0.read().unwrap()),
+			Protocol::Sar(sar) => ProtocolParams::Sar(*sar.0.read().unwrap()),
+			Protocol::Atr(atr) => ProtocolParams::Atr(*atr.0.read().unwrap()),
+			Protocol::ApproachingLimit(al) => ProtocolParams::ApproachingLimit(*al.0.read().unwrap()),
+			Protocol::DummyMarket(dm) => ProtocolParams::DummyMarket(dm.0.read().unwrap().clone()),
+			Protocol::TpSl(tpsl) => ProtocolParams::TpSl(tpsl.0.read().unwrap().clone()),
+			Protocol::Time(time) => ProtocolParams::Time(*time.0.read().unwrap()),
+			Protocol::BreakEven(be) => ProtocolParams::BreakEven(*be.0.read().unwrap()),
+		}
+	}
+
+	pub fn weight(&self) -> f64 {
+		match self {
+			Protocol::TrailingStop(ts) => ts.weight(),
+			Protocol::Sar(sar) => sar.weight(),
+			Protocol::Atr(atr) => atr.weight(),
+			Protocol::ApproachingLimit(al) => al.weight(),
+			Protocol::DummyMarket(dm) => dm.weight(),
+			Protocol::TpSl(tpsl) => tpsl.weight(),
+			Protocol::Time(time) => time.weight(),
+			Protocol::BreakEven(be) => be.weight(),
+		}
+	}
+
+	/// Whether both are of the same protocol, regardless of params.
+	pub fn same_kind(&self, other: &Protocol) -> bool {
+		std::mem::discriminant(self) == std::mem::discriminant(other)
+	}
+
+	/// `ProtocolType`s the protocol's orders are sized under, each with the id of the `ProtocolOrders` it sends for it.
+	///
+	/// Most protocols are of a single type and send under their id. Those managing orders of several types send a separate batch for each.
+	pub fn sizing_legs(&self) -> Vec<(ProtocolType, String)> {
+		match self {
+			Protocol::TpSl(tpsl) => {
+				let id = tpsl.id();
+				[ProtocolType::TP, ProtocolType::SL].into_iter().map(|t| (t, TpSl::leg_id(&id, t))).collect()
+			}
+			_ => vec![(self.get_type(), self.id())],
 		}
 	}
 }
@@ -112,7 +262,12 @@ impl Protocol {
 pub enum ProtocolParams {
 	TrailingStop(TrailingStop),
 	Sar(Sar),
+	Atr(Atr),
 	ApproachingLimit(ApproachingLimit),
+	DummyMarket(DummyMarket),
+	TpSl(TpSl),
+	Time(Time),
+	BreakEven(BreakEven),
 }
 impl From<TrailingStop> for ProtocolParams {
 	fn from(ts: TrailingStop) -> Self {
@@ -124,23 +279,78 @@ impl From<Sar> for ProtocolParams {
 		ProtocolParams::Sar(sar)
 	}
 }
+impl From<Atr> for ProtocolParams {
+	fn from(atr: Atr) -> Self {
+		ProtocolParams::Atr(atr)
+	}
+}
 impl From<ApproachingLimit> for ProtocolParams {
 	fn from(al: ApproachingLimit) -> Self {
 		ProtocolParams::ApproachingLimit(al)
 	}
 }
+impl From<DummyMarket> for ProtocolParams {
+	fn from(dm: DummyMarket) -> Self {
+		ProtocolParams::DummyMarket(dm)
+	}
+}
+impl From<TpSl> for ProtocolParams {
+	fn from(tpsl: TpSl) -> Self {
+		ProtocolParams::TpSl(tpsl)
+	}
+}
+impl From<Time> for ProtocolParams {
+	fn from(time: Time) -> Self {
+		ProtocolParams::Time(time)
+	}
+}
+impl From<BreakEven> for ProtocolParams {
+	fn from(be: BreakEven) -> Self {
+		ProtocolParams::BreakEven(be)
+	}
+}
 //,}}}
 
+/// Weight of a protocol's share of the size, relative to the others of its `ProtocolType`. Applies to all protocols, so it's taken out of the spec before it gets to theirs.
+const WEIGHT_PARAM: &str = "w";
+
+/// Splits the `w<weight>` param off the spec, e.g. `ts:p0.5:w0.7` -> (`ts:p0.5`, 0.7). Weight defaults to 1.
+pub fn split_weight(spec: &str) -> Result<(String, f64)> {
+	let mut weight = None;
+	let mut parts = Vec::new();
+	for (i, part) in spec.split(':').enumerate() {
+		match part.strip_prefix(WEIGHT_PARAM).map(str::parse::<f64>) {
+			Some(Ok(w)) if i > 0 =>
+				if weight.replace(w).is_some() {
+					bail!("Weight is specified more than once: {spec}");
+				},
+			_ => parts.push(part),
+		}
+	}
+	let weight = weight.unwrap_or(1.0);
+	if !(weight.is_finite() && weight > 0.0) {
+		bail!("Weight must be positive, got {weight} in {spec}");
+	}
+	Ok((parts.join(":"), weight))
+}
+
 #[instrument]
 pub fn interpret_protocol_specs(protocol_specs: Vec<String>) -> Result<Vec<Protocol>> {
 	let protocol_specs: Vec<String> = protocol_specs.into_iter().filter(|s| s != "").collect();
 	if protocol_specs.len() == 0 {
 		bail!("No protocols specified");
 	}
-	assert_eq!(protocol_specs.len(), protocol_specs.iter().collect::<HashSet<&String>>().len()); // protocol specs are later used as their IDs
 	let mut protocols = Vec::new();
+	// protocol specs are later used as their IDs
+	let mut ids = HashSet::new();
 	for spec in protocol_specs {
 		let protocol = Protocol::from_str(&spec)?;
+		if !ids.insert(protocol.id()) {
+			bail!(
+				"Protocol {} is specified more than once (weights aside), merge them into one with the summed weight instead",
+				protocol.id()
+			);
+		}
 		protocols.push(protocol);
 	}
 	Ok(protocols)
@@ -150,6 +360,8 @@ pub fn interpret_protocol_specs(protocol_specs: Vec<String>) -> Result<Vec<Proto
 pub struct ProtocolFill {
 	pub id: ProtocolOrderId,
 	pub qty: f64,
+	/// `None` if the exchange didn't report it.
+	pub price: Option<f64>,
 }
 
 #[derive(Clone, Debug, Default, derive_new::new)]
@@ -229,6 +441,8 @@ impl ProtocolOrders {
 
 	/// Order is *NOT* preserved. Orders with no remaining size are completely excluded from the output.
 	///
+	/// Each order is sized to its share of the whole `protocol_controlled_notional`, less what it has filled itself. So a filled order stays filled, instead of being handed a share of what's left again.
+	///
 	//HACK: doesn't yet work with multiple symbols.
 	// Matter of fact, none of this does. Currently all Positions assume working with specific asset.
 	#[instrument(skip(self))]
@@ -240,7 +454,7 @@ impl ProtocolOrders {
 	) -> RecalculatedAllocation {
 		assert_eq!(self.__orders.len(), per_order_infos.len());
 
-		let mut left_controlled_notional = protocol_controlled_notional - per_order_infos.iter().map(|info| info.filled).sum::<f64>();
+		let left_controlled_notional = protocol_controlled_notional - per_order_infos.iter().map(|info| info.filled).sum::<f64>();
 		// Must be comparing against the largest of min_qties, as we can't force protocols to send their largest order always of the order_type with smallest min_qty.
 		if left_controlled_notional < min_qty_any_ordertype {
 			return RecalculatedAllocation {
@@ -248,7 +462,9 @@ impl ProtocolOrders {
 				leftovers: Some(left_controlled_notional),
 			};
 		}
-		let mut per_order_additional_percents_from_skipped = Percent::new(0.0);
+		let mut per_order_additional_notional_from_skipped = 0.0;
+		// orders that overfilled their share would otherwise have the rest add up to more than what's left
+		let mut left_to_allocate = left_controlled_notional;
 
 		let orders: Vec<ConceptualOrder<ProtocolOrderId>> = self
 			.__orders
@@ -256,8 +472,10 @@ impl ProtocolOrders {
 			.enumerate()
 			.filter_map(|(i, order)| match order {
 				Some(order) => {
-					let desired_notional_i = *(order.qty_percent_of_controlled + per_order_additional_percents_from_skipped) * left_controlled_notional;
+					let left_of_share_i = (*order.qty_percent_of_controlled * protocol_controlled_notional - per_order_infos[i].filled).max(0.0);
+					let desired_notional_i = (left_of_share_i + per_order_additional_notional_from_skipped).min(left_to_allocate);
 					if desired_notional_i > per_order_infos[i].min_possible_qty {
+						left_to_allocate -= desired_notional_i;
 						let order = ConceptualOrder::new(
 							ProtocolOrderId::new(self.protocol_id.clone(), i),
 							order.order_type,
@@ -265,10 +483,9 @@ impl ProtocolOrders {
 							order.side,
 							desired_notional_i,
 						);
-						left_controlled_notional -= desired_notional_i;
 						Some(order)
 					} else {
-						per_order_additional_percents_from_skipped += Percent::new(*order.qty_percent_of_controlled / (self.__orders.len() - (i + 1)) as f64);
+						per_order_additional_notional_from_skipped += left_of_share_i / (self.__orders.len() - (i + 1)) as f64;
 						None
 					}
 				}
@@ -459,4 +676,18 @@ mod tests {
 			}
 		}
 	}
+
+	#[test]
+	fn weight() {
+		assert_eq!(split_weight("ts:p0.5:w0.7").unwrap(), ("ts:p0.5".to_owned(), 0.7));
+		assert_eq!(split_weight("ts:w2:p0.5").unwrap(), ("ts:p0.5".to_owned(), 2.0));
+		assert_eq!(split_weight("ts:p0.5").unwrap(), ("ts:p0.5".to_owned(), 1.0));
+		assert!(split_weight("ts:p0.5:w0").is_err());
+		assert!(split_weight("ts:p0.5:w1:w2").is_err());
+
+		let weighted = Protocol::from_str("ts:p0.5:w0.7").unwrap();
+		assert_eq!(weighted.weight(), 0.7);
+		assert_eq!(weighted.id(), Protocol::from_str("ts:p0.5").unwrap().id());
+		assert!(interpret_protocol_specs(vec!["ts:p0.5:w1".to_owned(), "ts:p0.5:w2".to_owned()]).is_err());
+	}
 }
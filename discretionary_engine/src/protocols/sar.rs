@@ -1,12 +1,9 @@
 #[allow(unused_imports)] // RA bug
 use std::str::FromStr;
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, bail};
 use discretionary_engine_macros::ProtocolWrapper;
-use futures_util::StreamExt;
-use serde_json::Value;
 use tokio::{sync::mpsc, task::JoinSet};
-use tokio_tungstenite::connect_async;
 use tracing::{debug, instrument};
 use v_utils::{
 	Percent,
@@ -16,19 +13,15 @@ use v_utils::{
 
 use crate::{
 	exchange_apis::{Market, Symbol, order_types::*},
-	protocols::{ProtocolOrders, ProtocolTrait, ProtocolType},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
 };
 
-const BINANCE_TIMEFRAMES: [&str; 19] = [
-	"1s", "5s", "15s", "30s", "1m", "3m", "5m", "15m", "30m", "1h", "2h", "4h", "6h", "8h", "12h", "1d", "3d", "1w", "1M",
-];
-
 #[derive(Clone, CompactFormat, Copy, Debug, Default, ProtocolWrapper, derive_new::new)]
 pub struct Sar {
 	start: Percent,
 	increment: Percent,
 	max: Percent,
-	/// NB: Impossible to change dynamically currently (because the websocket connection contains timeframe in its signature)
+	/// NB: Can't be updated on a running protocol, as it's what the kline stream is subscribed to.
 	timeframe: Timeframe,
 }
 
@@ -36,70 +29,45 @@ impl ProtocolTrait for SarWrapper {
 	type Params = Sar;
 
 	#[instrument(skip(position_js, tx_orders))]
-	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side) -> Result<()> {
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
 		let symbol = Symbol {
 			base: asset,
 			quote: "USDT".to_owned(),
 			market: Market::BinanceFutures,
 		};
 		let tf = { self.0.read().unwrap().timeframe };
-		let (tx, mut rx) = tokio::sync::mpsc::channel::<Ohlc>(256);
+		let mut klines = position.market_data.klines(&symbol, tf)?;
 		let mut last_order: Option<ConceptualOrderPercents> = None;
-		let symbol_clone = symbol.clone();
-		let params_arc = self.0.clone();
+		let (params_arc, protocol_id) = (self.0.clone(), self.id());
 		position_js.spawn(async move {
-			let mut js = JoinSet::new();
-			js.spawn(async move {
-				let address = format!("wss://fstream.binance.com/ws/{}@kline_{tf}", symbol_clone.to_string().to_lowercase());
-				let (ws_stream, _) = connect_async(address).await.unwrap();
-				let (_, mut read) = ws_stream.split();
-
-				while let Some(msg) = read.next().await {
-					let data = msg.unwrap().into_data();
-					debug!("SAR received websocket klines update: {:?}", data);
-					match serde_json::from_slice::<Value>(&data) {
-						Ok(json) =>
-							if let Some(open_str) = json.get("o") {
-								let open: f64 = open_str.as_str().unwrap().parse().unwrap();
-								let high: f64 = json["h"].as_str().unwrap().parse().unwrap();
-								let low: f64 = json["l"].as_str().unwrap().parse().unwrap();
-								let close: f64 = json["c"].as_str().unwrap().parse().unwrap();
-								tx.send(Ohlc { open, high, low, close }).await.unwrap();
-							},
-						Err(e) => {
-							println!("Failed to parse message as JSON: {}", e);
-						}
-					}
+			let init_ohlcs = position.market_data.closed_klines(&symbol, tf, 100).await?;
+			debug!("initialized klines");
+			let mut sar = SarIndicator::init(&init_ohlcs, &params_arc.read().unwrap());
+
+			while let Some(kline) = klines.recv().await {
+				// SAR is defined over complete klines only
+				if !kline.closed {
+					continue;
 				}
-			});
-
-			js.spawn(async move {
-				// HACK: shouldn't be unwrapping
-				debug!("about to initialise klines");
-				let tf_str = tf.try_as_predefined(&BINANCE_TIMEFRAMES).unwrap().to_string();
-				let init_klines = crate::exchange_apis::binance::get_historic_klines(symbol.to_string(), tf_str, 100).await.unwrap();
-				debug!("initialized klines");
-				let init_ohlcs = init_klines.into_iter().map(|k| k.into()).collect::<Vec<Ohlc>>();
-				let mut sar = SarIndicator::init(&init_ohlcs, &params_arc.read().unwrap());
-
-				while let Some(ohlc) = rx.recv().await {
-					let maybe_order = sar.step(ohlc, &params_arc.read().unwrap(), &symbol, protocol_side);
-					if last_order != maybe_order {
-						let protocol_spec = params_arc.read().unwrap().to_string();
-						tx_orders.send(ProtocolOrders::new(protocol_spec.clone(), vec![maybe_order.clone()])).await.unwrap();
-						last_order = maybe_order;
-					}
+				let maybe_order = sar.step(kline.ohlc, &params_arc.read().unwrap(), &symbol, protocol_side);
+				if last_order != maybe_order {
+					tx_orders.send(ProtocolOrders::new(protocol_id.clone(), vec![maybe_order.clone()])).await?;
+					last_order = maybe_order;
 				}
-			});
-			js.join_all().await;
+			}
 			Ok(())
 		});
 
 		Ok(())
 	}
 
+	/// Takes effect from the next kline, as that's the only time SAR moves.
 	fn update_params(&self, new_params: Sar) -> Result<()> {
-		*self.0.write().unwrap() = new_params;
+		let current_tf = self.0.read().unwrap().timeframe;
+		if new_params.timeframe != current_tf {
+			bail!("Can't change the timeframe of a running sar ({current_tf} -> {})", new_params.timeframe);
+		}
+		self.set_params(new_params);
 		Ok(())
 	}
 
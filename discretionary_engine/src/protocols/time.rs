@@ -0,0 +1,190 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Result, bail, eyre};
+use discretionary_engine_macros::ProtocolWrapper;
+use tokio::{select, sync::mpsc, task::JoinSet};
+use tracing::{info, warn};
+use v_utils::{
+	Percent,
+	trades::{Side, Timeframe},
+};
+
+use crate::{
+	exchange_apis::{Market, Symbol, order_types::*},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
+};
+
+/// How often to see whether an expired position has stopped being in profit.
+const PROFIT_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Closes whatever remains of the position once its edge is expected to be gone.
+///
+/// Format: `time[:d<timeframe>][:c<timeframe>][:u]`, e.g. `time:d4h:c5m:u`.
+/// - `d`: how long after the position was opened to close it. Defaults to the position's `tf`.
+/// - `c`: chase the close for this long instead of sending a market order.
+/// - `u`: only close while the position is not in profit; a winning one is left to its other protocols until it stops being one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ProtocolWrapper, derive_new::new)]
+pub struct Time {
+	duration: Option<Timeframe>,
+	chase: Option<Timeframe>,
+	unprofitable_only: bool,
+}
+
+impl Time {
+	fn deadline(&self, position: &PositionContext) -> Result<DateTime<Utc>> {
+		let duration = match (self.duration, position.tf) {
+			(Some(tf), _) => Duration::from_millis(tf.0),
+			(None, Some(position_tf)) => position_tf,
+			(None, None) => bail!("`time` protocol needs either its own duration or the position's `tf`"),
+		};
+		Ok(position.opened_at + chrono::Duration::from_std(duration)?)
+	}
+
+	fn close_order(&self, symbol: Symbol, side: Side) -> ConceptualOrderPercents {
+		let order_type = match self.chase {
+			Some(chase) => ConceptualOrderType::Chase(ConceptualChase::new(Duration::from_millis(chase.0), None)),
+			None => ConceptualOrderType::Market(ConceptualMarket::new(Percent(1.0))),
+		};
+		ConceptualOrderPercents::new(order_type, symbol, side, Percent::new(1.0))
+	}
+}
+
+/// `side` is that of the closing orders, so opposite to the position's.
+fn in_profit(price: f64, entry_price: f64, side: Side) -> bool {
+	match side {
+		Side::Sell => price > entry_price,
+		Side::Buy => price < entry_price,
+	}
+}
+
+impl FromStr for Time {
+	type Err = eyre::Report;
+
+	fn from_str(spec: &str) -> Result<Self> {
+		let mut parts = spec.split(':');
+		if parts.next() != Some("time") {
+			bail!("Not a time spec: {spec}");
+		}
+		let mut time = Self::default();
+		for part in parts {
+			match part.split_at_checked(1) {
+				Some(("d", v)) => time.duration = Some(Timeframe::from_str(v).map_err(|e| eyre!("{e}"))?),
+				Some(("c", v)) => time.chase = Some(Timeframe::from_str(v).map_err(|e| eyre!("{e}"))?),
+				Some(("u", "")) => time.unprofitable_only = true,
+				_ => bail!("Unknown time param: {part}"),
+			}
+		}
+		Ok(time)
+	}
+}
+
+impl fmt::Display for Time {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "time")?;
+		if let Some(duration) = self.duration {
+			write!(f, ":d{duration}")?;
+		}
+		if let Some(chase) = self.chase {
+			write!(f, ":c{chase}")?;
+		}
+		if self.unprofitable_only {
+			write!(f, ":u")?;
+		}
+		Ok(())
+	}
+}
+
+impl ProtocolTrait for TimeWrapper {
+	type Params = Time;
+
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
+		// fail early rather than once it's too late
+		self.0.read().unwrap().deadline(&position)?;
+		let symbol = Symbol {
+			base: asset,
+			quote: "USDT".to_owned(),
+			market: Market::BinanceFutures,
+		};
+
+		let (params, updated, protocol_id) = (self.0.clone(), self.1.clone(), self.id());
+		position_js.spawn(async move {
+			loop {
+				let deadline = params.read().unwrap().deadline(&position)?;
+				let Ok(until_deadline) = (deadline - Utc::now()).to_std() else {
+					break;
+				};
+				// updated params could have moved the deadline
+				select! {
+					_ = tokio::time::sleep(until_deadline) => {},
+					_ = updated.notified() => {},
+				}
+			}
+
+			loop {
+				let current = *params.read().unwrap();
+				let keep = match (current.unprofitable_only, position.entry_price) {
+					(false, _) => false,
+					(true, Some(entry_price)) => in_profit(position.price().await, entry_price, protocol_side),
+					(true, None) => {
+						warn!("Entry price of the position is unknown, can't tell whether it's in profit. Closing regardless.");
+						false
+					}
+				};
+				if !keep {
+					info!("Position on {} expired, closing what's left of it", symbol);
+					let order = current.close_order(symbol.clone(), protocol_side);
+					tx_orders.send(ProtocolOrders::new(protocol_id.clone(), vec![Some(order)])).await?;
+					break;
+				}
+				tokio::time::sleep(PROFIT_RECHECK_INTERVAL).await;
+			}
+
+			// protocols are expected to outlive the position
+			std::future::pending::<()>().await;
+			Ok(())
+		});
+		Ok(())
+	}
+
+	fn update_params(&self, new_params: Time) -> Result<()> {
+		if new_params.duration.is_none() && self.0.read().unwrap().duration.is_some() {
+			bail!("Can't drop the duration of a running `time`, as the position might have no `tf` to fall back on");
+		}
+		self.set_params(new_params);
+		Ok(())
+	}
+
+	fn get_type(&self) -> ProtocolType {
+		ProtocolType::Expiry
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn deadline() {
+		let opened_at = DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z").unwrap().to_utc();
+		let with_tf = PositionContext {
+			entry_price: Some(100.0),
+			opened_at,
+			tf: Some(Duration::from_secs(3600)),
+			..Default::default()
+		};
+		let without_tf = PositionContext { tf: None, ..with_tf.clone() };
+
+		let own = Time::from_str("time:d4h:u").unwrap();
+		assert!(own.unprofitable_only);
+		assert_eq!(own.deadline(&with_tf).unwrap(), opened_at + chrono::Duration::hours(4));
+
+		let inherited = Time::from_str("time:c5m").unwrap();
+		assert_eq!(inherited.deadline(&with_tf).unwrap(), opened_at + chrono::Duration::hours(1));
+		assert!(inherited.deadline(&without_tf).is_err());
+		assert_eq!(Time::from_str(&inherited.to_string()).unwrap(), inherited);
+
+		assert!(in_profit(110.0, 100.0, Side::Sell));
+		assert!(!in_profit(110.0, 100.0, Side::Buy));
+	}
+}
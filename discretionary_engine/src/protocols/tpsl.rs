@@ -0,0 +1,208 @@
+use std::{fmt, str::FromStr};
+
+use color_eyre::eyre::{Result, bail, ensure};
+use discretionary_engine_macros::ProtocolWrapper;
+use tokio::{sync::mpsc, task::JoinSet};
+use v_utils::{Percent, trades::Side};
+
+use crate::{
+	exchange_apis::{Market, Symbol, order_types::*},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
+};
+
+/// Fixed take-profit and stop-loss prices.
+///
+/// Format: `tpsl:t<price>:s<price>`. Several TP levels are separated by commas, each optionally followed by `@<share>` of the position it takes out, e.g. `tpsl:t0.47@0.3,0.45:s0.52`. Levels without a share split what's left equally.
+///
+/// TP and SL are sized as separate `ProtocolType`s, so each leg is sent in its own `ProtocolOrders`, under [TpSl::leg_id].
+#[derive(Clone, Debug, Default, PartialEq, ProtocolWrapper, derive_new::new)]
+pub struct TpSl {
+	tp: Vec<TpLevel>,
+	sl: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, derive_new::new)]
+pub struct TpLevel {
+	price: f64,
+	share: Percent,
+}
+
+impl TpSl {
+	/// Id of the `ProtocolOrders` carrying the leg of given type.
+	pub fn leg_id(signature: &str, protocol_type: ProtocolType) -> String {
+		match protocol_type {
+			ProtocolType::TP => format!("{signature}/tp"),
+			ProtocolType::SL => format!("{signature}/sl"),
+			_ => unreachable!("tpsl only has TP and SL legs"),
+		}
+	}
+
+	/// `protocol_id` is that of the whole protocol, the legs are sent under [TpSl::leg_id] of it.
+	fn orders(&self, protocol_id: &str, symbol: &Symbol, side: Side) -> (ProtocolOrders, ProtocolOrders) {
+		let tp_orders = self
+			.tp
+			.iter()
+			.map(|level| {
+				let limit = ConceptualLimit::new(level.price, true);
+				Some(ConceptualOrderPercents::new(ConceptualOrderType::Limit(limit), symbol.clone(), side, level.share))
+			})
+			.collect();
+		let sm = ConceptualStopMarket::new(self.sl);
+		let sl_order = ConceptualOrderPercents::new(ConceptualOrderType::StopMarket(sm), symbol.clone(), side, Percent::new(1.0));
+		(
+			ProtocolOrders::new(Self::leg_id(protocol_id, ProtocolType::TP), tp_orders),
+			ProtocolOrders::new(Self::leg_id(protocol_id, ProtocolType::SL), vec![Some(sl_order)]),
+		)
+	}
+}
+
+impl FromStr for TpSl {
+	type Err = eyre::Report;
+
+	fn from_str(spec: &str) -> Result<Self> {
+		let mut parts = spec.split(':');
+		if parts.next() != Some("tpsl") {
+			bail!("Not a tpsl spec: {spec}");
+		}
+		let (mut tp_spec, mut sl) = (None, None);
+		for part in parts {
+			match part.split_at_checked(1) {
+				Some(("t", v)) => tp_spec = Some(v),
+				Some(("s", v)) => sl = Some(v.parse::<f64>()?),
+				_ => bail!("Unknown tpsl param: {part}"),
+			}
+		}
+		let (Some(tp_spec), Some(sl)) = (tp_spec, sl) else {
+			bail!("tpsl requires both `t` and `s` params, got: {spec}");
+		};
+
+		let mut levels: Vec<(f64, Option<f64>)> = Vec::new();
+		for level in tp_spec.split(',') {
+			let (price, share) = match level.split_once('@') {
+				Some((price, share)) => (price.parse::<f64>()?, Some(share.parse::<f64>()?)),
+				None => (level.parse::<f64>()?, None),
+			};
+			ensure!(price > 0.0, "TP price must be positive, got {price}");
+			ensure!(share.is_none_or(|s| s > 0.0), "TP share must be positive, got {level}");
+			levels.push((price, share));
+		}
+		ensure!(sl > 0.0, "SL price must be positive, got {sl}");
+
+		let assigned: f64 = levels.iter().filter_map(|(_, share)| *share).sum();
+		let n_unassigned = levels.iter().filter(|(_, share)| share.is_none()).count();
+		ensure!(assigned <= 1.0 + f64::EPSILON, "TP shares add up to more than the whole position: {assigned}");
+		ensure!(n_unassigned > 0 || (1.0 - assigned).abs() < 1e-9, "TP shares must add up to the whole position, got {assigned}");
+		let tp = levels
+			.into_iter()
+			.map(|(price, share)| TpLevel::new(price, Percent::new(share.unwrap_or((1.0 - assigned) / n_unassigned as f64))))
+			.collect();
+
+		Ok(Self { tp, sl })
+	}
+}
+
+impl fmt::Display for TpSl {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let tp = match self.tp.as_slice() {
+			[single] => single.price.to_string(),
+			levels => levels.iter().map(|l| format!("{}@{}", l.price, *l.share)).collect::<Vec<_>>().join(","),
+		};
+		write!(f, "tpsl:t{tp}:s{}", self.sl)
+	}
+}
+
+impl ProtocolTrait for TpSlWrapper {
+	type Params = TpSl;
+
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, _position: PositionContext) -> Result<()> {
+		let symbol = Symbol {
+			base: asset,
+			quote: "USDT".to_owned(),
+			market: Market::BinanceFutures,
+		};
+
+		let (params, updated, protocol_id) = (self.0.clone(), self.1.clone(), self.id());
+		position_js.spawn(async move {
+			// Nothing to react to but our own params
+			loop {
+				let current = params.read().unwrap().clone();
+				let (tp_orders, sl_orders) = current.orders(&protocol_id, &symbol, protocol_side);
+				tx_orders.send(tp_orders).await?;
+				tx_orders.send(sl_orders).await?;
+				updated.notified().await;
+			}
+		});
+		Ok(())
+	}
+
+	fn update_params(&self, new_params: TpSl) -> Result<()> {
+		let n_levels = self.0.read().unwrap().tp.len();
+		// fills are tracked per order, so the set of orders of a running protocol must stay the same
+		if new_params.tp.len() != n_levels {
+			bail!("Can't change the number of TP levels of a running tpsl ({n_levels} -> {})", new_params.tp.len());
+		}
+		self.set_params(new_params);
+		Ok(())
+	}
+
+	/// SL leg is sized separately, see [TpSl::leg_id].
+	fn get_type(&self) -> ProtocolType {
+		ProtocolType::TP
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use insta::assert_debug_snapshot;
+
+	use super::*;
+	use crate::protocols::RecalculateOrdersPerOrderInfo;
+
+	#[test]
+	fn parse() {
+		let single = TpSl::from_str("tpsl:t0.4884:s0.519").unwrap();
+		assert_eq!(single.to_string(), "tpsl:t0.4884:s0.519");
+
+		let levels = TpSl::from_str("tpsl:t0.47@0.5,0.46,0.45:s0.52").unwrap();
+		assert_debug_snapshot!(levels.tp.iter().map(|l| (l.price, *l.share)).collect::<Vec<_>>(), @r###"
+  [
+      (
+          0.47,
+          0.5,
+      ),
+      (
+          0.46,
+          0.25,
+      ),
+      (
+          0.45,
+          0.25,
+      ),
+  ]
+  "###);
+		assert_eq!(TpSl::from_str(&levels.to_string()).unwrap(), levels);
+
+		assert!(TpSl::from_str("tpsl:t0.47@0.7,0.46@0.7:s0.52").is_err());
+		assert!(TpSl::from_str("tpsl:t0.47@0.5,0.46@0.4:s0.52").is_err());
+		assert!(TpSl::from_str("tpsl:t0.47").is_err());
+	}
+
+	#[test]
+	fn allocation() {
+		let tpsl = TpSl::from_str("tpsl:t0.47@0.3,0.45:s0.52").unwrap();
+		let symbol = Symbol::new("ADA".to_owned(), "USDT".to_owned(), Market::BinanceFutures);
+		let (tp_orders, _) = tpsl.orders("tpsl", &symbol, Side::Buy);
+		let allocate = |filled: [f64; 2]| {
+			let per_order_infos = filled.map(|f| RecalculateOrdersPerOrderInfo::new(f, 1.0));
+			let allocation = tp_orders.recalculate_protocol_orders_allocation(&per_order_infos, 100.0, 1.0);
+			allocation.orders.into_iter().map(|o| (o.id.ordinal, (o.qty_notional * 1e6).round() / 1e6)).collect::<Vec<_>>()
+		};
+
+		assert_eq!(allocate([0.0, 0.0]), vec![(0, 30.0), (1, 70.0)]);
+		// later levels keep their size, and the first only has its own remainder left
+		assert_eq!(allocate([10.0, 0.0]), vec![(0, 20.0), (1, 70.0)]);
+		// filled level is not placed again
+		assert_eq!(allocate([30.0, 0.0]), vec![(1, 70.0)]);
+		assert_eq!(allocate([30.0, 20.0]), vec![(1, 50.0)]);
+	}
+}
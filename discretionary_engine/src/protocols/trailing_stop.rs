@@ -1,14 +1,11 @@
 use color_eyre::eyre::Result;
 use discretionary_engine_macros::ProtocolWrapper;
-use futures_util::StreamExt;
-use serde_json::Value;
-use tokio::{sync::mpsc, task::JoinSet};
-use tokio_tungstenite::connect_async;
+use tokio::{select, sync::mpsc, task::JoinSet};
 use v_utils::{Percent, macros::CompactFormat, trades::Side};
 
 use crate::{
 	exchange_apis::{Market, Symbol, order_types::*},
-	protocols::{ProtocolOrders, ProtocolTrait, ProtocolType},
+	protocols::{PositionContext, ProtocolOrders, ProtocolTrait, ProtocolType},
 };
 
 #[derive(Clone, CompactFormat, Copy, Debug, Default, ProtocolWrapper, derive_new::new)]
@@ -19,56 +16,32 @@ pub struct TrailingStop {
 impl ProtocolTrait for TrailingStopWrapper {
 	type Params = TrailingStop;
 
-	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side) -> Result<()> {
+	fn attach(&self, position_js: &mut JoinSet<Result<()>>, tx_orders: mpsc::Sender<ProtocolOrders>, asset: String, protocol_side: Side, position: PositionContext) -> Result<()> {
 		let symbol = Symbol {
 			base: asset,
 			quote: "USDT".to_owned(),
 			market: Market::BinanceFutures,
 		};
-		let address = format!("wss://fstream.binance.com/ws/{}@aggTrade", symbol.to_string().to_lowercase());
-
-		let (tx, mut rx) = tokio::sync::mpsc::channel::<f64>(256);
-		let params = self.0.clone();
+		let mut trades = position.market_data.trades(&symbol);
+		let (params, updated, protocol_id) = (self.0.clone(), self.1.clone(), self.id());
 		position_js.spawn(async move {
-			let mut js = JoinSet::new();
-			js.spawn(async move {
-				let (ws_stream, _) = connect_async(address).await.unwrap();
-				let (_, mut read) = ws_stream.split();
-
-				while let Some(msg) = read.next().await {
-					let data = msg.unwrap().into_data();
-					match serde_json::from_slice::<Value>(&data) {
-						Ok(json) =>
-							if let Some(price_str) = json.get("p") {
-								let price: f64 = price_str.as_str().unwrap().parse().unwrap();
-								tx.send(price).await.unwrap();
-							},
-						Err(e) => {
-							println!("Failed to parse message as JSON: {}", e);
-						}
-					}
-				}
-			});
-
-			js.spawn(async move {
-				let mut ts_indicator = TrailingStopIndicator::new();
-				while let Some(price) = rx.recv().await {
-					let maybe_order = ts_indicator.step(price, params.read().unwrap().percent, protocol_side, &symbol);
-					if let Some(order) = maybe_order {
-						let protocol_spec = params.read().unwrap().to_string();
-						let protocol_orders = ProtocolOrders::new(protocol_spec, vec![Some(order)]);
-						tx_orders.send(protocol_orders).await.unwrap();
-					}
+			let mut ts_indicator = TrailingStopIndicator::new();
+			loop {
+				let maybe_order = select! {
+					Some(price) = trades.recv() => ts_indicator.step(price, params.read().unwrap().percent, protocol_side, &symbol),
+					_ = updated.notified() => ts_indicator.order(params.read().unwrap().percent, protocol_side, &symbol),
+				};
+				if let Some(order) = maybe_order {
+					let protocol_orders = ProtocolOrders::new(protocol_id.clone(), vec![Some(order)]);
+					tx_orders.send(protocol_orders).await?;
 				}
-			});
-			js.join_all().await;
-			Ok(())
+			}
 		});
 		Ok(())
 	}
 
 	fn update_params(&self, new_params: TrailingStop) -> Result<()> {
-		*self.0.write().unwrap() = new_params;
+		self.set_params(new_params);
 		Ok(())
 	}
 
@@ -91,23 +64,28 @@ impl TrailingStopIndicator {
 		if price < self.bottom || self.bottom == 0.0 {
 			self.bottom = price;
 			if side == Side::Buy {
-				let target_price = price * ((1.0_f64 + percent.abs()).ln() + 1.0);
-				let sm = ConceptualStopMarket::new(target_price);
-				let order = Some(ConceptualOrderPercents::new(ConceptualOrderType::StopMarket(sm), symbol.clone(), Side::Buy, Percent::new(1.0)));
-				return order;
+				return self.order(percent, side, symbol);
 			}
 		}
 		if price > self.top || self.top == 0.0 {
 			self.top = price;
 			if side == Side::Sell {
-				let target_price = price * ((1.0_f64 - percent.abs()).ln() + 1.0);
-				let sm = ConceptualStopMarket::new(target_price);
-				let order = Some(ConceptualOrderPercents::new(ConceptualOrderType::StopMarket(sm), symbol.clone(), Side::Sell, Percent::new(1.0)));
-				return order;
+				return self.order(percent, side, symbol);
 			}
 		}
 		None
 	}
+
+	/// Stop for the current extreme. `None` until the first price is seen.
+	fn order(&self, percent: Percent, side: Side, symbol: &Symbol) -> Option<ConceptualOrderPercents> {
+		let target_price = match side {
+			Side::Buy if self.bottom != 0.0 => self.bottom * ((1.0_f64 + percent.abs()).ln() + 1.0),
+			Side::Sell if self.top != 0.0 => self.top * ((1.0_f64 - percent.abs()).ln() + 1.0),
+			_ => return None,
+		};
+		let sm = ConceptualStopMarket::new(target_price);
+		Some(ConceptualOrderPercents::new(ConceptualOrderType::StopMarket(sm), symbol.clone(), side, Percent::new(1.0)))
+	}
 }
 
 #[cfg(test)]
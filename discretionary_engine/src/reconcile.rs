@@ -0,0 +1,336 @@
+//! Comparing the engine's knowledge of the account against what is actually on the exchange.
+//!
+//! Runs once on startup and then periodically. Every discrepancy is reported; unmanaged positions can be adopted as [PositionFollowup]s, and orphaned orders cancelled, if so configured.
+//!
+//! Every position runs in its own engine process, so what this one doesn't know about may well be another's. Positions with a live control socket in `positions_dir` (see [control::live_positions]) are left to their engines.
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::PathBuf,
+	sync::Arc,
+	time::Duration,
+};
+
+use color_eyre::eyre::{Context, Result};
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+use v_utils::trades::Side;
+
+use crate::{
+	config::{LiveSettings, ReconciliationConfig},
+	control,
+	exchange_apis::{
+		Market,
+		binance::{self, BinanceOrder, BinancePositionSide, FuturesPositionResponse},
+		exchanges::Exchanges,
+		hub::PositionToHub,
+	},
+	positions::{ClientOrderId, PositionAcquisition, PositionFollowup, PositionSpec},
+	protocols,
+};
+
+/// Anything below is considered rounding noise.
+const QTY_TOLERANCE: f64 = 1e-8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Drift {
+	/// Position on the exchange differs from what the engine has accumulated through its own fills.
+	Position {
+		symbol: String,
+		position_side: BinancePositionSide,
+		exchange_qty: f64,
+		engine_qty: f64,
+	},
+	/// Placed by the engine (as per its client order id), for a position no running engine serves. Typically left over from a previous run.
+	OrphanedOrder { symbol: String, order_id: i64, position_id: Uuid },
+	/// Placed outside of the engine. Only reported.
+	UnknownOrder { symbol: String, order_id: i64 },
+	/// The engine believes the order is deployed, but it's not open on the exchange. Can be transient, if the order was filled since the last poll.
+	MissingOrder { symbol: String, order_id: i64 },
+}
+impl std::fmt::Display for Drift {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Drift::Position {
+				symbol,
+				position_side,
+				exchange_qty,
+				engine_qty,
+			} => write!(f, "{symbol} ({position_side}): exchange has {exchange_qty}, engine expects {engine_qty}"),
+			Drift::OrphanedOrder { symbol, order_id, position_id } => write!(f, "{symbol}: order {order_id} of position {position_id} is not tracked by the engine"),
+			Drift::UnknownOrder { symbol, order_id } => write!(f, "{symbol}: order {order_id} was placed outside of the engine"),
+			Drift::MissingOrder { symbol, order_id } => write!(f, "{symbol}: engine's order {order_id} is not open on the exchange"),
+		}
+	}
+}
+
+#[instrument(skip_all)]
+pub async fn reconciliation_loop(live_settings: Arc<LiveSettings>, exchanges: Arc<Exchanges>, hub_tx: mpsc::Sender<PositionToHub>) -> Result<()> {
+	let mut adopted_js = JoinSet::new();
+	let mut unmanaged = HashMap::new();
+	//LOOP: for the entire lifetime of the engine
+	loop {
+		let reconciliation_config = live_settings.config()?.reconciliation.unwrap_or_default();
+		if let Err(e) = reconcile(live_settings.clone(), exchanges.clone(), hub_tx.clone(), &reconciliation_config, &mut unmanaged, &mut adopted_js).await {
+			warn!("Reconciliation failed: {e:?}");
+		}
+		tokio::time::sleep(Duration::from_secs(reconciliation_config.interval_s)).await;
+	}
+}
+
+/// `unmanaged`: positions nobody accounted for as of the previous poll. Only those still there, and of the same size, get adopted; otherwise we'd be racing fills that are yet to be reported, be it of our own acquisition or of a freshly started engine.
+#[instrument(skip(live_settings, exchanges, hub_tx, unmanaged, adopted_js))]
+async fn reconcile(
+	live_settings: Arc<LiveSettings>,
+	exchanges: Arc<Exchanges>,
+	hub_tx: mpsc::Sender<PositionToHub>,
+	reconciliation_config: &ReconciliationConfig,
+	unmanaged: &mut HashMap<(String, BinancePositionSide), f64>,
+	adopted_js: &mut JoinSet<Result<PositionFollowup>>,
+) -> Result<()> {
+	use secrecy::ExposeSecret;
+	use v_exchanges::ExchangeName;
+
+	let config = live_settings.config()?;
+	let binance_config = config.get_exchange(ExchangeName::Binance)?;
+	let (key, secret) = (binance_config.api_pubkey.clone(), binance_config.api_secret.expose_secret().to_string());
+
+	let exchange_positions = binance::get_futures_positions(key.clone(), secret.clone()).await?;
+	let open_orders = binance::get_open_orders(key.clone(), secret.clone()).await?;
+	let (engine_positions, engine_orders) = {
+		let lock = exchanges.binance.read().unwrap();
+		(lock.engine_positions.clone(), lock.engine_orders.clone())
+	};
+	let live_positions = control::live_positions(&config.positions_dir).await?;
+
+	let drift = find_drift(&exchange_positions, &open_orders, &engine_positions, &engine_orders, &live_positions);
+	let previously_unmanaged = std::mem::take(unmanaged);
+	if drift.is_empty() {
+		info!("No drift between engine and exchange");
+		return Ok(());
+	}
+	for d in &drift {
+		warn!("Drift: {d}");
+	}
+
+	for d in drift {
+		match d {
+			Drift::Position {
+				symbol,
+				position_side,
+				exchange_qty,
+				engine_qty,
+			} if engine_qty.abs() < QTY_TOLERANCE => {
+				let Some(adopt_with) = &reconciliation_config.adopt_with else { continue };
+				let key = (symbol.clone(), position_side);
+				if previously_unmanaged.get(&key).is_none_or(|qty| (qty - exchange_qty).abs() > QTY_TOLERANCE) {
+					unmanaged.insert(key, exchange_qty);
+					continue;
+				}
+				adopt_position(
+					&symbol,
+					position_side,
+					exchange_qty,
+					adopt_with,
+					config.positions_dir.clone(),
+					hub_tx.clone(),
+					exchanges.clone(),
+					adopted_js,
+				)
+				.await
+				.wrap_err_with(|| format!("Failed to adopt position on {symbol}"))?;
+			}
+			Drift::OrphanedOrder { symbol, order_id, .. } if reconciliation_config.cancel_orphaned => {
+				binance::cancel_order(key.clone(), secret.clone(), &symbol, order_id).await?;
+				info!("Cancelled orphaned order {order_id} on {symbol}");
+			}
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+/// Hands a position opened outside of the engine over to [PositionFollowup] with the configured protocols. Like any other, it's served on a control socket, which also tells other engines it's taken.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(hub_tx, exchanges, adopted_js))]
+async fn adopt_position(
+	symbol: &str,
+	position_side: BinancePositionSide,
+	exchange_qty: f64,
+	adopt_with: &str,
+	positions_dir: PathBuf,
+	hub_tx: mpsc::Sender<PositionToHub>,
+	exchanges: Arc<Exchanges>,
+	adopted_js: &mut JoinSet<Result<PositionFollowup>>,
+) -> Result<()> {
+	let Some(asset) = symbol.strip_suffix("USDT") else {
+		warn!("Can only adopt USDT-margined positions, skipping {symbol}");
+		return Ok(());
+	};
+	let protocols = protocols::interpret_protocol_specs(adopt_with.split_whitespace().map(str::to_owned).collect())?;
+
+	let side = match exchange_qty > 0.0 {
+		true => Side::Buy,
+		false => Side::Sell,
+	};
+	let price = binance::futures_price(asset).await?;
+	let spec = PositionSpec::new(asset.to_owned(), side, exchange_qty.abs() * price, Market::BinanceFutures, None, None, None, None);
+	let acquired = PositionAcquisition::adopt(spec.clone(), exchange_qty.abs(), None); // we only get to know its size
+
+	// From now on the position is the engine's; its followup fills will be accounted against this.
+	exchanges.binance.write().unwrap().engine_positions.insert((symbol.to_owned(), position_side), exchange_qty);
+	let served_protocols = protocols.clone();
+	adopted_js.spawn(async move {
+		let control = tokio::spawn(async move {
+			if let Err(e) = control::serve(positions_dir, &spec, served_protocols).await {
+				warn!("Protocol updates are unavailable for the adopted position: {e:?}");
+			}
+		});
+		let followed = PositionFollowup::do_followup(acquired, protocols, hub_tx, exchanges).await;
+		control.abort();
+		followed
+	});
+	info!("Adopted {exchange_qty} {symbol} position");
+	Ok(())
+}
+
+/// `live_positions`: `(asset, position id)` of positions served by running engines. Their orders are theirs, and so are positions on their symbols we know nothing of.
+fn find_drift(
+	exchange_positions: &HashMap<(String, BinancePositionSide), f64>,
+	open_orders: &[FuturesPositionResponse],
+	engine_positions: &HashMap<(String, BinancePositionSide), f64>,
+	engine_orders: &[BinanceOrder],
+	live_positions: &HashSet<(String, Uuid)>,
+) -> Vec<Drift> {
+	let mut drift = Vec::new();
+	let live_symbols = live_positions.iter().map(|(asset, _)| format!("{asset}USDT")).collect::<HashSet<_>>();
+	let live_ids = live_positions.iter().map(|(_, id)| *id).collect::<HashSet<_>>();
+
+	let mut keys = exchange_positions.keys().chain(engine_positions.keys()).cloned().collect::<Vec<_>>();
+	keys.sort();
+	keys.dedup();
+	for (symbol, position_side) in keys {
+		let exchange_qty = exchange_positions.get(&(symbol.clone(), position_side)).copied().unwrap_or_default();
+		let engine_qty = engine_positions.get(&(symbol.clone(), position_side)).copied().unwrap_or_default();
+		if engine_qty.abs() < QTY_TOLERANCE && live_symbols.contains(&symbol) {
+			continue;
+		}
+		if (exchange_qty - engine_qty).abs() > QTY_TOLERANCE {
+			drift.push(Drift::Position {
+				symbol,
+				position_side,
+				exchange_qty,
+				engine_qty,
+			});
+		}
+	}
+
+	let engine_order_ids = engine_orders.iter().filter_map(|o| o.binance_id).collect::<Vec<_>>();
+	for o in open_orders {
+		if engine_order_ids.contains(&o.order_id) {
+			continue;
+		}
+		let (symbol, order_id) = (o.symbol.clone(), o.order_id);
+		match o.client_order_id.as_deref().and_then(|id| id.parse::<ClientOrderId>().ok()) {
+			Some(client_order_id) if live_ids.contains(&client_order_id.position_id) => {}
+			Some(client_order_id) => drift.push(Drift::OrphanedOrder {
+				symbol,
+				order_id,
+				position_id: client_order_id.position_id,
+			}),
+			None => drift.push(Drift::UnknownOrder { symbol, order_id }),
+		}
+	}
+
+	for o in engine_orders {
+		if let Some(binance_id) = o.binance_id
+			&& !open_orders.iter().any(|open| open.order_id == binance_id)
+		{
+			drift.push(Drift::MissingOrder {
+				symbol: o.base_info.symbol.to_string(),
+				order_id: binance_id,
+			});
+		}
+	}
+
+	drift
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::exchange_apis::{Symbol, order_types::Order};
+
+	fn open_order(symbol: &str, order_id: i64, client_order_id: &str) -> FuturesPositionResponse {
+		FuturesPositionResponse {
+			symbol: symbol.to_owned(),
+			order_id,
+			client_order_id: Some(client_order_id.to_owned()),
+			..Default::default()
+		}
+	}
+
+	fn engine_order(base: &str, binance_id: i64) -> BinanceOrder {
+		let order = Order {
+			symbol: Symbol::new(base, "USDT", Market::BinanceFutures),
+			..Default::default()
+		};
+		BinanceOrder {
+			binance_id: Some(binance_id),
+			..BinanceOrder::new(order, false)
+		}
+	}
+
+	#[test]
+	fn drift() {
+		let exchange_positions = HashMap::from([
+			(("BTCUSDT".to_owned(), BinancePositionSide::Both), 0.5),
+			(("ETHUSDT".to_owned(), BinancePositionSide::Both), -2.0),
+			(("SOLUSDT".to_owned(), BinancePositionSide::Both), 10.0),
+		]);
+		let engine_positions = HashMap::from([(("BTCUSDT".to_owned(), BinancePositionSide::Both), 0.5)]);
+		let engine_orders = vec![engine_order("BTC", 1), engine_order("BTC", 2)];
+		let orphaned_client_id = "de0AS8IHKyFgXPRLDPdapPXlDHCRa01";
+		// of another engine's position
+		let other_engine_id = Uuid::parse_str("86acfda1-ef53-4bae-9f20-bbad6cbc8504").unwrap();
+		let other_engine_client_id = ClientOrderId {
+			position_id: other_engine_id,
+			..orphaned_client_id.parse().unwrap()
+		}
+		.to_string();
+		let open_orders = vec![
+			open_order("BTCUSDT", 1, orphaned_client_id),
+			open_order("BTCUSDT", 3, orphaned_client_id),
+			open_order("ADAUSDT", 4, "web_Kj3b9aL1"),
+			open_order("SOLUSDT", 5, &other_engine_client_id),
+		];
+		let live_positions = HashSet::from([("SOL".to_owned(), other_engine_id)]);
+
+		let drift = find_drift(&exchange_positions, &open_orders, &engine_positions, &engine_orders, &live_positions);
+		assert_eq!(
+			drift,
+			vec![
+				Drift::Position {
+					symbol: "ETHUSDT".to_owned(),
+					position_side: BinancePositionSide::Both,
+					exchange_qty: -2.0,
+					engine_qty: 0.0,
+				},
+				Drift::OrphanedOrder {
+					symbol: "BTCUSDT".to_owned(),
+					order_id: 3,
+					position_id: Uuid::parse_str("058a3b5d-7ce0-465c-9339-b43261e99b19").unwrap(),
+				},
+				Drift::UnknownOrder {
+					symbol: "ADAUSDT".to_owned(),
+					order_id: 4
+				},
+				Drift::MissingOrder {
+					symbol: "BTCUSDT".to_owned(),
+					order_id: 2
+				},
+			]
+		);
+	}
+}
@@ -47,7 +47,7 @@ impl Default for SizeArgs {
 	}
 }
 
-fn get_exchanges_auth(config: &AppConfig) -> std::collections::HashMap<String, ExchangeAuth> {
+pub(crate) fn get_exchanges_auth(config: &AppConfig) -> std::collections::HashMap<String, ExchangeAuth> {
 	config
 		.exchanges
 		.iter()
@@ -5,25 +5,34 @@
 /// 2. Continuously amends the price to stay one tick better than market
 /// 3. Monitors fills via WebSocket order events
 /// 4. When duration expires, cancels and market-fills remaining quantity
+/// 5. If price runs further than the max range from arrival, cancels and leaves the rest unfilled
 ///
 /// All operations use WebSocket for low latency and reliability.
 use color_eyre::eyre::{Context, Result, bail};
 use futures_util::{StreamExt, pin_mut};
 use nautilus_bybit::{
-	common::enums::{BybitEnvironment, BybitOrderSide, BybitOrderType, BybitProductType, BybitTimeInForce},
+	common::enums::{BybitOrderSide, BybitOrderType, BybitTimeInForce},
 	websocket::{
 		client::BybitWebSocketClient,
 		messages::{BybitWsAmendOrderParams, BybitWsCancelOrderParams, BybitWsPlaceOrderParams, NautilusWsMessage},
 	},
 };
 use nautilus_model::identifiers::{ClientOrderId, InstrumentId, StrategyId, TraderId, VenueOrderId};
-use tokio::time::{Duration, sleep};
+use tokio::{
+	sync::watch,
+	time::{Duration, sleep},
+};
 use tracing::info;
 use ustr::Ustr;
-use v_utils::{log, trades::Timeframe};
+use v_utils::log;
+
+use crate::{
+	bybit_common::{BybitCategory, BybitSignedClient, fetch_book_ticker},
+	exchange_apis::order_types::PriceDistance,
+};
 
 /// Format quantity string based on step size to avoid "Qty invalid" errors
-fn format_qty(qty: f64, qty_step: f64) -> String {
+pub(crate) fn format_qty(qty: f64, qty_step: f64) -> String {
 	if qty_step >= 1.0 {
 		format!("{:.0}", qty)
 	} else if qty_step >= 0.1 {
@@ -40,7 +49,7 @@ fn format_qty(qty: f64, qty_step: f64) -> String {
 }
 
 /// Format price string based on tick size
-fn format_price(price: f64, tick_size: f64) -> String {
+pub(crate) fn format_price(price: f64, tick_size: f64) -> String {
 	if tick_size >= 1.0 {
 		format!("{:.0}", price)
 	} else if tick_size >= 0.1 {
@@ -56,33 +65,51 @@ fn format_price(price: f64, tick_size: f64) -> String {
 	}
 }
 
+/// Hooks for running the chase on behalf of the engine, rather than as a one-off CLI execution.
+#[derive(Debug)]
+pub struct ChaseControl {
+	/// Used instead of a random one, so that fills can be attributed to the engine's order. The final market leg gets `-f` appended, so must be at most 34 chars.
+	pub order_link_id: String,
+	/// Flipping to `true` cancels the outstanding limit and returns, without market-filling the remainder.
+	pub stop: watch::Receiver<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChaseOutcome {
+	pub filled: f64,
+	/// Left unfilled on purpose: price ran past the max range, or the engine no longer wanted the order.
+	pub abandoned: f64,
+	/// Volume-weighted over the fills we got reports for. `None` if there were none.
+	pub avg_price: Option<f64>,
+}
+
 /// Executes an order using WebSocket-based chase-limit strategy
 ///
 /// # Arguments
-/// * `raw_client` - Raw HTTP client for initial price fetch
-/// * `api_key` - API key for WebSocket authentication
-/// * `api_secret` - API secret for WebSocket authentication
-/// * `environment` - Bybit environment (mainnet/testnet)
+/// * `signed_client` - Account to trade on; also where the WebSocket credentials and environment come from
+/// * `category` - Bybit category the symbol trades in
 /// * `symbol` - Trading symbol (Bybit format, e.g., "BTCUSDT")
 /// * `instrument_id` - Nautilus instrument ID for ticker subscription
 /// * `side` - Order side (\"Buy\" or \"Sell\")
-/// * `target_qty` - Total quantity to execute
+/// * `target_qty` - Total quantity to execute, in base (spot included)
 /// * `qty_step` - Minimum quantity increment
 /// * `price_tick` - Minimum price increment
 /// * `duration` - Optional duration for patient execution
+/// * `max_range` - Optional distance from the arrival price past which we stop chasing
+/// * `control` - Set when the chase is driven by the engine
 pub async fn execute_ws_chase_limit(
-	raw_client: &nautilus_bybit::http::client::BybitRawHttpClient,
-	api_key: String,
-	api_secret: String,
-	environment: BybitEnvironment,
+	signed_client: &BybitSignedClient,
+	category: BybitCategory,
 	symbol: &str,
 	instrument_id: InstrumentId,
 	side: &str,
 	target_qty: f64,
 	qty_step: f64,
 	price_tick: f64,
-	duration: Option<Timeframe>,
-) -> Result<f64> {
+	duration: Option<Duration>,
+	max_range: Option<PriceDistance>,
+	control: Option<ChaseControl>,
+) -> Result<ChaseOutcome> {
 	log!("Starting WebSocket chase-limit execution for {} {} {}", side, target_qty, symbol);
 
 	// Create identifiers for nautilus order management
@@ -90,32 +117,31 @@ pub async fn execute_ws_chase_limit(
 	let strategy_id = StrategyId::from("CHASE_LIMIT");
 
 	// Get initial price via HTTP to start immediately
-	use nautilus_bybit::http::query::BybitTickersParamsBuilder;
-
-	let ticker_params = BybitTickersParamsBuilder::default()
-		.category(BybitProductType::Linear)
-		.symbol(symbol.to_string())
-		.build()
-		.context("Failed to build ticker params")?;
-
-	let ticker_response: nautilus_bybit::http::models::BybitTickersLinearResponse = raw_client
-		.get_tickers::<nautilus_bybit::http::models::BybitTickersLinearResponse>(&ticker_params)
+	let ticker = fetch_book_ticker(signed_client.testnet(), category, symbol)
 		.await
 		.context("Failed to fetch initial ticker data")?;
-
-	let ticker = ticker_response.result.list.get(0).ok_or_else(|| color_eyre::eyre::eyre!("No ticker data found for {}", symbol))?;
-
-	let initial_bid: f64 = ticker.bid1_price.parse().context("Failed to parse bid price")?;
-	let initial_ask: f64 = ticker.ask1_price.parse().context("Failed to parse ask price")?;
+	let (initial_bid, initial_ask) = (ticker.bid, ticker.ask);
 
 	log!("Initial market: bid={}, ask={}", initial_bid, initial_ask);
 
+	// Chasing stops once our limit would have to go past this
+	let arrival_price = (initial_bid + initial_ask) / 2.0;
+	let range_bound = max_range.map(|r| {
+		let distance = r.distance(arrival_price);
+		match side {
+			"Sell" => arrival_price - distance,
+			_ => arrival_price + distance,
+		}
+	});
+	if let Some(bound) = range_bound {
+		log!("Arrival price {}, will stop chasing past {}", arrival_price, bound);
+	}
+
 	// Calculate execution parameters based on duration
-	let (update_interval, end_time) = if let Some(duration_tf) = duration {
-		let total_duration_ms = duration_tf.0;
+	let (update_interval, end_time) = if let Some(duration) = duration {
 		let update_interval_ms = 1000; // Check/update every 1 second
-		let end_time = std::time::Instant::now() + Duration::from_millis(total_duration_ms);
-		log!("Patient execution over {:?}: update_interval={}ms", duration_tf, update_interval_ms);
+		let end_time = std::time::Instant::now() + duration;
+		log!("Patient execution over {:?}: update_interval={}ms", duration, update_interval_ms);
 		(Duration::from_millis(update_interval_ms), Some(end_time))
 	} else {
 		// Aggressive execution: update quickly
@@ -124,6 +150,8 @@ pub async fn execute_ws_chase_limit(
 	};
 
 	// Create trade WebSocket client for order operations
+	let environment = signed_client.environment();
+	let (api_key, api_secret) = signed_client.credentials();
 	let mut trade_client = BybitWebSocketClient::new_trade(
 		environment,
 		Some(api_key),
@@ -134,7 +162,7 @@ pub async fn execute_ws_chase_limit(
 
 	// Create market data WebSocket client for ticker
 	let mut market_client = BybitWebSocketClient::new_public_with(
-		BybitProductType::Linear,
+		category.product_type(),
 		environment,
 		None, // url
 		None, // heartbeat
@@ -199,9 +227,11 @@ pub async fn execute_ws_chase_limit(
 	log!("Calculated initial limit price: {} (bid={}, ask={})", initial_limit_price, initial_bid, initial_ask);
 
 	// Place initial order immediately
-	// Note: order_link_id must be <= 45 chars. UUID is 32 hex chars (without hyphens), so "c-{}" = 34 chars
-	let short_uuid = uuid::Uuid::new_v4().simple().to_string();
-	let order_link_id = format!("c-{}", short_uuid);
+	// Note: order_link_id must be <= 36 chars, including the `-f` of the final leg. UUID is 32 hex chars (without hyphens), so "c-{}" = 34 chars
+	let (order_link_id, mut stop) = match control {
+		Some(control) => (control.order_link_id, Some(control.stop)),
+		None => (format!("c-{}", uuid::Uuid::new_v4().simple()), None),
+	};
 	let client_order_id = ClientOrderId::from(order_link_id.as_str());
 	let bybit_side = match side {
 		"Buy" => BybitOrderSide::Buy,
@@ -210,7 +240,7 @@ pub async fn execute_ws_chase_limit(
 	};
 
 	let initial_order = BybitWsPlaceOrderParams {
-		category: BybitProductType::Linear,
+		category: category.product_type(),
 		symbol: Ustr::from(symbol),
 		side: bybit_side,
 		order_type: BybitOrderType::Limit,
@@ -251,6 +281,10 @@ pub async fn execute_ws_chase_limit(
 	let mut order_placed = true;
 	let mut last_amend_price = Some(initial_limit_price);
 	let mut filled_qty = 0.0;
+	let mut abandoned_qty = 0.0;
+	// (qty, value) over individual fill reports, for the average price
+	let mut reported_fills = (0.0, 0.0);
+	let mut out_of_range = false;
 	let mut iteration = 0;
 	let mut venue_order_id: Option<VenueOrderId> = None;
 
@@ -274,7 +308,7 @@ pub async fn execute_ws_chase_limit(
 				// Cancel existing limit order
 				if order_placed {
 					let cancel_params = BybitWsCancelOrderParams {
-						category: BybitProductType::Linear,
+						category: category.product_type(),
 						symbol: Ustr::from(symbol),
 						order_id: None,
 						order_link_id: Some(order_link_id.clone()),
@@ -292,15 +326,15 @@ pub async fn execute_ws_chase_limit(
 				// Place market order for remaining quantity
 				let remaining_qty = target_qty - filled_qty;
 				if remaining_qty > 0.0 {
-					let final_order_link_id = format!("{}-final", order_link_id);
+					let final_order_link_id = format!("{}-f", order_link_id);
 					let final_client_order_id = ClientOrderId::from(final_order_link_id.as_str());
 					let market_params = BybitWsPlaceOrderParams {
-						category: BybitProductType::Linear,
+						category: category.product_type(),
 						symbol: Ustr::from(symbol),
 						side: bybit_side,
 						order_type: BybitOrderType::Market,
 						qty: format_qty(remaining_qty, qty_step),
-						market_unit: None,
+						market_unit: category.market_unit(),
 						price: None,
 						time_in_force: Some(BybitTimeInForce::Ioc),
 						order_link_id: Some(final_order_link_id.clone()),
@@ -341,7 +375,7 @@ pub async fn execute_ws_chase_limit(
 										for report in reports {
 											let coid_str = report.client_order_id.as_ref().map(|c| c.to_string()).unwrap_or_default();
 											log!("  Order: coid={}, status={:?}, filled={}", coid_str, report.order_status, report.filled_qty.as_f64());
-											if coid_str.starts_with("c-") {
+											if coid_str.starts_with(&order_link_id) {
 												filled_qty = report.filled_qty.as_f64();
 												if filled_qty >= target_qty - 0.0001 {
 													log!("Final order fully filled");
@@ -355,8 +389,10 @@ pub async fn execute_ws_chase_limit(
 										for fill in fills {
 											let coid_str = fill.client_order_id.as_ref().map(|c| c.to_string()).unwrap_or_default();
 											log!("  Fill: coid={}, qty={} @ price={}", coid_str, fill.last_qty.as_f64(), fill.last_px.as_f64());
-											if coid_str.starts_with("c-") {
+											if coid_str.starts_with(&order_link_id) {
 												filled_qty += fill.last_qty.as_f64();
+												reported_fills.0 += fill.last_qty.as_f64();
+												reported_fills.1 += fill.last_qty.as_f64() * fill.last_px.as_f64();
 											}
 										}
 									}
@@ -409,11 +445,36 @@ pub async fn execute_ws_chase_limit(
 
 								info!("[{}] Market: bid={}, ask={}, new target {} limit @ {}", iteration, bid_price, ask_price, side, new_limit_price);
 
+								out_of_range = match (side, range_bound) {
+									("Buy", Some(bound)) => new_limit_price > bound,
+									("Sell", Some(bound)) => new_limit_price < bound,
+									_ => false,
+								};
+								if out_of_range {
+									log!("[{}] Price ran past max range ({}), abandoning with {} filled out of {}", iteration, range_bound.unwrap(), filled_qty, target_qty);
+									if order_placed {
+										let cancel_params = BybitWsCancelOrderParams {
+											category: category.product_type(),
+											symbol: Ustr::from(symbol),
+											order_id: None,
+											order_link_id: Some(order_link_id.clone()),
+										};
+										if let Err(e) = trade_client
+											.cancel_order(cancel_params, client_order_id, trader_id, strategy_id, instrument_id, venue_order_id)
+											.await
+										{
+											log!("Failed to cancel order (may already be filled): {}", e);
+										}
+									}
+									abandoned_qty = (target_qty - filled_qty).max(0.0);
+									break; // out of the quotes; the chase loop is exited right after the select
+								}
+
 								// Place initial order if not yet placed
 								if !order_placed {
 									let limit_price = new_limit_price;
 									let place_params = BybitWsPlaceOrderParams {
-										category: BybitProductType::Linear,
+										category: category.product_type(),
 										symbol: Ustr::from(symbol),
 										side: bybit_side,
 										order_type: BybitOrderType::Limit,
@@ -475,7 +536,7 @@ pub async fn execute_ws_chase_limit(
 									if should_amend && filled_qty < target_qty {
 										let remaining_qty = target_qty - filled_qty;
 										let amend_params = BybitWsAmendOrderParams {
-											category: BybitProductType::Linear,
+											category: category.product_type(),
 											symbol: Ustr::from(symbol),
 											order_id: None,
 											order_link_id: Some(order_link_id.clone()),
@@ -529,7 +590,7 @@ pub async fn execute_ws_chase_limit(
 						for report in reports {
 							// Check if this is our order
 							if let Some(ref coid) = report.client_order_id {
-								if coid.to_string().starts_with("c-") {
+								if coid.to_string().starts_with(&order_link_id) {
 									log!("[{}] Order update: {:?} filled_qty={}", iteration, report.order_status, report.filled_qty.as_f64());
 
 									// Capture venue order ID for amend/cancel
@@ -550,14 +611,16 @@ pub async fn execute_ws_chase_limit(
 					NautilusWsMessage::FillReports(fills) => {
 						for fill in fills {
 							if let Some(ref coid) = fill.client_order_id {
-								if coid.to_string().starts_with("c-") {
+								if coid.to_string().starts_with(&order_link_id) {
 									log!("[{}] Fill: qty={} @ price={}", iteration, fill.last_qty.as_f64(), fill.last_px.as_f64());
+									reported_fills.0 += fill.last_qty.as_f64();
+									reported_fills.1 += fill.last_qty.as_f64() * fill.last_px.as_f64();
 								}
 							}
 						}
 					}
 					NautilusWsMessage::OrderRejected(rejected) => {
-						if rejected.client_order_id.to_string().starts_with("c-") {
+						if rejected.client_order_id.to_string().starts_with(&order_link_id) {
 							log!("[{}] Order rejected: {}", iteration, rejected.reason);
 							// If PostOnly rejected, we'll retry on next ticker update
 							order_placed = false;
@@ -585,6 +648,34 @@ pub async fn execute_ws_chase_limit(
 				}
 			}
 
+			// Engine no longer wants the order
+			Ok(()) = async {
+				match &mut stop {
+					Some(stop) => stop.changed().await,
+					None => std::future::pending().await,
+				}
+			} => {
+				if stop.as_ref().is_some_and(|s| *s.borrow()) {
+					log!("[{}] Stop requested, cancelling with {} filled out of {}", iteration, filled_qty, target_qty);
+					abandoned_qty = (target_qty - filled_qty).max(0.0);
+					if order_placed {
+						let cancel_params = BybitWsCancelOrderParams {
+							category: category.product_type(),
+							symbol: Ustr::from(symbol),
+							order_id: None,
+							order_link_id: Some(order_link_id.clone()),
+						};
+						if let Err(e) = trade_client
+							.cancel_order(cancel_params, client_order_id, trader_id, strategy_id, instrument_id, venue_order_id)
+							.await
+						{
+							log!("Failed to cancel order (may already be filled): {}", e);
+						}
+					}
+					break;
+				}
+			}
+
 			// Timeout to prevent blocking forever
 			_ = sleep(update_interval) => {
 				if iteration % 10 == 0 {
@@ -593,6 +684,10 @@ pub async fn execute_ws_chase_limit(
 			}
 		}
 
+		if out_of_range {
+			break;
+		}
+
 		// Safety check: don't run forever
 		if iteration > 10000 {
 			log!("Max iterations reached, stopping");
@@ -607,6 +702,10 @@ pub async fn execute_ws_chase_limit(
 	// Suppress unused variable warning
 	let _ = current_order_price;
 
-	log!("Chase-limit execution completed: filled {} out of {}", filled_qty, target_qty);
-	Ok(filled_qty)
+	log!("Chase-limit execution completed: filled {} out of {}, abandoned {}", filled_qty, target_qty, abandoned_qty);
+	Ok(ChaseOutcome {
+		filled: filled_qty,
+		abandoned: abandoned_qty,
+		avg_price: (reported_fills.0 > 0.0).then(|| reported_fills.1 / reported_fills.0),
+	})
 }
@@ -25,20 +25,40 @@ pub fn derive_protocol_wrapper(input: TokenStream) -> TokenStream {
 	let wrapper_name = format_ident!("{}Wrapper", name);
 
 	let expanded = quote! {
+		/// Params, a signal of them being updated, the id the protocol was created under, and its weight among the protocols of the same `ProtocolType`.
 		#[derive(Clone, Debug, Default)]
-		pub struct #wrapper_name(std::sync::Arc<std::sync::RwLock<#name>>);
+		pub struct #wrapper_name(std::sync::Arc<std::sync::RwLock<#name>>, std::sync::Arc<tokio::sync::Notify>, String, f64);
 		impl #wrapper_name {
+			/// Spec of the current params.
 			pub fn signature(&self) -> String {
 				self.0.read().unwrap().to_string()
 			}
+
+			/// Spec the protocol was created with. Unlike the [signature](Self::signature), survives param updates, so orders and fills stay attributed to the protocol.
+			pub fn id(&self) -> String {
+				self.2.clone()
+			}
+
+			/// Relative share of the position's size the protocol controls within its `ProtocolType`. Set with the `w` param, fixed for the lifetime of the protocol.
+			pub fn weight(&self) -> f64 {
+				self.3
+			}
+
+			/// Swaps the params, waking the protocol up to re-emit its orders under them.
+			pub fn set_params(&self, params: #name) {
+				*self.0.write().unwrap() = params;
+				self.1.notify_one();
+			}
 		}
 
 		impl std::str::FromStr for #wrapper_name {
 			type Err = eyre::Report;
 
 			fn from_str(spec: &str) -> eyre::Result<Self> {
-				let params = #name::from_str(spec)?;
-				Ok(Self(std::sync::Arc::new(std::sync::RwLock::new(params))))
+				let (spec, weight) = crate::protocols::split_weight(spec)?;
+				let params = #name::from_str(&spec)?;
+				let id = params.to_string();
+				Ok(Self(std::sync::Arc::new(std::sync::RwLock::new(params)), std::sync::Arc::default(), id, weight))
 			}
 		}
 	};